@@ -1,14 +1,23 @@
 // SPDX-License-Identifier: MPL-2.0
-use crate::memory::{allocate_phys_range, free_range, get_rsdp};
+use crate::memory::{
+    get_rsdp,
+    mmio::{CacheType, IoMapping},
+    numa::{self, CpuAffinity, MemoryAffinity, Topology},
+};
 use acpi::fadt::Fadt;
 use acpi::hpet::*;
-use acpi::sdt::Signature;
+use acpi::platform::PlatformInfo;
+use acpi::sdt::{SdtHeader, Signature};
 use acpi::*;
+use alloc::vec::Vec;
 use bit_field::BitField;
+use byteorder::{ByteOrder, LittleEndian};
+use core::mem::size_of;
 use core::ptr::NonNull;
+use core::slice;
 use log::*;
 use spin::*;
-use voladdress::*;
+use x86_64::VirtAddr;
 
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Default)]
@@ -23,11 +32,14 @@ impl AcpiHandler for AcpiMapper {
                 addr.get_bits(48..64)
             );
         }
-        allocate_phys_range(addr as u64, (addr + size) as u64, true, None);
+        // Tables often share pages with each other, so each gets a mapping of its own that it can drop on its own
+        let virt = IoMapping::new(addr as u64, size as u64, CacheType::WriteBack)
+            .unwrap_or_else(|| panic!("Cannot map ACPI region at {:X}", addr))
+            .into_raw();
         unsafe {
             PhysicalMapping::new(
                 addr,
-                NonNull::new(addr as *mut T).unwrap(),
+                NonNull::new(virt.as_mut_ptr()).unwrap(),
                 size,
                 size,
                 *self,
@@ -36,14 +48,44 @@ impl AcpiHandler for AcpiMapper {
     }
 
     fn unmap_physical_region<T>(mapping: &PhysicalMapping<Self, T>) {
-        free_range(
-            mapping.physical_start() as u64,
-            (mapping.physical_start() as u64) + (mapping.region_length() as u64),
-        );
+        drop(unsafe {
+            IoMapping::from_raw(
+                mapping.physical_start() as u64,
+                VirtAddr::from_ptr(mapping.virtual_start().as_ptr()),
+                mapping.mapped_length() as u64,
+                CacheType::WriteBack,
+            )
+        });
+    }
+}
+
+/// The System Resource Affinity Table. Only the header has a fixed layout; the affinity structures after it are
+/// parsed by hand.
+#[repr(C)]
+struct Srat {
+    header: SdtHeader,
+}
+
+impl AcpiTable for Srat {
+    fn header(&self) -> &SdtHeader {
+        &self.header
+    }
+}
+
+/// The System Locality Information Table: a header followed by the number of localities and a distance matrix.
+#[repr(C)]
+struct Slit {
+    header: SdtHeader,
+}
+
+impl AcpiTable for Slit {
+    fn header(&self) -> &SdtHeader {
+        &self.header
     }
 }
 
 static TABLES: Once<AcpiTables<AcpiMapper>> = Once::new();
+static HPET: Once<IoMapping> = Once::new();
 
 /// Initializes the ACPI tables.
 #[cold]
@@ -66,16 +108,14 @@ pub async fn init() {
                 info!("SMI command port: {:X}", smi_cmd);
             }
         }
+        init_numa(tables);
+        crate::ioapic::init();
         if let Ok(hpet_info) = get_hpet_info() {
-            let _ = allocate_phys_range(
-                hpet_info.base_address as u64,
-                (hpet_info.base_address as u64) + 0x3FF,
-                true,
-                None,
-            );
-            let hpet_cfg: VolAddress<u64, Safe, Safe> =
-                unsafe { VolAddress::new(hpet_info.base_address) };
-            let caps = hpet_cfg.read();
+            let hpet = HPET.call_once(|| {
+                IoMapping::new(hpet_info.base_address as u64, 0x400, CacheType::Uncached)
+                    .expect("Cannot map HPET registers")
+            });
+            let caps: u64 = hpet.read(0);
             info!(
                 "Found HPET at addr {:X}, rev. id {:X}",
                 hpet_info.base_address,
@@ -87,22 +127,16 @@ pub async fn init() {
                 panic!("HPET main counter is not 64 bits wide");
             }
             if (0..caps.get_bits(8..16) as usize)
-                .map(|tmr| unsafe {
-                    VolAddress::<u64, Safe, Safe>::new(
-                        hpet_info.base_address + (0x20 * tmr) + 0x100,
-                    )
-                })
-                .all(|cfg| cfg.read().get_bit(5))
+                .map(|tmr| hpet.read::<u64>((0x20 * tmr) + 0x100))
+                .all(|cfg| cfg.get_bit(5))
             {
                 panic!("Not all HPET timers are 64 bits wide!");
             }
             info!("Enabling HPET");
-            let cfg: VolAddress<u64, Safe, Safe> =
-                unsafe { VolAddress::new(hpet_info.base_address + 0x10) };
-            let mut cur_cfg = cfg.read();
+            let mut cur_cfg: u64 = hpet.read(0x10);
             cur_cfg.set_bit(1, true);
             cur_cfg.set_bit(0, true);
-            cfg.write(cur_cfg);
+            hpet.write(0x10, cur_cfg);
         } else {
             panic!("HPET not supported, but HPET required");
         }
@@ -111,12 +145,102 @@ pub async fn init() {
     }
 }
 
+/// Calls `f` with the bytes of the table `T` with the given signature, header included, if the firmware provides it.
+fn with_table_bytes<T: AcpiTable, R>(
+    tables: &AcpiTables<AcpiMapper>,
+    signature: Signature,
+    f: impl FnOnce(&[u8]) -> R,
+) -> Option<R> {
+    let mapping = unsafe { tables.get_sdt::<T>(signature) }.ok()??;
+    let len = mapping.header().length as usize;
+    let bytes =
+        unsafe { slice::from_raw_parts(mapping.virtual_start().as_ptr().cast::<u8>(), len) };
+    Some(f(bytes))
+}
+
+/// Reads the NUMA topology from the SRAT and SLIT and hands it to the memory manager.
+#[cold]
+fn init_numa(tables: &AcpiTables<AcpiMapper>) {
+    let mut memory = Vec::new();
+    let mut cpus = Vec::new();
+    let found = with_table_bytes::<Srat, _>(tables, Signature::SRAT, |srat| {
+        // The affinity structures start after the header and 12 reserved bytes
+        let mut offset = size_of::<SdtHeader>() + 12;
+        while offset + 2 <= srat.len() {
+            let len = srat[offset + 1] as usize;
+            if len < 2 || offset + len > srat.len() {
+                warn!("SRAT entry at offset {:X} is malformed", offset);
+                break;
+            }
+            let entry = &srat[offset..offset + len];
+            match entry[0] {
+                // Processor local APIC affinity
+                0 if len >= 16 && LittleEndian::read_u32(&entry[4..]).get_bit(0) => {
+                    cpus.push(CpuAffinity {
+                        apic_id: u32::from(entry[3]),
+                        node: u32::from(entry[2]) | (LittleEndian::read_u24(&entry[9..]) << 8),
+                    });
+                }
+                // Memory affinity
+                1 if len >= 40 && LittleEndian::read_u32(&entry[28..]).get_bit(0) => {
+                    let flags = LittleEndian::read_u32(&entry[28..]);
+                    let base = LittleEndian::read_u64(&entry[8..]);
+                    memory.push(MemoryAffinity {
+                        range: base..base + LittleEndian::read_u64(&entry[16..]),
+                        node: LittleEndian::read_u32(&entry[2..]),
+                        hot_pluggable: flags.get_bit(1),
+                        non_volatile: flags.get_bit(2),
+                    });
+                }
+                // Processor local x2APIC affinity
+                2 if len >= 24 && LittleEndian::read_u32(&entry[12..]).get_bit(0) => {
+                    cpus.push(CpuAffinity {
+                        apic_id: LittleEndian::read_u32(&entry[8..]),
+                        node: LittleEndian::read_u32(&entry[4..]),
+                    });
+                }
+                _ => (),
+            }
+            offset += len;
+        }
+    });
+    if found.is_none() {
+        info!("No SRAT; assuming a single NUMA node");
+        return;
+    }
+    let (localities, distances) = with_table_bytes::<Slit, _>(tables, Signature::SLIT, |slit| {
+        let start = size_of::<SdtHeader>() + 8;
+        if slit.len() < start {
+            return (0, Vec::new());
+        }
+        let localities = LittleEndian::read_u64(&slit[size_of::<SdtHeader>()..]) as usize;
+        let end = localities
+            .checked_mul(localities)
+            .and_then(|n| n.checked_add(start))
+            .filter(|&end| end <= slit.len())
+            .unwrap_or(start);
+        (localities, slit[start..end].to_vec())
+    })
+    .unwrap_or((0, Vec::new()));
+    numa::init(Topology::new(memory, cpus, localities, distances));
+}
+
 /// Returns a list of PCI regions.
 pub fn get_pci_regions() -> Result<PciConfigRegions, AcpiError> {
     PciConfigRegions::new(TABLES.get().unwrap())
 }
 
+/// Returns the interrupt model and processors the MADT describes.
+pub fn get_platform_info() -> Result<PlatformInfo, AcpiError> {
+    TABLES.get().unwrap().platform_info()
+}
+
 /// Returns information about the high precision event timer (HPET)
 pub fn get_hpet_info() -> Result<HpetInfo, AcpiError> {
     HpetInfo::new(TABLES.get().unwrap())
 }
+
+/// Returns the mapping of the HPET's registers, once ACPI initialization has mapped them.
+pub fn get_hpet_registers() -> Option<&'static IoMapping> {
+    HPET.get()
+}
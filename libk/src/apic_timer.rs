@@ -0,0 +1,262 @@
+// SPDX-License-Identifier: MPL-2.0
+use crate::acpi::get_hpet_registers;
+use crate::interrupts::TIMER_VECTOR;
+use crate::ioapic;
+use bit_field::BitField;
+use core::arch::x86_64::_rdtsc;
+use core::fmt;
+use core::hint::spin_loop;
+use core::sync::atomic::{fence, AtomicU32, Ordering};
+use log::*;
+use raw_cpuid::CpuId;
+use spin::Once;
+use x86_64::{instructions::interrupts::without_interrupts, registers::model_specific::Msr};
+
+/// The x2APIC timer registers.
+const LVT_TIMER: u32 = 0x832;
+const INITIAL_COUNT: u32 = 0x838;
+const CURRENT_COUNT: u32 = 0x839;
+const DIVIDE_CONFIG: u32 = 0x83E;
+/// The IA32_TSC_DEADLINE MSR.
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+/// Bits of the LVT timer register.
+const LVT_MASKED: usize = 16;
+const LVT_MODE: core::ops::Range<usize> = 17..19;
+/// The divide configuration for dividing the bus clock by 16.
+const DIVIDE_BY_16: u64 = 0b0011;
+/// The HPET registers used for calibration.
+const HPET_CAPABILITIES: usize = 0x00;
+const HPET_MAIN_COUNTER: usize = 0xF0;
+/// How long calibration measures the timer against the HPET, in nanoseconds.
+const CALIBRATION_NS: u64 = 10_000_000;
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const FEMTOS_PER_SEC: u128 = 1_000_000_000_000_000;
+/// The tick rate [`init`] starts the timer at, in hertz.
+pub const DEFAULT_TICK_RATE: u32 = 1000;
+
+/// The rates the timer and TSC run at, measured once against the HPET.
+static CALIBRATION: Once<Calibration> = Once::new();
+/// The rate the periodic tick was last started at, in hertz, or 0 if it has not been.
+static TICK_RATE: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Clone, Copy, Debug)]
+struct Calibration {
+    /// Timer counts per second, after dividing the bus clock by 16.
+    timer_hz: u64,
+    /// TSC ticks per second.
+    tsc_hz: u64,
+}
+
+/// How the local APIC timer fires.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TimerMode {
+    /// Once, when the count programmed into it runs out.
+    OneShot,
+    /// Over and over, each time the count programmed into it runs out.
+    Periodic,
+    /// Once, when the TSC reaches the deadline programmed into it.
+    TscDeadline,
+}
+
+impl TimerMode {
+    #[inline]
+    fn bits(self) -> u64 {
+        match self {
+            TimerMode::OneShot => 0b00,
+            TimerMode::Periodic => 0b01,
+            TimerMode::TscDeadline => 0b10,
+        }
+    }
+}
+
+/// Errors reported when programming the local APIC timer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TimerError {
+    /// The timer has not been calibrated yet.
+    NotCalibrated,
+    /// The processor does not support TSC-deadline mode.
+    Unsupported,
+    /// The rate or delay is zero, or too long for the timer's 32-bit count.
+    OutOfRange,
+}
+
+impl fmt::Display for TimerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimerError::NotCalibrated => write!(f, "local APIC timer not calibrated"),
+            TimerError::Unsupported => write!(f, "TSC-deadline mode not supported"),
+            TimerError::OutOfRange => write!(f, "rate or delay out of range"),
+        }
+    }
+}
+
+/// Calibrates the local APIC timer, masks the legacy timer that shares its vector, and starts the kernel tick on
+/// this processor at [`DEFAULT_TICK_RATE`]. Must run once the HPET is enabled.
+#[cold]
+pub async fn init() {
+    if calibrate().is_none() {
+        error!("Cannot calibrate local APIC timer; no tick source");
+        return;
+    }
+    // The PIT is routed to the tick vector too, and would tick on top of the local APIC timer
+    if let Some(gsi) = ioapic::isa_gsi(0) {
+        let _ = ioapic::mask(gsi);
+    }
+    match start_periodic(DEFAULT_TICK_RATE) {
+        Ok(()) => info!("Kernel tick running at {} Hz", DEFAULT_TICK_RATE),
+        Err(e) => error!("Cannot start kernel tick: {}", e),
+    }
+}
+
+/// Measures how fast the local APIC timer and the TSC run against the HPET main counter. The bus clock is the same on
+/// every processor, so this only runs once; later calls return the first measurement. Returns `None` if the HPET is
+/// not mapped yet.
+#[cold]
+pub fn calibrate() -> Option<(u64, u64)> {
+    if let Some(c) = CALIBRATION.get() {
+        return Some((c.timer_hz, c.tsc_hz));
+    }
+    let hpet = get_hpet_registers()?;
+    let period = u128::from(hpet.read::<u64>(HPET_CAPABILITIES).get_bits(32..64));
+    let wait = (u128::from(CALIBRATION_NS) * 1_000_000 / period) as u64;
+    let (counted, tsc, elapsed) = without_interrupts(|| unsafe {
+        Msr::new(DIVIDE_CONFIG).write(DIVIDE_BY_16);
+        Msr::new(LVT_TIMER).write(*u64::from(TIMER_VECTOR).set_bit(LVT_MASKED, true));
+        let start = hpet.read::<u64>(HPET_MAIN_COUNTER);
+        let tsc_start = _rdtsc();
+        Msr::new(INITIAL_COUNT).write(u64::from(u32::MAX));
+        let mut now = start;
+        while now.wrapping_sub(start) < wait {
+            spin_loop();
+            now = hpet.read::<u64>(HPET_MAIN_COUNTER);
+        }
+        let remaining = Msr::new(CURRENT_COUNT).read();
+        let tsc_end = _rdtsc();
+        Msr::new(INITIAL_COUNT).write(0);
+        (
+            u64::from(u32::MAX) - remaining,
+            tsc_end - tsc_start,
+            now.wrapping_sub(start),
+        )
+    });
+    let elapsed_fs = u128::from(elapsed) * period;
+    let calibration = CALIBRATION.call_once(|| Calibration {
+        timer_hz: (u128::from(counted) * FEMTOS_PER_SEC / elapsed_fs) as u64,
+        tsc_hz: (u128::from(tsc) * FEMTOS_PER_SEC / elapsed_fs) as u64,
+    });
+    info!(
+        "Local APIC timer runs at {} Hz (bus clock / 16), TSC at {} Hz",
+        calibration.timer_hz, calibration.tsc_hz
+    );
+    Some((calibration.timer_hz, calibration.tsc_hz))
+}
+
+/// Returns whether this processor's local APIC timer supports [`TimerMode::TscDeadline`].
+pub fn has_tsc_deadline() -> bool {
+    CpuId::new()
+        .get_feature_info()
+        .map_or(false, |f| f.has_tsc_deadline())
+}
+
+/// Starts the timer on this processor firing at [`TIMER_VECTOR`] `hz` times a second, which is the kernel tick
+/// [`get_tick_count`](crate::interrupts::get_tick_count) counts.
+pub fn start_periodic(hz: u32) -> Result<(), TimerError> {
+    let c = CALIBRATION.get().ok_or(TimerError::NotCalibrated)?;
+    if hz == 0 {
+        return Err(TimerError::OutOfRange);
+    }
+    let count = c.timer_hz / u64::from(hz);
+    if count == 0 || count > u64::from(u32::MAX) {
+        return Err(TimerError::OutOfRange);
+    }
+    program(TimerMode::Periodic, count);
+    TICK_RATE.store(hz, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Fires the timer on this processor once, `nanos` nanoseconds from now, replacing whatever it was set to do.
+pub fn start_one_shot(nanos: u64) -> Result<(), TimerError> {
+    let c = CALIBRATION.get().ok_or(TimerError::NotCalibrated)?;
+    let count = u128::from(nanos) * u128::from(c.timer_hz) / u128::from(NANOS_PER_SEC);
+    if count == 0 || count > u128::from(u32::MAX) {
+        return Err(TimerError::OutOfRange);
+    }
+    program(TimerMode::OneShot, count as u64);
+    Ok(())
+}
+
+/// Fires the timer on this processor once the TSC reaches `deadline`, replacing whatever it was set to do. A deadline
+/// already passed fires at once.
+pub fn set_tsc_deadline(deadline: u64) -> Result<(), TimerError> {
+    if !has_tsc_deadline() {
+        return Err(TimerError::Unsupported);
+    }
+    program(TimerMode::TscDeadline, 0);
+    // The LVT write must land before the deadline is armed
+    fence(Ordering::SeqCst);
+    unsafe {
+        Msr::new(IA32_TSC_DEADLINE).write(deadline);
+    }
+    Ok(())
+}
+
+/// Fires the timer on this processor once, `nanos` nanoseconds from now, in TSC-deadline mode.
+pub fn start_tsc_deadline(nanos: u64) -> Result<(), TimerError> {
+    let c = CALIBRATION.get().ok_or(TimerError::NotCalibrated)?;
+    let ticks = u128::from(nanos) * u128::from(c.tsc_hz) / u128::from(NANOS_PER_SEC);
+    let now = unsafe { _rdtsc() };
+    set_tsc_deadline(now.saturating_add(ticks.min(u128::from(u64::MAX)) as u64))
+}
+
+/// Stops the timer on this processor.
+pub fn stop() {
+    unsafe {
+        Msr::new(LVT_TIMER).write(*u64::from(TIMER_VECTOR).set_bit(LVT_MASKED, true));
+        Msr::new(INITIAL_COUNT).write(0);
+        if has_tsc_deadline() {
+            Msr::new(IA32_TSC_DEADLINE).write(0);
+        }
+    }
+}
+
+/// Returns the mode the timer on this processor is in.
+pub fn mode() -> TimerMode {
+    match unsafe { Msr::new(LVT_TIMER).read() }.get_bits(LVT_MODE) {
+        0b01 => TimerMode::Periodic,
+        0b10 => TimerMode::TscDeadline,
+        _ => TimerMode::OneShot,
+    }
+}
+
+/// Returns the rate the periodic tick was last started at, in hertz, or 0 if it has not been.
+#[inline]
+pub fn tick_rate() -> u32 {
+    TICK_RATE.load(Ordering::Relaxed)
+}
+
+/// Returns the rate the TSC runs at, in hertz, once the timer has been calibrated.
+#[inline]
+pub fn tsc_frequency() -> Option<u64> {
+    CALIBRATION.get().map(|c| c.tsc_hz)
+}
+
+/// Returns the rate the timer counts down at, in hertz, once it has been calibrated.
+#[inline]
+pub fn timer_frequency() -> Option<u64> {
+    CALIBRATION.get().map(|c| c.timer_hz)
+}
+
+/// Switches the timer on this processor to `mode` at [`TIMER_VECTOR`], unmasked, and starts it counting down from
+/// `count` (which TSC-deadline mode ignores).
+fn program(mode: TimerMode, count: u64) {
+    let mut lvt = u64::from(TIMER_VECTOR);
+    lvt.set_bits(LVT_MODE, mode.bits());
+    without_interrupts(|| unsafe {
+        Msr::new(INITIAL_COUNT).write(0);
+        Msr::new(DIVIDE_CONFIG).write(DIVIDE_BY_16);
+        Msr::new(LVT_TIMER).write(lvt);
+        if mode != TimerMode::TscDeadline {
+            Msr::new(INITIAL_COUNT).write(count);
+        }
+    });
+}
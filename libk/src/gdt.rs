@@ -1,4 +1,8 @@
 // SPDX-License-Identifier: MPL-2.0
+use crate::memory::{image, stack, stack::KernelStack};
+use alloc::boxed::Box;
+use core::mem::forget;
+use core::ptr::addr_of;
 use log::*;
 use spin::Lazy;
 use x86_64::instructions::{
@@ -13,15 +17,36 @@ use x86_64::VirtAddr;
 
 /// Double-fault stack index
 pub const DF_IST_IDX: u16 = 0;
+/// Page-fault stack index
+pub const PF_IST_IDX: u16 = 1;
+/// NMI stack index
+pub const NMI_IST_IDX: u16 = 2;
+/// Size of each interrupt stack
+const IST_STACK_SIZE: usize = 16384;
+/// Size of the guard page below each interrupt stack
+const IST_GUARD_SIZE: usize = 4096;
+
+/// An interrupt stack with room for a guard page below it.
+#[repr(C, align(4096))]
+struct IstStack {
+    guard: [u8; IST_GUARD_SIZE],
+    stack: [u8; IST_STACK_SIZE],
+}
+
+const IST_EMPTY: IstStack = IstStack {
+    guard: [0; IST_GUARD_SIZE],
+    stack: [0; IST_STACK_SIZE],
+};
+/// The interrupt stacks, indexed by IST index, and who uses them.
+static mut IST_STACKS: [IstStack; 3] = [IST_EMPTY; 3];
+const IST_OWNERS: [&str; 3] = ["double fault handler", "page fault handler", "NMI handler"];
 
 static TSS: Lazy<TaskStateSegment> = Lazy::new(|| {
     let mut tss = TaskStateSegment::new();
-    tss.interrupt_stack_table[DF_IST_IDX as usize] = {
-        const STACK_SIZE: usize = 4096;
-        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-        let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-        stack_start + STACK_SIZE
-    };
+    (0..IST_OWNERS.len()).for_each(|i| {
+        let stack_start = VirtAddr::from_ptr(unsafe { addr_of!(IST_STACKS[i].stack) });
+        tss.interrupt_stack_table[i] = stack_start + IST_STACK_SIZE;
+    });
     tss
 });
 static GDT: Lazy<(GlobalDescriptorTable, Selectors)> = Lazy::new(|| {
@@ -52,3 +77,37 @@ pub fn init() {
         load_tss(GDT.1.tss_selector);
     }
 }
+
+/// Unmaps the guard pages below the interrupt stacks, so that a handler overflowing its stack faults instead of
+/// running into whatever lies below. Requires the memory manager.
+#[cold]
+pub fn protect_ist_stacks() {
+    IST_OWNERS.iter().enumerate().for_each(|(i, owner)| {
+        let guard = unsafe { addr_of!(IST_STACKS[i].guard) } as u64;
+        image::unmap_guard(guard..guard + IST_GUARD_SIZE as u64);
+        stack::register(guard..guard + IST_GUARD_SIZE as u64, owner);
+    });
+}
+
+/// Gives an application processor a GDT and TSS of its own, with guarded interrupt stacks, and loads them. A TSS is
+/// marked busy once loaded, so the bootstrap processor's cannot be shared. Requires the memory manager.
+#[cold]
+pub fn init_ap() {
+    let mut tss = TaskStateSegment::new();
+    IST_OWNERS.iter().enumerate().for_each(|(i, owner)| {
+        let stack = KernelStack::new(IST_STACK_SIZE as u64, owner)
+            .expect("Cannot allocate an interrupt stack");
+        tss.interrupt_stack_table[i] = stack.top();
+        forget(stack);
+    });
+    let tss: &'static TaskStateSegment = Box::leak(Box::new(tss));
+    let gdt = Box::leak(Box::new(GlobalDescriptorTable::new()));
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    let gdt: &'static GlobalDescriptorTable = gdt;
+    gdt.load();
+    unsafe {
+        CS::set_reg(code_selector);
+        load_tss(tss_selector);
+    }
+}
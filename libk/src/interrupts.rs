@@ -1,9 +1,10 @@
 // SPDX-License-Identifier: MPL-2.0
-use crate::acpi::get_hpet_info;
+use crate::acpi::{get_hpet_info, get_hpet_registers};
 use crate::gdt;
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 use bit_field::BitField;
-use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
 use heapless::FnvIndexMap;
 use log::*;
 use minivec::MiniVec;
@@ -11,30 +12,79 @@ use raw_cpuid::*;
 use spin::{Lazy, RwLock};
 use voladdress::*;
 use x86_64::{
-    instructions::hlt,
+    instructions::{hlt, interrupts::without_interrupts},
     registers::model_specific::Msr,
     structures::idt::{
-        InterruptDescriptorTable, InterruptStackFrame, InterruptStackFrameValue, PageFaultErrorCode,
+        HandlerFunc, InterruptDescriptorTable, InterruptStackFrame, InterruptStackFrameValue,
+        PageFaultErrorCode,
     },
 };
 
 /// Types to contain IRQ functions and interrupt handlers
-type IrqList = FnvIndexMap<u8, MiniVec<InterruptHandler>, 256>;
+type IrqList = FnvIndexMap<u8, MiniVec<(HandlerId, InterruptHandler)>, 256>;
 /// This is the type for interrupt handlers.
 pub type InterruptHandler = Box<dyn Fn(InterruptStackFrameValue) + Send + Sync>;
 
+/// The first vector that is not a processor exception.
+pub const FIRST_IRQ_VECTOR: u8 = 32;
+/// The number of legacy ISA IRQs. The I/O APIC delivers IRQ `n` at vector [`FIRST_IRQ_VECTOR`]` + n`.
+pub const ISA_IRQ_COUNT: u8 = 16;
+/// The lowest priority class vectors can be allocated from. Classes 0 and 1 hold the processor exceptions, and
+/// class 2 the legacy ISA IRQs.
+pub const MIN_PRIORITY_CLASS: u8 = (FIRST_IRQ_VECTOR >> 4) + 1;
+/// The highest priority class.
+pub const MAX_PRIORITY_CLASS: u8 = 15;
+/// The vector of the kernel tick, raised by the local APIC timer (and by the legacy timer until that is masked).
+pub const TIMER_VECTOR: u8 = 32;
+/// The vector of the legacy RTC.
+const RTC_VECTOR: u8 = 40;
+/// The vector the local APIC delivers spurious interrupts to.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// Allocated vectors, one bit per vector in each priority class. Exceptions and the vectors with fixed handlers are
+/// never handed out.
+static VECTORS: [AtomicU16; 16] = [
+    AtomicU16::new(u16::MAX),
+    AtomicU16::new(u16::MAX),
+    AtomicU16::new(1 << (TIMER_VECTOR & 0xF) | 1 << (RTC_VECTOR & 0xF)),
+    AtomicU16::new(0),
+    AtomicU16::new(0),
+    AtomicU16::new(0),
+    AtomicU16::new(0),
+    AtomicU16::new(0),
+    AtomicU16::new(0),
+    AtomicU16::new(0),
+    AtomicU16::new(0),
+    AtomicU16::new(0),
+    AtomicU16::new(0),
+    AtomicU16::new(0),
+    AtomicU16::new(0),
+    AtomicU16::new(1 << (SPURIOUS_VECTOR & 0xF)),
+];
+static NEXT_HANDLER_ID: AtomicU64 = AtomicU64::new(0);
+
 static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
     let mut idt = InterruptDescriptorTable::new();
     // Handle BPs
     let _ = idt.breakpoint.set_handler_fn(handle_breakpoint);
-    // Handle DFs (on our set-up separate 4K kernel stack)
+    // Handle DFs (on our set-up separate kernel stack)
     unsafe {
         let _ = idt
             .double_fault
             .set_handler_fn(handle_double_fault)
             .set_stack_index(gdt::DF_IST_IDX);
     }
-    let _ = idt.page_fault.set_handler_fn(handle_page_fault);
+    // Page faults and NMIs get their own stacks, so that a kernel stack overflow can still be reported
+    unsafe {
+        let _ = idt
+            .page_fault
+            .set_handler_fn(handle_page_fault)
+            .set_stack_index(gdt::PF_IST_IDX);
+        let _ = idt
+            .non_maskable_interrupt
+            .set_handler_fn(handle_non_maskable_interrupt)
+            .set_stack_index(gdt::NMI_IST_IDX);
+    }
     let _ = idt.overflow.set_handler_fn(handle_overflow);
     let _ = idt
         .bound_range_exceeded
@@ -49,9 +99,6 @@ static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
     let _ = idt.alignment_check.set_handler_fn(handle_alignment_check);
     let _ = idt.debug.set_handler_fn(handle_debug);
     let _ = idt.divide_error.set_handler_fn(handle_divide_error);
-    let _ = idt
-        .non_maskable_interrupt
-        .set_handler_fn(handle_non_maskable_interrupt);
     let _ = idt.invalid_tss.set_handler_fn(handle_invalid_tss);
     let _ = idt
         .segment_not_present
@@ -72,237 +119,23 @@ static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
     let _ = idt
         .security_exception
         .set_handler_fn(handle_security_exception);
-    let _ = idt[32].set_handler_fn(handle_timer);
-    let _ = idt[33].set_handler_fn(handle_keyboard);
-    let _ = idt[34].set_handler_fn(handle_cascade);
-    let _ = idt[35].set_handler_fn(handle_uart1);
-    let _ = idt[36].set_handler_fn(handle_serial1);
-    let _ = idt[37].set_handler_fn(handle_parallel);
-    let _ = idt[38].set_handler_fn(handle_floppy);
-    let _ = idt[39].set_handler_fn(handle_lpt1);
-    let _ = idt[40].set_handler_fn(handle_rtc);
-    let _ = idt[41].set_handler_fn(handle_acpi);
-    let _ = idt[42].set_handler_fn(handle_open1);
-    let _ = idt[43].set_handler_fn(handle_open2);
-    let _ = idt[44].set_handler_fn(handle_mouse);
-    let _ = idt[45].set_handler_fn(handle_coprocessor);
-    let _ = idt[46].set_handler_fn(handle_primary_ata);
-    let _ = idt[47].set_handler_fn(handle_secondary_ata);
-    let _ = idt[48].set_handler_fn(handle_irq48);
-    let _ = idt[49].set_handler_fn(handle_irq49);
-    let _ = idt[50].set_handler_fn(handle_irq50);
-    let _ = idt[51].set_handler_fn(handle_irq51);
-    let _ = idt[52].set_handler_fn(handle_irq52);
-    let _ = idt[53].set_handler_fn(handle_irq53);
-    let _ = idt[54].set_handler_fn(handle_irq54);
-    let _ = idt[55].set_handler_fn(handle_irq55);
-    let _ = idt[56].set_handler_fn(handle_irq56);
-    let _ = idt[57].set_handler_fn(handle_irq57);
-    let _ = idt[58].set_handler_fn(handle_irq58);
-    let _ = idt[59].set_handler_fn(handle_irq59);
-    let _ = idt[60].set_handler_fn(handle_irq60);
-    let _ = idt[61].set_handler_fn(handle_irq61);
-    let _ = idt[62].set_handler_fn(handle_irq62);
-    let _ = idt[63].set_handler_fn(handle_irq63);
-    let _ = idt[64].set_handler_fn(handle_irq64);
-    let _ = idt[65].set_handler_fn(handle_irq65);
-    let _ = idt[66].set_handler_fn(handle_irq66);
-    let _ = idt[67].set_handler_fn(handle_irq67);
-    let _ = idt[68].set_handler_fn(handle_irq68);
-    let _ = idt[69].set_handler_fn(handle_irq69);
-    let _ = idt[70].set_handler_fn(handle_irq70);
-    let _ = idt[71].set_handler_fn(handle_irq71);
-    let _ = idt[72].set_handler_fn(handle_irq72);
-    let _ = idt[73].set_handler_fn(handle_irq73);
-    let _ = idt[74].set_handler_fn(handle_irq74);
-    let _ = idt[75].set_handler_fn(handle_irq75);
-    let _ = idt[76].set_handler_fn(handle_irq76);
-    let _ = idt[77].set_handler_fn(handle_irq77);
-    let _ = idt[78].set_handler_fn(handle_irq78);
-    let _ = idt[79].set_handler_fn(handle_irq79);
-    let _ = idt[80].set_handler_fn(handle_irq80);
-    let _ = idt[81].set_handler_fn(handle_irq81);
-    let _ = idt[82].set_handler_fn(handle_irq82);
-    let _ = idt[83].set_handler_fn(handle_irq83);
-    let _ = idt[84].set_handler_fn(handle_irq84);
-    let _ = idt[85].set_handler_fn(handle_irq85);
-    let _ = idt[86].set_handler_fn(handle_irq86);
-    let _ = idt[87].set_handler_fn(handle_irq87);
-    let _ = idt[88].set_handler_fn(handle_irq88);
-    let _ = idt[89].set_handler_fn(handle_irq89);
-    let _ = idt[90].set_handler_fn(handle_irq90);
-    let _ = idt[91].set_handler_fn(handle_irq91);
-    let _ = idt[92].set_handler_fn(handle_irq92);
-    let _ = idt[93].set_handler_fn(handle_irq93);
-    let _ = idt[94].set_handler_fn(handle_irq94);
-    let _ = idt[95].set_handler_fn(handle_irq95);
-    let _ = idt[96].set_handler_fn(handle_irq96);
-    let _ = idt[97].set_handler_fn(handle_irq97);
-    let _ = idt[98].set_handler_fn(handle_irq98);
-    let _ = idt[99].set_handler_fn(handle_irq99);
-    let _ = idt[100].set_handler_fn(handle_irq100);
-    let _ = idt[101].set_handler_fn(handle_irq101);
-    let _ = idt[102].set_handler_fn(handle_irq102);
-    let _ = idt[103].set_handler_fn(handle_irq103);
-    let _ = idt[104].set_handler_fn(handle_irq104);
-    let _ = idt[105].set_handler_fn(handle_irq105);
-    let _ = idt[106].set_handler_fn(handle_irq106);
-    let _ = idt[107].set_handler_fn(handle_irq107);
-    let _ = idt[108].set_handler_fn(handle_irq108);
-    let _ = idt[109].set_handler_fn(handle_irq109);
-    let _ = idt[110].set_handler_fn(handle_irq110);
-    let _ = idt[111].set_handler_fn(handle_irq111);
-    let _ = idt[112].set_handler_fn(handle_irq112);
-    let _ = idt[113].set_handler_fn(handle_irq113);
-    let _ = idt[114].set_handler_fn(handle_irq114);
-    let _ = idt[115].set_handler_fn(handle_irq115);
-    let _ = idt[116].set_handler_fn(handle_irq116);
-    let _ = idt[117].set_handler_fn(handle_irq117);
-    let _ = idt[118].set_handler_fn(handle_irq118);
-    let _ = idt[119].set_handler_fn(handle_irq119);
-    let _ = idt[120].set_handler_fn(handle_irq120);
-    let _ = idt[121].set_handler_fn(handle_irq121);
-    let _ = idt[122].set_handler_fn(handle_irq122);
-    let _ = idt[123].set_handler_fn(handle_irq123);
-    let _ = idt[124].set_handler_fn(handle_irq124);
-    let _ = idt[125].set_handler_fn(handle_irq125);
-    let _ = idt[126].set_handler_fn(handle_irq126);
-    let _ = idt[127].set_handler_fn(handle_irq127);
-    let _ = idt[128].set_handler_fn(handle_irq128);
-    let _ = idt[129].set_handler_fn(handle_irq129);
-    let _ = idt[130].set_handler_fn(handle_irq130);
-    let _ = idt[131].set_handler_fn(handle_irq131);
-    let _ = idt[132].set_handler_fn(handle_irq132);
-    let _ = idt[133].set_handler_fn(handle_irq133);
-    let _ = idt[134].set_handler_fn(handle_irq134);
-    let _ = idt[135].set_handler_fn(handle_irq135);
-    let _ = idt[136].set_handler_fn(handle_irq136);
-    let _ = idt[137].set_handler_fn(handle_irq137);
-    let _ = idt[138].set_handler_fn(handle_irq138);
-    let _ = idt[139].set_handler_fn(handle_irq139);
-    let _ = idt[140].set_handler_fn(handle_irq140);
-    let _ = idt[141].set_handler_fn(handle_irq141);
-    let _ = idt[142].set_handler_fn(handle_irq142);
-    let _ = idt[143].set_handler_fn(handle_irq143);
-    let _ = idt[144].set_handler_fn(handle_irq144);
-    let _ = idt[145].set_handler_fn(handle_irq145);
-    let _ = idt[146].set_handler_fn(handle_irq146);
-    let _ = idt[147].set_handler_fn(handle_irq147);
-    let _ = idt[148].set_handler_fn(handle_irq148);
-    let _ = idt[149].set_handler_fn(handle_irq149);
-    let _ = idt[150].set_handler_fn(handle_irq150);
-    let _ = idt[151].set_handler_fn(handle_irq151);
-    let _ = idt[152].set_handler_fn(handle_irq152);
-    let _ = idt[153].set_handler_fn(handle_irq153);
-    let _ = idt[154].set_handler_fn(handle_irq154);
-    let _ = idt[155].set_handler_fn(handle_irq155);
-    let _ = idt[156].set_handler_fn(handle_irq156);
-    let _ = idt[157].set_handler_fn(handle_irq157);
-    let _ = idt[158].set_handler_fn(handle_irq158);
-    let _ = idt[159].set_handler_fn(handle_irq159);
-    let _ = idt[160].set_handler_fn(handle_irq160);
-    let _ = idt[161].set_handler_fn(handle_irq161);
-    let _ = idt[162].set_handler_fn(handle_irq162);
-    let _ = idt[163].set_handler_fn(handle_irq163);
-    let _ = idt[164].set_handler_fn(handle_irq164);
-    let _ = idt[165].set_handler_fn(handle_irq165);
-    let _ = idt[166].set_handler_fn(handle_irq166);
-    let _ = idt[167].set_handler_fn(handle_irq167);
-    let _ = idt[168].set_handler_fn(handle_irq168);
-    let _ = idt[169].set_handler_fn(handle_irq169);
-    let _ = idt[170].set_handler_fn(handle_irq170);
-    let _ = idt[171].set_handler_fn(handle_irq171);
-    let _ = idt[172].set_handler_fn(handle_irq172);
-    let _ = idt[173].set_handler_fn(handle_irq173);
-    let _ = idt[174].set_handler_fn(handle_irq174);
-    let _ = idt[175].set_handler_fn(handle_irq175);
-    let _ = idt[176].set_handler_fn(handle_irq176);
-    let _ = idt[177].set_handler_fn(handle_irq177);
-    let _ = idt[178].set_handler_fn(handle_irq178);
-    let _ = idt[179].set_handler_fn(handle_irq179);
-    let _ = idt[180].set_handler_fn(handle_irq180);
-    let _ = idt[181].set_handler_fn(handle_irq181);
-    let _ = idt[182].set_handler_fn(handle_irq182);
-    let _ = idt[183].set_handler_fn(handle_irq183);
-    let _ = idt[184].set_handler_fn(handle_irq184);
-    let _ = idt[185].set_handler_fn(handle_irq185);
-    let _ = idt[186].set_handler_fn(handle_irq186);
-    let _ = idt[187].set_handler_fn(handle_irq187);
-    let _ = idt[188].set_handler_fn(handle_irq188);
-    let _ = idt[189].set_handler_fn(handle_irq189);
-    let _ = idt[190].set_handler_fn(handle_irq190);
-    let _ = idt[191].set_handler_fn(handle_irq191);
-    let _ = idt[192].set_handler_fn(handle_irq192);
-    let _ = idt[193].set_handler_fn(handle_irq193);
-    let _ = idt[194].set_handler_fn(handle_irq194);
-    let _ = idt[195].set_handler_fn(handle_irq195);
-    let _ = idt[196].set_handler_fn(handle_irq196);
-    let _ = idt[197].set_handler_fn(handle_irq197);
-    let _ = idt[198].set_handler_fn(handle_irq198);
-    let _ = idt[199].set_handler_fn(handle_irq199);
-    let _ = idt[200].set_handler_fn(handle_irq200);
-    let _ = idt[201].set_handler_fn(handle_irq201);
-    let _ = idt[202].set_handler_fn(handle_irq202);
-    let _ = idt[203].set_handler_fn(handle_irq203);
-    let _ = idt[204].set_handler_fn(handle_irq204);
-    let _ = idt[205].set_handler_fn(handle_irq205);
-    let _ = idt[206].set_handler_fn(handle_irq206);
-    let _ = idt[207].set_handler_fn(handle_irq207);
-    let _ = idt[208].set_handler_fn(handle_irq208);
-    let _ = idt[209].set_handler_fn(handle_irq209);
-    let _ = idt[210].set_handler_fn(handle_irq210);
-    let _ = idt[211].set_handler_fn(handle_irq211);
-    let _ = idt[212].set_handler_fn(handle_irq212);
-    let _ = idt[213].set_handler_fn(handle_irq213);
-    let _ = idt[214].set_handler_fn(handle_irq214);
-    let _ = idt[215].set_handler_fn(handle_irq215);
-    let _ = idt[216].set_handler_fn(handle_irq216);
-    let _ = idt[217].set_handler_fn(handle_irq217);
-    let _ = idt[218].set_handler_fn(handle_irq218);
-    let _ = idt[219].set_handler_fn(handle_irq219);
-    let _ = idt[220].set_handler_fn(handle_irq220);
-    let _ = idt[221].set_handler_fn(handle_irq221);
-    let _ = idt[222].set_handler_fn(handle_irq222);
-    let _ = idt[223].set_handler_fn(handle_irq223);
-    let _ = idt[224].set_handler_fn(handle_irq224);
-    let _ = idt[225].set_handler_fn(handle_irq225);
-    let _ = idt[226].set_handler_fn(handle_irq226);
-    let _ = idt[227].set_handler_fn(handle_irq227);
-    let _ = idt[228].set_handler_fn(handle_irq228);
-    let _ = idt[229].set_handler_fn(handle_irq229);
-    let _ = idt[230].set_handler_fn(handle_irq230);
-    let _ = idt[231].set_handler_fn(handle_irq231);
-    let _ = idt[232].set_handler_fn(handle_irq232);
-    let _ = idt[233].set_handler_fn(handle_irq233);
-    let _ = idt[234].set_handler_fn(handle_irq234);
-    let _ = idt[235].set_handler_fn(handle_irq235);
-    let _ = idt[236].set_handler_fn(handle_irq236);
-    let _ = idt[237].set_handler_fn(handle_irq237);
-    let _ = idt[238].set_handler_fn(handle_irq238);
-    let _ = idt[239].set_handler_fn(handle_irq239);
-    let _ = idt[240].set_handler_fn(handle_irq240);
-    let _ = idt[241].set_handler_fn(handle_irq241);
-    let _ = idt[242].set_handler_fn(handle_irq242);
-    let _ = idt[243].set_handler_fn(handle_irq243);
-    let _ = idt[244].set_handler_fn(handle_irq244);
-    let _ = idt[245].set_handler_fn(handle_irq245);
-    let _ = idt[246].set_handler_fn(handle_irq246);
-    let _ = idt[247].set_handler_fn(handle_irq247);
-    let _ = idt[248].set_handler_fn(handle_irq248);
-    let _ = idt[249].set_handler_fn(handle_irq249);
-    let _ = idt[250].set_handler_fn(handle_irq250);
-    let _ = idt[251].set_handler_fn(handle_irq251);
-    let _ = idt[252].set_handler_fn(handle_irq252);
-    let _ = idt[253].set_handler_fn(handle_irq253);
-    let _ = idt[254].set_handler_fn(handle_irq254);
-    let _ = idt[255].set_handler_fn(handle_irq255);
+    STUBS
+        .iter()
+        .zip(usize::from(FIRST_IRQ_VECTOR)..)
+        .for_each(|(&stub, vector)| {
+            let _ = idt[vector].set_handler_fn(stub);
+        });
+    // The legacy timer and RTC vectors keep handlers of their own
+    let _ = idt[usize::from(TIMER_VECTOR)].set_handler_fn(handle_timer);
+    let _ = idt[usize::from(RTC_VECTOR)].set_handler_fn(handle_rtc);
+    let _ = idt[usize::from(SPURIOUS_VECTOR)].set_handler_fn(handle_spurious);
     idt
 });
 static TICK_COUNT: AtomicU64 = AtomicU64::new(0);
 static IRQ_FUNCS: Lazy<RwLock<IrqList>> = Lazy::new(|| {
     let mut table = IrqList::new();
-    (0..u8::MAX).for_each(|i| {
-        let v = MiniVec::<InterruptHandler>::new();
+    (0..=u8::MAX).for_each(|i| {
+        let v = MiniVec::new();
         if table.insert(i, v).is_err() {
             panic!("Cannot add ISR function table for interrupt {}!", i);
         }
@@ -331,7 +164,11 @@ pub fn init_ic() {
                 apic_base.set_bit(11, true);
                 ia32_apic_base.write(apic_base);
             }
-            info!("X2apic configured");
+            // Software-enable the local APIC, or it delivers nothing but NMIs, SMIs and INITs
+            unsafe {
+                Msr::new(0x80F).write(u64::from(SPURIOUS_VECTOR) | 1 << 8);
+            }
+            info!("X2apic configured, local APIC ID {:X}", local_apic_id());
         }
     } else {
         panic!("APIC/X2APIC not available/supported");
@@ -346,26 +183,67 @@ pub fn init_idt() {
     IDT.load();
 }
 
-// Macro to generate interrupt functions
-macro_rules! gen_interrupt_fn {
-    ($i:ident, $p:expr) => {
-        extern "x86-interrupt" fn $i(stack_frame: InterruptStackFrame) {
-            signal_eoi();
-            debug!("Interrupt received for int {}", $p);
-            IRQ_FUNCS
-                .read()
-                .get(&$p)
-                .unwrap()
-                .iter()
-                .enumerate()
-                .for_each(|(i, func)| {
-                    debug!("Calling func {:X}", i);
-                    (func)(stack_frame.clone());
-                });
-        }
+// Macro to generate the table of interrupt stubs, 16 per priority class. The last class stops short of the spurious
+// vector, which has a handler of its own
+macro_rules! stubs {
+    ($($class:literal)* ; $last:literal) => {
+        [$(
+            irq_stub::<{ $class * 16 }>,
+            irq_stub::<{ $class * 16 + 1 }>,
+            irq_stub::<{ $class * 16 + 2 }>,
+            irq_stub::<{ $class * 16 + 3 }>,
+            irq_stub::<{ $class * 16 + 4 }>,
+            irq_stub::<{ $class * 16 + 5 }>,
+            irq_stub::<{ $class * 16 + 6 }>,
+            irq_stub::<{ $class * 16 + 7 }>,
+            irq_stub::<{ $class * 16 + 8 }>,
+            irq_stub::<{ $class * 16 + 9 }>,
+            irq_stub::<{ $class * 16 + 10 }>,
+            irq_stub::<{ $class * 16 + 11 }>,
+            irq_stub::<{ $class * 16 + 12 }>,
+            irq_stub::<{ $class * 16 + 13 }>,
+            irq_stub::<{ $class * 16 + 14 }>,
+            irq_stub::<{ $class * 16 + 15 }>,
+        )*
+        irq_stub::<{ $last * 16 }>,
+        irq_stub::<{ $last * 16 + 1 }>,
+        irq_stub::<{ $last * 16 + 2 }>,
+        irq_stub::<{ $last * 16 + 3 }>,
+        irq_stub::<{ $last * 16 + 4 }>,
+        irq_stub::<{ $last * 16 + 5 }>,
+        irq_stub::<{ $last * 16 + 6 }>,
+        irq_stub::<{ $last * 16 + 7 }>,
+        irq_stub::<{ $last * 16 + 8 }>,
+        irq_stub::<{ $last * 16 + 9 }>,
+        irq_stub::<{ $last * 16 + 10 }>,
+        irq_stub::<{ $last * 16 + 11 }>,
+        irq_stub::<{ $last * 16 + 12 }>,
+        irq_stub::<{ $last * 16 + 13 }>,
+        irq_stub::<{ $last * 16 + 14 }>,
+        ]
     };
 }
 
+/// The entry points of vectors 32 to 254, in order.
+static STUBS: [HandlerFunc; SPURIOUS_VECTOR as usize - FIRST_IRQ_VECTOR as usize] =
+    stubs!(2 3 4 5 6 7 8 9 10 11 12 13 14; 15);
+
+extern "x86-interrupt" fn handle_spurious(_: InterruptStackFrame) {
+    // A spurious interrupt is not in service, so it must not be EOI'd: the EOI would retire a real interrupt instead
+}
+
+extern "x86-interrupt" fn irq_stub<const VECTOR: u8>(stack_frame: InterruptStackFrame) {
+    debug!("Interrupt received for int {}", VECTOR);
+    if let Some(funcs) = IRQ_FUNCS.read().get(&VECTOR) {
+        funcs.iter().for_each(|(id, func)| {
+            debug!("Calling func {:X}", id.0);
+            (func)(*stack_frame);
+        });
+    }
+    // A level-triggered line is still asserted until a handler quiets the device, so the EOI waits for them
+    signal_eoi();
+}
+
 extern "x86-interrupt" fn handle_breakpoint(stack_frame: InterruptStackFrame) {
     // All we do here is notify the user and continue on.
     info!(
@@ -404,8 +282,28 @@ extern "x86-interrupt" fn handle_page_fault(
     error_code: PageFaultErrorCode,
 ) {
     use crate::idle_forever;
-    use x86_64::registers::control::Cr2;
-    let addr = Cr2::read();
+    use x86_64::{registers::control::Cr2, VirtAddr};
+    let addr = crate::memory::canonicalize(Cr2::read_raw());
+    // With 5-level paging the address may be outside the 48-bit canonical range `VirtAddr` holds; nothing the kernel
+    // maps lives there, so such faults are never handled
+    if let Ok(vaddr) = VirtAddr::try_new(addr) {
+        if crate::memory::vm::handle_fault(vaddr, error_code) {
+            return;
+        }
+    }
+    if crate::memory::stack::guard_owner(addr, |owner| {
+        error!(
+            "Kernel stack overflow in task {}: RIP = {:X}, RSP = {:X}, fault address {:X}h",
+            owner,
+            frame.instruction_pointer.as_u64(),
+            frame.stack_pointer.as_u64(),
+            addr
+        )
+    })
+    .is_some()
+    {
+        idle_forever();
+    }
     error!(
         "Page fault: {} while {} memory address {:X}h",
         if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
@@ -436,7 +334,7 @@ extern "x86-interrupt" fn handle_page_fault(
         } else {
             "reading from"
         },
-        addr.as_u64()
+        addr
     );
     error!(
         "Details:\nRegisters: RIP = {:X}\tCS = {:X}\tflags = {:X}\tRSP = {:X}\tSS = {:X}",
@@ -541,229 +439,6 @@ extern "x86-interrupt" fn handle_security_exception(f: InterruptStackFrame, erro
     idle_forever();
 }
 
-gen_interrupt_fn!(handle_keyboard, 33);
-gen_interrupt_fn!(handle_cascade, 34);
-gen_interrupt_fn!(handle_uart1, 35);
-gen_interrupt_fn!(handle_serial1, 36);
-gen_interrupt_fn!(handle_parallel, 37);
-gen_interrupt_fn!(handle_floppy, 38);
-gen_interrupt_fn!(handle_lpt1, 39);
-gen_interrupt_fn!(handle_acpi, 41);
-gen_interrupt_fn!(handle_open1, 42);
-gen_interrupt_fn!(handle_open2, 43);
-gen_interrupt_fn!(handle_mouse, 44);
-gen_interrupt_fn!(handle_coprocessor, 45);
-gen_interrupt_fn!(handle_primary_ata, 46);
-gen_interrupt_fn!(handle_secondary_ata, 47);
-gen_interrupt_fn!(handle_irq48, 48);
-gen_interrupt_fn!(handle_irq49, 49);
-gen_interrupt_fn!(handle_irq50, 50);
-gen_interrupt_fn!(handle_irq51, 51);
-gen_interrupt_fn!(handle_irq52, 52);
-gen_interrupt_fn!(handle_irq53, 53);
-gen_interrupt_fn!(handle_irq54, 54);
-gen_interrupt_fn!(handle_irq55, 55);
-gen_interrupt_fn!(handle_irq56, 56);
-gen_interrupt_fn!(handle_irq57, 57);
-gen_interrupt_fn!(handle_irq58, 58);
-gen_interrupt_fn!(handle_irq59, 59);
-gen_interrupt_fn!(handle_irq60, 60);
-gen_interrupt_fn!(handle_irq61, 61);
-gen_interrupt_fn!(handle_irq62, 62);
-gen_interrupt_fn!(handle_irq63, 63);
-gen_interrupt_fn!(handle_irq64, 64);
-gen_interrupt_fn!(handle_irq65, 65);
-gen_interrupt_fn!(handle_irq66, 66);
-gen_interrupt_fn!(handle_irq67, 67);
-gen_interrupt_fn!(handle_irq68, 68);
-gen_interrupt_fn!(handle_irq69, 69);
-gen_interrupt_fn!(handle_irq70, 70);
-gen_interrupt_fn!(handle_irq71, 71);
-gen_interrupt_fn!(handle_irq72, 72);
-gen_interrupt_fn!(handle_irq73, 73);
-gen_interrupt_fn!(handle_irq74, 74);
-gen_interrupt_fn!(handle_irq75, 75);
-gen_interrupt_fn!(handle_irq76, 76);
-gen_interrupt_fn!(handle_irq77, 77);
-gen_interrupt_fn!(handle_irq78, 78);
-gen_interrupt_fn!(handle_irq79, 79);
-gen_interrupt_fn!(handle_irq80, 80);
-gen_interrupt_fn!(handle_irq81, 81);
-gen_interrupt_fn!(handle_irq82, 82);
-gen_interrupt_fn!(handle_irq83, 83);
-gen_interrupt_fn!(handle_irq84, 84);
-gen_interrupt_fn!(handle_irq85, 85);
-gen_interrupt_fn!(handle_irq86, 86);
-gen_interrupt_fn!(handle_irq87, 87);
-gen_interrupt_fn!(handle_irq88, 88);
-gen_interrupt_fn!(handle_irq89, 89);
-gen_interrupt_fn!(handle_irq90, 90);
-gen_interrupt_fn!(handle_irq91, 91);
-gen_interrupt_fn!(handle_irq92, 92);
-gen_interrupt_fn!(handle_irq93, 93);
-gen_interrupt_fn!(handle_irq94, 94);
-gen_interrupt_fn!(handle_irq95, 95);
-gen_interrupt_fn!(handle_irq96, 96);
-gen_interrupt_fn!(handle_irq97, 97);
-gen_interrupt_fn!(handle_irq98, 98);
-gen_interrupt_fn!(handle_irq99, 99);
-gen_interrupt_fn!(handle_irq100, 100);
-gen_interrupt_fn!(handle_irq101, 101);
-gen_interrupt_fn!(handle_irq102, 102);
-gen_interrupt_fn!(handle_irq103, 103);
-gen_interrupt_fn!(handle_irq104, 104);
-gen_interrupt_fn!(handle_irq105, 105);
-gen_interrupt_fn!(handle_irq106, 106);
-gen_interrupt_fn!(handle_irq107, 107);
-gen_interrupt_fn!(handle_irq108, 108);
-gen_interrupt_fn!(handle_irq109, 109);
-gen_interrupt_fn!(handle_irq110, 110);
-gen_interrupt_fn!(handle_irq111, 111);
-gen_interrupt_fn!(handle_irq112, 112);
-gen_interrupt_fn!(handle_irq113, 113);
-gen_interrupt_fn!(handle_irq114, 114);
-gen_interrupt_fn!(handle_irq115, 115);
-gen_interrupt_fn!(handle_irq116, 116);
-gen_interrupt_fn!(handle_irq117, 117);
-gen_interrupt_fn!(handle_irq118, 118);
-gen_interrupt_fn!(handle_irq119, 119);
-gen_interrupt_fn!(handle_irq120, 120);
-gen_interrupt_fn!(handle_irq121, 121);
-gen_interrupt_fn!(handle_irq122, 122);
-gen_interrupt_fn!(handle_irq123, 123);
-gen_interrupt_fn!(handle_irq124, 124);
-gen_interrupt_fn!(handle_irq125, 125);
-gen_interrupt_fn!(handle_irq126, 126);
-gen_interrupt_fn!(handle_irq127, 127);
-gen_interrupt_fn!(handle_irq128, 128);
-gen_interrupt_fn!(handle_irq129, 129);
-gen_interrupt_fn!(handle_irq130, 130);
-gen_interrupt_fn!(handle_irq131, 131);
-gen_interrupt_fn!(handle_irq132, 132);
-gen_interrupt_fn!(handle_irq133, 133);
-gen_interrupt_fn!(handle_irq134, 134);
-gen_interrupt_fn!(handle_irq135, 135);
-gen_interrupt_fn!(handle_irq136, 136);
-gen_interrupt_fn!(handle_irq137, 137);
-gen_interrupt_fn!(handle_irq138, 138);
-gen_interrupt_fn!(handle_irq139, 139);
-gen_interrupt_fn!(handle_irq140, 140);
-gen_interrupt_fn!(handle_irq141, 141);
-gen_interrupt_fn!(handle_irq142, 142);
-gen_interrupt_fn!(handle_irq143, 143);
-gen_interrupt_fn!(handle_irq144, 144);
-gen_interrupt_fn!(handle_irq145, 145);
-gen_interrupt_fn!(handle_irq146, 146);
-gen_interrupt_fn!(handle_irq147, 147);
-gen_interrupt_fn!(handle_irq148, 148);
-gen_interrupt_fn!(handle_irq149, 149);
-gen_interrupt_fn!(handle_irq150, 150);
-gen_interrupt_fn!(handle_irq151, 151);
-gen_interrupt_fn!(handle_irq152, 152);
-gen_interrupt_fn!(handle_irq153, 153);
-gen_interrupt_fn!(handle_irq154, 154);
-gen_interrupt_fn!(handle_irq155, 155);
-gen_interrupt_fn!(handle_irq156, 156);
-gen_interrupt_fn!(handle_irq157, 157);
-gen_interrupt_fn!(handle_irq158, 158);
-gen_interrupt_fn!(handle_irq159, 159);
-gen_interrupt_fn!(handle_irq160, 160);
-gen_interrupt_fn!(handle_irq161, 161);
-gen_interrupt_fn!(handle_irq162, 162);
-gen_interrupt_fn!(handle_irq163, 163);
-gen_interrupt_fn!(handle_irq164, 164);
-gen_interrupt_fn!(handle_irq165, 165);
-gen_interrupt_fn!(handle_irq166, 166);
-gen_interrupt_fn!(handle_irq167, 167);
-gen_interrupt_fn!(handle_irq168, 168);
-gen_interrupt_fn!(handle_irq169, 169);
-gen_interrupt_fn!(handle_irq170, 170);
-gen_interrupt_fn!(handle_irq171, 171);
-gen_interrupt_fn!(handle_irq172, 172);
-gen_interrupt_fn!(handle_irq173, 173);
-gen_interrupt_fn!(handle_irq174, 174);
-gen_interrupt_fn!(handle_irq175, 175);
-gen_interrupt_fn!(handle_irq176, 176);
-gen_interrupt_fn!(handle_irq177, 177);
-gen_interrupt_fn!(handle_irq178, 178);
-gen_interrupt_fn!(handle_irq179, 179);
-gen_interrupt_fn!(handle_irq180, 180);
-gen_interrupt_fn!(handle_irq181, 181);
-gen_interrupt_fn!(handle_irq182, 182);
-gen_interrupt_fn!(handle_irq183, 183);
-gen_interrupt_fn!(handle_irq184, 184);
-gen_interrupt_fn!(handle_irq185, 185);
-gen_interrupt_fn!(handle_irq186, 186);
-gen_interrupt_fn!(handle_irq187, 187);
-gen_interrupt_fn!(handle_irq188, 188);
-gen_interrupt_fn!(handle_irq189, 189);
-gen_interrupt_fn!(handle_irq190, 190);
-gen_interrupt_fn!(handle_irq191, 191);
-gen_interrupt_fn!(handle_irq192, 192);
-gen_interrupt_fn!(handle_irq193, 193);
-gen_interrupt_fn!(handle_irq194, 194);
-gen_interrupt_fn!(handle_irq195, 195);
-gen_interrupt_fn!(handle_irq196, 196);
-gen_interrupt_fn!(handle_irq197, 197);
-gen_interrupt_fn!(handle_irq198, 198);
-gen_interrupt_fn!(handle_irq199, 199);
-gen_interrupt_fn!(handle_irq200, 200);
-gen_interrupt_fn!(handle_irq201, 201);
-gen_interrupt_fn!(handle_irq202, 202);
-gen_interrupt_fn!(handle_irq203, 203);
-gen_interrupt_fn!(handle_irq204, 204);
-gen_interrupt_fn!(handle_irq205, 205);
-gen_interrupt_fn!(handle_irq206, 206);
-gen_interrupt_fn!(handle_irq207, 207);
-gen_interrupt_fn!(handle_irq208, 208);
-gen_interrupt_fn!(handle_irq209, 209);
-gen_interrupt_fn!(handle_irq210, 210);
-gen_interrupt_fn!(handle_irq211, 211);
-gen_interrupt_fn!(handle_irq212, 212);
-gen_interrupt_fn!(handle_irq213, 213);
-gen_interrupt_fn!(handle_irq214, 214);
-gen_interrupt_fn!(handle_irq215, 215);
-gen_interrupt_fn!(handle_irq216, 216);
-gen_interrupt_fn!(handle_irq217, 217);
-gen_interrupt_fn!(handle_irq218, 218);
-gen_interrupt_fn!(handle_irq219, 219);
-gen_interrupt_fn!(handle_irq220, 220);
-gen_interrupt_fn!(handle_irq221, 221);
-gen_interrupt_fn!(handle_irq222, 222);
-gen_interrupt_fn!(handle_irq223, 223);
-gen_interrupt_fn!(handle_irq224, 224);
-gen_interrupt_fn!(handle_irq225, 225);
-gen_interrupt_fn!(handle_irq226, 226);
-gen_interrupt_fn!(handle_irq227, 227);
-gen_interrupt_fn!(handle_irq228, 228);
-gen_interrupt_fn!(handle_irq229, 229);
-gen_interrupt_fn!(handle_irq230, 230);
-gen_interrupt_fn!(handle_irq231, 231);
-gen_interrupt_fn!(handle_irq232, 232);
-gen_interrupt_fn!(handle_irq233, 233);
-gen_interrupt_fn!(handle_irq234, 234);
-gen_interrupt_fn!(handle_irq235, 235);
-gen_interrupt_fn!(handle_irq236, 236);
-gen_interrupt_fn!(handle_irq237, 237);
-gen_interrupt_fn!(handle_irq238, 238);
-gen_interrupt_fn!(handle_irq239, 239);
-gen_interrupt_fn!(handle_irq240, 240);
-gen_interrupt_fn!(handle_irq241, 241);
-gen_interrupt_fn!(handle_irq242, 242);
-gen_interrupt_fn!(handle_irq243, 243);
-gen_interrupt_fn!(handle_irq244, 244);
-gen_interrupt_fn!(handle_irq245, 245);
-gen_interrupt_fn!(handle_irq246, 246);
-gen_interrupt_fn!(handle_irq247, 247);
-gen_interrupt_fn!(handle_irq248, 248);
-gen_interrupt_fn!(handle_irq249, 249);
-gen_interrupt_fn!(handle_irq250, 250);
-gen_interrupt_fn!(handle_irq251, 251);
-gen_interrupt_fn!(handle_irq252, 252);
-gen_interrupt_fn!(handle_irq253, 253);
-gen_interrupt_fn!(handle_irq254, 254);
-gen_interrupt_fn!(handle_irq255, 255);
-
 fn is_apic_available() -> bool {
     let apic_available_in_msr = {
         let apicbase = Msr::new(0x1B);
@@ -777,6 +452,12 @@ fn apic_addr() -> u64 {
     unsafe { apicbase.read().get_bits(12..52) }
 }
 
+/// Returns the x2APIC ID of the processor this runs on.
+#[inline]
+pub fn local_apic_id() -> u32 {
+    unsafe { Msr::new(0x802).read() as u32 }
+}
+
 #[inline]
 fn signal_eoi() {
     let mut eoi = Msr::new(0x80B);
@@ -793,8 +474,9 @@ pub fn get_tick_count() -> u64 {
 /// Sleeps for the given duration of nanoseconds.
 pub fn sleep_for(duration: u64) {
     let hpet_info = get_hpet_info().unwrap();
-    let intsts: VolAddress<u64, Safe, Safe> =
-        unsafe { VolAddress::new(hpet_info.base_address + 0x20) };
+    let hpet = get_hpet_registers().expect("HPET registers are not mapped");
+    // The HPET mapping lives forever, so these addresses stay valid
+    let intsts: VolAddress<u64, Safe, Safe> = unsafe { hpet.reg(0x20) };
     if duration < (hpet_info.clock_tick_unit as u64) {
         warn!(
             "Duration {} is less than minimum clock tick duration for HPET of {}; adjusting delay to {}",
@@ -804,8 +486,7 @@ pub fn sleep_for(duration: u64) {
         );
     }
     // Set HPET timer 0 in non-periodic mode
-    let t0cfg: VolAddress<usize, Safe, Safe> =
-        unsafe { VolAddress::new(hpet_info.base_address + (0x20 * 0) + 0x100) };
+    let t0cfg: VolAddress<usize, Safe, Safe> = unsafe { hpet.reg((0x20 * 0) + 0x100) };
     let mut cfg = t0cfg.read();
     let oldcfg = cfg;
     if cfg.get_bit(4) {
@@ -815,8 +496,7 @@ pub fn sleep_for(duration: u64) {
     cfg.set_bit(1, true);
     cfg.set_bit(8, false);
     t0cfg.write(cfg);
-    let t0comp: VolAddress<u64, Safe, Safe> =
-        unsafe { VolAddress::new(hpet_info.base_address + (0x20 * 0) + 0x108) };
+    let t0comp: VolAddress<u64, Safe, Safe> = unsafe { hpet.reg((0x20 * 0) + 0x108) };
     let duration = if duration < (hpet_info.clock_tick_unit as u64) {
         t0comp.read() + (hpet_info.clock_tick_unit as u64) + duration
     } else {
@@ -836,34 +516,137 @@ pub fn sleep_for(duration: u64) {
     t0cfg.write(oldcfg);
 }
 
-/// Registers the given interrupt handler at the given interrupt. Note that this must be an interrupt
-/// greater than or equal to 32.
-pub fn register_interrupt_handler(interrupt: u8, func: InterruptHandler) -> usize {
-    x86_64::instructions::interrupts::disable();
-    debug!("Registering handler for int. {:X} ({:p})", interrupt, &func);
-    let mut idx = 0usize;
-    let mut tables = IRQ_FUNCS.write();
-    if let Some(funcs) = tables.get_mut(&interrupt) {
-        funcs.push(func);
-        idx = funcs.len();
+/// Identifies a handler registered on an [`IrqVector`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct HandlerId(u64);
+
+/// An interrupt vector allocated with [`alloc_vector`]. Handlers registered on it run, in the order they were
+/// registered, whenever the vector is raised. The vector is freed, and every handler still registered on it
+/// unregistered, when this is dropped.
+#[derive(Debug)]
+pub struct IrqVector {
+    vector: u8,
+}
+
+/// Allocates a free vector in the given priority class (the upper 4 bits of the vector; the local APIC delivers
+/// higher classes first), or failing that in the closest lower class down to [`MIN_PRIORITY_CLASS`]. Classes above
+/// [`MAX_PRIORITY_CLASS`] are treated as the highest one. Returns `None` if no such vector is free.
+pub fn alloc_vector(priority_class: u8) -> Option<IrqVector> {
+    alloc_block(priority_class, 1).map(|vector| {
+        debug!("Allocated int. {:X}", vector);
+        IrqVector { vector }
+    })
+}
+
+/// Allocates `count` consecutive vectors starting at a multiple of `count`, all in the same priority class, as
+/// multiple-message MSI needs. Classes are searched as with [`alloc_vector`]. Returns `None` if `count` is not a
+/// power of two of at most 16, or no such block is free.
+pub fn alloc_vectors(priority_class: u8, count: u8) -> Option<Vec<IrqVector>> {
+    if !count.is_power_of_two() || count > 16 {
+        return None;
     }
-    x86_64::instructions::interrupts::enable();
-    idx
+    let first = alloc_block(priority_class, count)?;
+    debug!("Allocated ints. {:X}-{:X}", first, first + (count - 1));
+    Some(
+        (first..first + count)
+            .map(|vector| IrqVector { vector })
+            .collect(),
+    )
+}
+
+/// Claims `count` free vectors starting at a multiple of `count` in the highest class it can, and returns the first.
+fn alloc_block(priority_class: u8, count: u8) -> Option<u8> {
+    let block = ((1u32 << count) - 1) as u16;
+    (MIN_PRIORITY_CLASS..=priority_class.min(MAX_PRIORITY_CLASS))
+        .rev()
+        .find_map(|class| {
+            let vectors = &VECTORS[usize::from(class)];
+            let mut current = vectors.load(Ordering::Acquire);
+            loop {
+                let index = (0..16)
+                    .step_by(usize::from(count))
+                    .find(|&index| current & block << index == 0)?;
+                match vectors.compare_exchange_weak(
+                    current,
+                    current | block << index,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                ) {
+                    Ok(_) => return Some((class << 4) | index),
+                    Err(actual) => current = actual,
+                }
+            }
+        })
 }
 
-/// Unregisters the given interrupt handler given an interrupt number and function index ID.
-pub fn unregister_interrupt_handler(int: u8, id: usize) -> bool {
-    x86_64::instructions::interrupts::disable();
-    debug!("Unregistering handler for int. {:X} (id {:X})", int, id);
-    let irq = 32_u8.saturating_add(int);
-    if let Some(funcs) = IRQ_FUNCS.write().get_mut(&irq) {
-        if funcs.len() >= id {
-            let _ = funcs.remove(id);
-        } else {
-            x86_64::instructions::interrupts::enable();
-            return false;
-        }
+/// Claims the vector the I/O APIC delivers legacy ISA IRQ `irq` at. Returns `None` if `irq` is not an ISA IRQ or
+/// its vector is already claimed; the timer and RTC vectors always are, as they keep handlers of their own.
+pub fn isa_vector(irq: u8) -> Option<IrqVector> {
+    if irq >= ISA_IRQ_COUNT {
+        return None;
+    }
+    let vectors = &VECTORS[usize::from(FIRST_IRQ_VECTOR >> 4)];
+    if vectors.fetch_or(1 << irq, Ordering::AcqRel) & 1 << irq != 0 {
+        return None;
+    }
+    let vector = FIRST_IRQ_VECTOR + irq;
+    debug!("Claimed int. {:X} for IRQ {}", vector, irq);
+    Some(IrqVector { vector })
+}
+
+impl IrqVector {
+    /// Returns the vector number, as programmed into interrupt sources.
+    #[inline]
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+
+    /// Returns the priority class of the vector.
+    #[inline]
+    pub fn priority_class(&self) -> u8 {
+        self.vector >> 4
+    }
+
+    /// Registers a handler to run whenever the vector is raised.
+    pub fn register(&self, func: InterruptHandler) -> HandlerId {
+        let id = HandlerId(NEXT_HANDLER_ID.fetch_add(1, Ordering::Relaxed));
+        debug!(
+            "Registering handler for int. {:X} ({:p}, id {:X})",
+            self.vector, &func, id.0
+        );
+        without_interrupts(|| {
+            if let Some(funcs) = IRQ_FUNCS.write().get_mut(&self.vector) {
+                funcs.push((id, func));
+            }
+        });
+        id
+    }
+
+    /// Unregisters a handler registered with [`register`](IrqVector::register). Returns false if it is not
+    /// registered on this vector.
+    pub fn unregister(&self, id: HandlerId) -> bool {
+        debug!(
+            "Unregistering handler for int. {:X} (id {:X})",
+            self.vector, id.0
+        );
+        // Dropping the handler happens outside the lock, with interrupts enabled again
+        let removed = without_interrupts(|| {
+            let mut tables = IRQ_FUNCS.write();
+            let funcs = tables.get_mut(&self.vector)?;
+            let index = funcs.iter().position(|&(f, _)| f == id)?;
+            Some(funcs.remove(index))
+        });
+        removed.is_some()
+    }
+}
+
+impl Drop for IrqVector {
+    fn drop(&mut self) {
+        let removed =
+            without_interrupts(|| IRQ_FUNCS.write().get_mut(&self.vector).map(core::mem::take));
+        drop(removed);
+        let _ = VECTORS[usize::from(self.vector >> 4)]
+            .fetch_and(!(1 << (self.vector & 0xF)), Ordering::AcqRel);
+        debug!("Freed int. {:X}", self.vector);
     }
-    x86_64::instructions::interrupts::enable();
-    true
 }
@@ -0,0 +1,323 @@
+// SPDX-License-Identifier: MPL-2.0
+use crate::acpi::get_platform_info;
+use crate::interrupts::{local_apic_id, FIRST_IRQ_VECTOR, ISA_IRQ_COUNT};
+use crate::memory::mmio::{CacheType, IoMapping};
+use acpi::platform::interrupt::{InterruptModel, Polarity, TriggerMode};
+use alloc::vec::Vec;
+use bit_field::BitField;
+use core::fmt;
+use log::*;
+use spin::{mutex::ticket::TicketMutex, Once};
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+
+/// The register select and data window registers, as offsets into an I/O APIC's registers.
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+/// The size of an I/O APIC's register block.
+const REGISTERS_SIZE: u64 = 0x20;
+/// The indirect registers.
+const IOAPICID: u32 = 0x00;
+const IOAPICVER: u32 = 0x01;
+const IOREDTBL: u32 = 0x10;
+/// Bits of the low half of a redirection entry.
+const POLARITY_LOW: usize = 13;
+const TRIGGER_LEVEL: usize = 15;
+const MASKED: usize = 16;
+/// The ISA IRQ that only ever cascades the slave 8259 into the master.
+const CASCADE_IRQ: u8 = 2;
+
+/// The I/O APICs, and where the ISA IRQs are wired to.
+static IO_APICS: Once<IoApics> = Once::new();
+
+/// Errors reported when programming the I/O APICs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum IoApicError {
+    /// The MADT has not been parsed, or describes no I/O APICs.
+    NotInitialized,
+    /// No I/O APIC handles this global system interrupt.
+    NoSuchGsi(u32),
+    /// The processor with this local APIC ID cannot be named in a redirection entry, whose destination is 8 bits wide.
+    UnreachableCpu(u32),
+    /// The vector is a processor exception, and cannot be raised by a device.
+    InvalidVector(u8),
+}
+
+impl fmt::Display for IoApicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IoApicError::NotInitialized => write!(f, "I/O APICs not initialized"),
+            IoApicError::NoSuchGsi(gsi) => write!(f, "no I/O APIC handles GSI {}", gsi),
+            IoApicError::UnreachableCpu(id) => {
+                write!(
+                    f,
+                    "local APIC ID {:X} cannot be an I/O APIC destination",
+                    id
+                )
+            }
+            IoApicError::InvalidVector(vector) => write!(f, "vector {:X} is an exception", vector),
+        }
+    }
+}
+
+/// How an interrupt source signals.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct Signal {
+    active_low: bool,
+    level: bool,
+}
+
+impl Signal {
+    /// ISA interrupts are edge triggered and active high unless the MADT overrides them.
+    const ISA: Signal = Signal {
+        active_low: false,
+        level: false,
+    };
+    /// Interrupts that are not ISA IRQs come from PCI, whose interrupts are level triggered and active low.
+    const PCI: Signal = Signal {
+        active_low: true,
+        level: true,
+    };
+}
+
+/// Where an ISA IRQ is wired to.
+#[derive(Clone, Copy, Debug)]
+struct IsaRoute {
+    gsi: u32,
+    signal: Signal,
+    /// Whether an interrupt source override moved or reconfigured the IRQ.
+    overridden: bool,
+}
+
+#[derive(Debug)]
+struct IoApics {
+    controllers: Vec<IoApic>,
+    isa: [IsaRoute; ISA_IRQ_COUNT as usize],
+}
+
+impl IoApics {
+    /// Returns the I/O APIC handling `gsi`, and the index of its redirection entry there.
+    fn find(&self, gsi: u32) -> Result<(&IoApic, u32), IoApicError> {
+        self.controllers
+            .iter()
+            .find(|c| (c.gsi_base..c.gsi_base + c.entries).contains(&gsi))
+            .map(|c| (c, gsi - c.gsi_base))
+            .ok_or(IoApicError::NoSuchGsi(gsi))
+    }
+
+    /// Returns how `gsi` signals: as its ISA IRQ says, if it has one, or as PCI interrupts do.
+    fn signal(&self, gsi: u32) -> Signal {
+        self.isa
+            .iter()
+            .filter(|r| r.gsi == gsi)
+            .max_by_key(|r| r.overridden)
+            .map_or(Signal::PCI, |r| r.signal)
+    }
+}
+
+/// A single I/O APIC.
+#[derive(Debug)]
+struct IoApic {
+    id: u8,
+    gsi_base: u32,
+    /// The number of redirection entries.
+    entries: u32,
+    /// The register block. Selecting a register and accessing it through the window must not be interleaved.
+    regs: TicketMutex<IoMapping>,
+}
+
+impl IoApic {
+    fn read(&self, reg: u32) -> u32 {
+        without_interrupts(|| {
+            let regs = self.regs.lock();
+            regs.write(IOREGSEL, reg);
+            regs.read(IOWIN)
+        })
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        without_interrupts(|| {
+            let regs = self.regs.lock();
+            regs.write(IOREGSEL, reg);
+            regs.write(IOWIN, value);
+        });
+    }
+
+    /// Programs redirection entry `index`. The entry is masked while it changes, so that no interrupt is delivered
+    /// half-programmed.
+    fn set_entry(&self, index: u32, low: u32, high: u32) {
+        let reg = IOREDTBL + 2 * index;
+        self.write(reg, *self.read(reg).set_bit(MASKED, true));
+        self.write(reg + 1, high);
+        self.write(reg, low);
+    }
+
+    fn set_masked(&self, index: u32, masked: bool) {
+        let reg = IOREDTBL + 2 * index;
+        self.write(reg, *self.read(reg).set_bit(MASKED, masked));
+    }
+}
+
+/// Discovers the I/O APICs and interrupt source overrides in the MADT, masks the 8259 PICs, and routes every ISA IRQ
+/// to vector [`FIRST_IRQ_VECTOR`]` + irq` on this processor. Every other global system interrupt starts out masked.
+/// Must run once the ACPI tables are loaded; only the first call has any effect.
+#[cold]
+pub fn init() {
+    if IO_APICS.is_completed() {
+        warn!("Got request to reinitialize I/O APICs; ignoring");
+        return;
+    }
+    let apic = match get_platform_info().map(|info| info.interrupt_model) {
+        Ok(InterruptModel::Apic(apic)) => apic,
+        _ => {
+            warn!("MADT describes no APICs; legacy IRQs will not be delivered");
+            return;
+        }
+    };
+    if apic.also_has_legacy_pics {
+        disable_pics();
+    }
+    let controllers: Vec<IoApic> = apic
+        .io_apics
+        .iter()
+        .filter_map(|desc| {
+            let regs = IoMapping::new(u64::from(desc.address), REGISTERS_SIZE, CacheType::Uncached);
+            if regs.is_none() {
+                error!("Cannot map I/O APIC {} at {:X}h", desc.id, desc.address);
+            }
+            let mut ioapic = IoApic {
+                id: desc.id,
+                gsi_base: desc.global_system_interrupt_base,
+                entries: 0,
+                regs: TicketMutex::new(regs?),
+            };
+            let version = ioapic.read(IOAPICVER);
+            ioapic.entries = version.get_bits(16..24) + 1;
+            info!(
+                "Found I/O APIC {} (hardware ID {:X}, version {:X}) at {:X}h, GSIs {}-{}",
+                ioapic.id,
+                ioapic.read(IOAPICID).get_bits(24..28),
+                version.get_bits(0..8),
+                desc.address,
+                ioapic.gsi_base,
+                ioapic.gsi_base + ioapic.entries - 1
+            );
+            (0..ioapic.entries).for_each(|index| ioapic.set_masked(index, true));
+            Some(ioapic)
+        })
+        .collect();
+    let mut isa = [IsaRoute {
+        gsi: 0,
+        signal: Signal::ISA,
+        overridden: false,
+    }; ISA_IRQ_COUNT as usize];
+    isa.iter_mut()
+        .zip(0..)
+        .for_each(|(route, irq)| route.gsi = irq);
+    apic.interrupt_source_overrides
+        .iter()
+        .filter(|iso| iso.isa_source < ISA_IRQ_COUNT)
+        .for_each(|iso| {
+            info!(
+                "IRQ {} is GSI {}, {:?}, {:?}",
+                iso.isa_source, iso.global_system_interrupt, iso.polarity, iso.trigger_mode
+            );
+            isa[usize::from(iso.isa_source)] = IsaRoute {
+                gsi: iso.global_system_interrupt,
+                signal: Signal {
+                    active_low: matches!(iso.polarity, Polarity::ActiveLow),
+                    level: matches!(iso.trigger_mode, TriggerMode::Level),
+                },
+                overridden: true,
+            };
+        });
+    let ioapics = IO_APICS.call_once(|| IoApics { controllers, isa });
+    let bsp = local_apic_id();
+    (0..ISA_IRQ_COUNT)
+        .filter(|&irq| irq != CASCADE_IRQ)
+        .for_each(|irq| {
+            let route = ioapics.isa[usize::from(irq)];
+            // An IRQ whose line another IRQ was moved onto is not wired anywhere
+            let taken = ioapics
+                .isa
+                .iter()
+                .zip(0..)
+                .any(|(other, i)| i != irq && other.overridden && other.gsi == route.gsi);
+            if taken && !route.overridden {
+                return;
+            }
+            if let Err(e) = route_gsi(route.gsi, FIRST_IRQ_VECTOR + irq, bsp) {
+                warn!("Cannot route IRQ {}: {}", irq, e);
+            }
+        });
+}
+
+/// Returns the global system interrupt ISA IRQ `irq` is wired to, or `None` if `irq` is not an ISA IRQ or the I/O
+/// APICs are not initialized.
+pub fn isa_gsi(irq: u8) -> Option<u32> {
+    IO_APICS
+        .get()?
+        .isa
+        .get(usize::from(irq))
+        .map(|route| route.gsi)
+}
+
+/// Routes global system interrupt `gsi` to `vector` on the processor with local APIC ID `dest_cpu`, and unmasks it.
+/// ISA IRQs keep the polarity and trigger mode the MADT gives them; every other interrupt is taken to be a level
+/// triggered, active low PCI interrupt.
+pub fn route_gsi(gsi: u32, vector: u8, dest_cpu: u32) -> Result<(), IoApicError> {
+    if vector < FIRST_IRQ_VECTOR {
+        return Err(IoApicError::InvalidVector(vector));
+    }
+    let dest = u8::try_from(dest_cpu).map_err(|_| IoApicError::UnreachableCpu(dest_cpu))?;
+    let ioapics = IO_APICS.get().ok_or(IoApicError::NotInitialized)?;
+    let (ioapic, index) = ioapics.find(gsi)?;
+    let signal = ioapics.signal(gsi);
+    // Fixed delivery to a single processor in physical destination mode
+    let mut low = u32::from(vector);
+    low.set_bit(POLARITY_LOW, signal.active_low);
+    low.set_bit(TRIGGER_LEVEL, signal.level);
+    ioapic.set_entry(index, low, u32::from(dest) << 24);
+    debug!(
+        "Routed GSI {} to int. {:X} on CPU {:X} ({}, {})",
+        gsi,
+        vector,
+        dest,
+        if signal.level { "level" } else { "edge" },
+        if signal.active_low {
+            "active low"
+        } else {
+            "active high"
+        }
+    );
+    Ok(())
+}
+
+/// Masks global system interrupt `gsi`, so that it is not delivered until it is unmasked again.
+pub fn mask(gsi: u32) -> Result<(), IoApicError> {
+    let (ioapic, index) = IO_APICS
+        .get()
+        .ok_or(IoApicError::NotInitialized)?
+        .find(gsi)?;
+    ioapic.set_masked(index, true);
+    Ok(())
+}
+
+/// Unmasks global system interrupt `gsi`. It should have been routed with [`route_gsi`] first.
+pub fn unmask(gsi: u32) -> Result<(), IoApicError> {
+    let (ioapic, index) = IO_APICS
+        .get()
+        .ok_or(IoApicError::NotInitialized)?
+        .find(gsi)?;
+    ioapic.set_masked(index, false);
+    Ok(())
+}
+
+/// Masks every line of both 8259 PICs, which would otherwise deliver the ISA IRQs a second time.
+#[cold]
+fn disable_pics() {
+    info!("Masking 8259 PICs");
+    unsafe {
+        Port::<u8>::new(0x21).write(0xFF);
+        Port::<u8>::new(0xA1).write(0xFF);
+    }
+}
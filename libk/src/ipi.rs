@@ -0,0 +1,370 @@
+// SPDX-License-Identifier: MPL-2.0
+use crate::acpi::get_platform_info;
+use crate::apic_timer;
+use crate::interrupts::{
+    alloc_vector, local_apic_id, IrqVector, FIRST_IRQ_VECTOR, MAX_PRIORITY_CLASS,
+};
+use acpi::platform::ProcessorState;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use bit_field::BitField;
+use core::arch::x86_64::_rdtsc;
+use core::fmt;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, Ordering};
+use log::*;
+use spin::{mutex::ticket::TicketMutex, Once};
+use x86_64::{
+    instructions::interrupts::without_interrupts, registers::model_specific::Msr,
+    structures::idt::InterruptStackFrameValue,
+};
+
+/// The x2APIC interrupt command register.
+const ICR: u32 = 0x830;
+/// Fields of the interrupt command register.
+const ICR_DELIVERY_MODE: core::ops::Range<usize> = 8..11;
+const ICR_LEVEL_ASSERT: usize = 14;
+const ICR_SHORTHAND: core::ops::Range<usize> = 18..20;
+const ICR_DESTINATION: core::ops::Range<usize> = 32..64;
+/// The most remote calls that can be waiting to run at once, across all processors.
+const MAX_PENDING_CALLS: usize = 64;
+/// How long [`call_on_cpu`] waits for a remote call to finish, in nanoseconds.
+const CALL_TIMEOUT_NS: u64 = 100_000_000;
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// The vector remote calls are delivered at.
+static CALL_VECTOR: Once<IrqVector> = Once::new();
+/// The local APIC IDs of the processors the MADT lists as usable.
+static CPUS: Once<Vec<u32>> = Once::new();
+/// The local APIC IDs of the processors that are running with interrupts enabled, and so can take remote calls.
+static ONLINE: TicketMutex<Vec<u32>> = TicketMutex::new(Vec::new());
+/// Remote calls waiting for their processor to pick them up.
+static CALLS: TicketMutex<heapless::Vec<Call, MAX_PENDING_CALLS>> =
+    TicketMutex::new(heapless::Vec::new());
+/// Remote calls that timed out after their processor had picked them up. They are freed once they finish, by the
+/// next caller of [`call_on_cpu`], since the processor running them must not touch the heap.
+static ABANDONED: TicketMutex<Vec<Box<dyn Job>>> = TicketMutex::new(Vec::new());
+
+/// The message an IPI carries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum IpiMessage {
+    /// An ordinary interrupt at the given vector.
+    Fixed(u8),
+    /// A non-maskable interrupt.
+    Nmi,
+    /// Resets the target to its wait-for-SIPI state.
+    Init,
+    /// Starts a processor waiting for a SIPI in real mode at the given page (the start address divided by 4096).
+    StartUp(u8),
+}
+
+impl IpiMessage {
+    #[inline]
+    fn delivery_mode(self) -> u64 {
+        match self {
+            IpiMessage::Fixed(_) => 0b000,
+            IpiMessage::Nmi => 0b100,
+            IpiMessage::Init => 0b101,
+            IpiMessage::StartUp(_) => 0b110,
+        }
+    }
+
+    #[inline]
+    fn vector(self) -> u8 {
+        match self {
+            IpiMessage::Fixed(vector) | IpiMessage::StartUp(vector) => vector,
+            IpiMessage::Nmi | IpiMessage::Init => 0,
+        }
+    }
+}
+
+/// The processors an IPI goes to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Destination {
+    /// The processor with the given local APIC ID.
+    Cpu(u32),
+    /// The processor sending the IPI. Only fixed IPIs can be sent this way.
+    SelfOnly,
+    /// Every processor, the sender included. INIT and SIPI cannot be sent this way.
+    All,
+    /// Every processor but the sender.
+    AllButSelf,
+}
+
+impl Destination {
+    #[inline]
+    fn shorthand(self) -> u64 {
+        match self {
+            Destination::Cpu(_) => 0b00,
+            Destination::SelfOnly => 0b01,
+            Destination::All => 0b10,
+            Destination::AllButSelf => 0b11,
+        }
+    }
+}
+
+/// Errors reported when sending IPIs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum IpiError {
+    /// Remote calls are not set up yet.
+    NotInitialized,
+    /// No usable processor has the local APIC ID asked for.
+    NoSuchCpu,
+    /// The processor has not been marked online, so it cannot take remote calls yet.
+    Offline,
+    /// Fixed IPIs cannot be sent at exception vectors.
+    InvalidVector(u8),
+    /// The message cannot be sent to that destination.
+    InvalidDestination,
+    /// Too many remote calls are waiting to run.
+    Busy,
+    /// The remote call did not finish in time.
+    Timeout,
+}
+
+impl fmt::Display for IpiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpiError::NotInitialized => write!(f, "remote calls not initialized"),
+            IpiError::NoSuchCpu => write!(f, "no usable processor has that local APIC ID"),
+            IpiError::Offline => write!(f, "processor is not online"),
+            IpiError::InvalidVector(vector) => write!(f, "vector {:X} is an exception", vector),
+            IpiError::InvalidDestination => write!(f, "message cannot be sent to that destination"),
+            IpiError::Busy => write!(f, "too many remote calls pending"),
+            IpiError::Timeout => write!(f, "remote call timed out"),
+        }
+    }
+}
+
+/// A remote call waiting for its processor. The job is boxed by the caller, which frees it once it has finished or
+/// been taken back off the queue.
+#[derive(Clone, Copy, Debug)]
+struct Call {
+    target: u32,
+    job: *mut dyn Job,
+}
+
+// Safety: the job is only run on the target processor, and is not freed while it can still be run
+unsafe impl Send for Call {}
+
+/// The function of a remote call, along with its result and whether it has finished.
+trait Job: Send {
+    /// Runs the function. Only the first run does anything.
+    fn run(&mut self);
+    /// Returns the flag set once the function has run.
+    fn done(&self) -> &AtomicBool;
+}
+
+struct Slot<F, R> {
+    func: Option<F>,
+    ret: Option<R>,
+    done: AtomicBool,
+}
+
+impl<F: FnOnce() -> R + Send, R: Send> Job for Slot<F, R> {
+    fn run(&mut self) {
+        self.ret = self.func.take().map(|f| f());
+    }
+
+    fn done(&self) -> &AtomicBool {
+        &self.done
+    }
+}
+
+/// Records the processors the MADT lists, sets up the vector remote calls are delivered at and marks this processor
+/// online. Must run on the bootstrap processor once the ACPI tables are loaded and interrupts are enabled.
+#[cold]
+pub async fn init() {
+    if CALL_VECTOR.is_completed() {
+        warn!("Got request to reinitialize IPIs; ignoring");
+        return;
+    }
+    let cpus = CPUS.call_once(|| {
+        get_platform_info()
+            .ok()
+            .and_then(|info| info.processor_info)
+            .map(|info| {
+                core::iter::once(info.boot_processor)
+                    .chain(info.application_processors)
+                    .filter(|p| p.state != ProcessorState::Disabled)
+                    .map(|p| p.local_apic_id)
+                    .collect()
+            })
+            .unwrap_or_else(|| [local_apic_id()].to_vec())
+    });
+    match alloc_vector(MAX_PRIORITY_CLASS) {
+        Some(vector) => {
+            let _ = vector.register(Box::new(handle_calls));
+            info!(
+                "Remote calls at int. {:X}; {} processor(s) usable",
+                vector.vector(),
+                cpus.len()
+            );
+            CALL_VECTOR.call_once(|| vector);
+        }
+        None => error!("No vector free for remote calls"),
+    }
+    mark_online();
+}
+
+/// Marks this processor online, so that remote calls can be sent to it. Every application processor must call this
+/// once its IDT is loaded and it runs with interrupts enabled; [`init`] does so for the bootstrap processor.
+pub fn mark_online() {
+    let me = local_apic_id();
+    let mut online = ONLINE.lock();
+    if !online.contains(&me) {
+        online.push(me);
+    }
+}
+
+/// Returns whether the processor with local APIC ID `cpu` has been marked online.
+pub fn is_online(cpu: u32) -> bool {
+    ONLINE.lock().contains(&cpu)
+}
+
+/// Returns the local APIC IDs of the processors the MADT lists as usable. Empty until [`init`] has run.
+pub fn cpus() -> &'static [u32] {
+    CPUS.get().map_or(&[], |cpus| cpus.as_slice())
+}
+
+/// Sends `message` to `destination` through the interrupt command register.
+///
+/// In x2APIC mode the command register has no delivery status bit, so sending is fire-and-forget: nothing says
+/// whether or when the IPI arrived. Callers that need to know must have the target acknowledge it, as
+/// [`call_on_cpu`] does.
+pub fn send(message: IpiMessage, destination: Destination) -> Result<(), IpiError> {
+    let icr = command(message, destination)?;
+    without_interrupts(|| unsafe {
+        Msr::new(ICR).write(icr);
+    });
+    Ok(())
+}
+
+/// Runs `f` on the processor with local APIC ID `cpu` and returns what it returns, waiting until it has run. `f` runs
+/// in interrupt context there, so it must be short and must not take locks the interrupted code may hold, the heap
+/// included. Calls to the processor this runs on run `f` directly.
+///
+/// The target must have been marked online with [`mark_online`], which application processors only do once they are
+/// started and running kernel code with interrupts enabled; until then only the calling processor can be reached. If it does not finish the call within 100 ms, as
+/// happens when it runs with interrupts disabled or `f` panics, this fails with [`IpiError::Timeout`]; `f` may still
+/// run later, and its result is then dropped.
+pub fn call_on_cpu<R, F>(cpu: u32, f: F) -> Result<R, IpiError>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    if cpu == local_apic_id() {
+        return Ok(f());
+    }
+    let vector = CALL_VECTOR.get().ok_or(IpiError::NotInitialized)?.vector();
+    let tsc_hz = apic_timer::tsc_frequency().ok_or(IpiError::NotInitialized)?;
+    if !cpus().contains(&cpu) {
+        return Err(IpiError::NoSuchCpu);
+    }
+    if !is_online(cpu) {
+        return Err(IpiError::Offline);
+    }
+    let icr = command(IpiMessage::Fixed(vector), Destination::Cpu(cpu))?;
+    free_abandoned();
+    let slot = Box::into_raw(Box::new(Slot {
+        func: Some(f),
+        ret: None,
+        done: AtomicBool::new(false),
+    }));
+    let call = Call {
+        target: cpu,
+        job: slot,
+    };
+    if without_interrupts(|| CALLS.lock().push(call)).is_err() {
+        drop(unsafe { Box::from_raw(slot) });
+        return Err(IpiError::Busy);
+    }
+    // The IPI is fire-and-forget; the target setting the call's flag is the only sign it arrived
+    without_interrupts(|| unsafe {
+        Msr::new(ICR).write(icr);
+    });
+    let timeout = u128::from(tsc_hz) * u128::from(CALL_TIMEOUT_NS) / u128::from(NANOS_PER_SEC);
+    let deadline = unsafe { _rdtsc() }.saturating_add(timeout as u64);
+    while !unsafe { &*slot }.done.load(Ordering::Acquire) {
+        if unsafe { _rdtsc() } >= deadline {
+            if take_back(slot) {
+                drop(unsafe { Box::from_raw(slot) });
+                return Err(IpiError::Timeout);
+            }
+            // The target is running the call already; it may yet finish, so it is freed once it has
+            if !unsafe { &*slot }.done.load(Ordering::Acquire) {
+                ABANDONED.lock().push(unsafe { Box::from_raw(slot) });
+                return Err(IpiError::Timeout);
+            }
+            break;
+        }
+        spin_loop();
+    }
+    let slot = unsafe { Box::from_raw(slot) };
+    Ok(slot.ret.expect("Remote call finished without running"))
+}
+
+/// Takes the call running `job` back off the queue. Returns false if its processor has already picked it up.
+fn take_back(job: *mut dyn Job) -> bool {
+    without_interrupts(|| {
+        let mut calls = CALLS.lock();
+        calls
+            .iter()
+            .position(|c| c.job.cast::<()>() == job.cast::<()>())
+            .map(|index| calls.swap_remove(index))
+            .is_some()
+    })
+}
+
+/// Frees the abandoned remote calls that have finished since.
+fn free_abandoned() {
+    ABANDONED
+        .lock()
+        .retain(|job| !job.done().load(Ordering::Acquire));
+}
+
+/// Builds the interrupt command register value for `message` to `destination`, refusing the combinations the local
+/// APIC does not allow.
+fn command(message: IpiMessage, destination: Destination) -> Result<u64, IpiError> {
+    match (message, destination) {
+        (IpiMessage::Fixed(vector), _) if vector < FIRST_IRQ_VECTOR => {
+            return Err(IpiError::InvalidVector(vector))
+        }
+        (IpiMessage::Fixed(_), _) => (),
+        (_, Destination::SelfOnly) => return Err(IpiError::InvalidDestination),
+        (IpiMessage::Init | IpiMessage::StartUp(_), Destination::All) => {
+            return Err(IpiError::InvalidDestination)
+        }
+        _ => (),
+    }
+    let mut icr = u64::from(message.vector());
+    icr.set_bits(ICR_DELIVERY_MODE, message.delivery_mode());
+    icr.set_bit(ICR_LEVEL_ASSERT, true);
+    icr.set_bits(ICR_SHORTHAND, destination.shorthand());
+    if let Destination::Cpu(cpu) = destination {
+        icr.set_bits(ICR_DESTINATION, u64::from(cpu));
+    }
+    Ok(icr)
+}
+
+/// Runs the remote calls waiting for this processor.
+fn handle_calls(_: InterruptStackFrameValue) {
+    let me = local_apic_id();
+    loop {
+        let call = {
+            let mut calls = CALLS.lock();
+            calls
+                .iter()
+                .position(|c| c.target == me)
+                .map(|index| calls.swap_remove(index))
+        };
+        match call {
+            Some(call) => {
+                let job = unsafe { &mut *call.job };
+                job.run();
+                job.done().store(true, Ordering::Release);
+            }
+            None => break,
+        }
+    }
+}
@@ -6,6 +6,7 @@
 #![feature(option_result_contains)]
 #![feature(alloc_layout_extra)]
 #![feature(async_closure)]
+#![cfg_attr(feature = "kasan", feature(no_sanitize))]
 #![allow(dead_code)]
 #![forbid(
     absolute_paths_not_starting_with_crate,
@@ -40,17 +41,25 @@
 extern crate alloc;
 /// The acpi module contains acpi initialization routines
 pub mod acpi;
+/// The apic_timer module drives the local APIC timer, which provides the kernel tick.
+pub mod apic_timer;
 /// The gdt module contains basic GDT functionality.
 pub mod gdt;
 /// The interrupts module contains functions to set up the IDT.
 /// It also utilizes full AIO support for keyboards and other devices.
 pub mod interrupts;
+/// The ioapic module routes global system interrupts through the I/O APICs.
+pub mod ioapic;
+/// The ipi module sends inter-processor interrupts and runs functions on other processors.
+pub mod ipi;
 /// The memory module contains functions for managing memory.
 pub mod memory;
 /// The pci module contains functions for reading from PCI devices and enumerating PCI buses.
 pub mod pci;
 /// The rtc modue/le contains RTC initialization code
 pub mod rtc;
+/// The smp module starts the application processors the bootloader parked.
+pub mod smp;
 /// The task module controls cooperative and preemptive multitasking schedulers. The
 /// cooperative scheduler runs in the kernel while the preemptive scheduler will run in
 /// userspace once implemented.
@@ -74,8 +83,11 @@ pub fn init() {
             "big endien"
         }
     );
+    interrupts::init_ic();
     let mut executor = Executor::new();
     executor.spawn(AsyncTask::new(acpi::init()));
+    executor.spawn(AsyncTask::new(apic_timer::init()));
+    executor.spawn(AsyncTask::new(ipi::init()));
     executor.spawn(AsyncTask::new(pci::init()));
     executor.spawn(AsyncTask::new(rtc::init()));
     executor.run();
@@ -0,0 +1,369 @@
+// SPDX-License-Identifier: MPL-2.0
+use core::fmt;
+use core::ops::Range;
+use x86_64::{structures::paging::PhysFrame, PhysAddr};
+
+/// The size of the smallest block (order 0) the buddy allocator manages.
+pub(crate) const FRAME_SIZE: u64 = 4096;
+/// The largest order the buddy allocator manages. An order-18 block is 1 GiB in size.
+pub(crate) const MAX_ORDER: usize = 18;
+/// Sentinel used to terminate free lists.
+const NIL: u64 = u64::MAX;
+/// Marker in the order map for frames that are not the head of a free block.
+const NOT_FREE: u8 = u8::MAX;
+
+/// Intrusive free-list node stored in the first frame of every free block.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct FreeBlock {
+    next: u64,
+    prev: u64,
+}
+
+/// A binary buddy allocator over physical frames.
+///
+/// Free blocks are kept on one doubly-linked list per order. The list nodes live inside the free
+/// blocks themselves and are reached through the higher-half physical memory mapping, so the
+/// allocator needs no heap. A byte-per-frame order map records which frames head a free block
+/// (and of what order), which is what lets a freed block find and merge with its buddy in
+/// O(`MAX_ORDER`) time.
+pub(crate) struct BuddyAllocator {
+    /// Offset of the higher-half physical memory mapping.
+    offset: u64,
+    /// Lowest physical address covered by the order map.
+    base: u64,
+    /// Physical address one past the highest frame covered by the order map.
+    limit: u64,
+    /// The frames holding the order map itself, followed by the metadata carved out for the caller.
+    map: Range<u64>,
+    /// The part of `map` carved out for the caller's per-frame metadata.
+    metadata: Range<u64>,
+    /// For every frame in `base..limit`, the order of the free block it heads, or `NOT_FREE`.
+    orders: &'static mut [u8],
+    /// Heads of the free lists, indexed by order.
+    heads: [u64; MAX_ORDER + 1],
+    /// Number of free frames.
+    free: usize,
+    /// Number of frames handed to the allocator at initialization.
+    total: usize,
+}
+
+impl fmt::Debug for BuddyAllocator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BuddyAllocator")
+            .field("base", &self.base)
+            .field("limit", &self.limit)
+            .field("free", &self.free)
+            .field("total", &self.total)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Returns the size in bytes of a block of the given order.
+#[inline]
+pub(crate) const fn order_size(order: usize) -> u64 {
+    FRAME_SIZE << order
+}
+
+/// Returns the smallest order whose block size is at least `size` bytes.
+#[inline]
+pub(crate) fn order_for_size(size: u64) -> usize {
+    let frames = (size + FRAME_SIZE - 1) / FRAME_SIZE;
+    frames.max(1).next_power_of_two().trailing_zeros() as usize
+}
+
+impl BuddyAllocator {
+    /// Creates a buddy allocator over the given free physical ranges. The order map also covers the `later` ranges,
+    /// so that they can be handed to the allocator with [`BuddyAllocator::add_region`] once they are free.
+    ///
+    /// The order map is carved out of the first range large enough to hold it, along with `metadata_size` bytes per
+    /// frame covered for the caller's own use (see [`BuddyAllocator::metadata`]); `reserved` ranges are never handed
+    /// out.
+    ///
+    /// # Safety
+    ///
+    /// `offset` must be the offset of a mapping covering all of physical memory, and the ranges in `usable` must be
+    /// free RAM that nothing else references.
+    #[cold]
+    pub(crate) unsafe fn new(
+        offset: u64,
+        usable: impl Iterator<Item = Range<u64>> + Clone,
+        later: impl Iterator<Item = Range<u64>> + Clone,
+        reserved: &[Range<u64>],
+        metadata_size: u64,
+    ) -> Option<Self> {
+        let covered = usable.clone().chain(later);
+        let base = covered.clone().map(|r| r.start).min()? & !(FRAME_SIZE - 1);
+        let limit = align_up(covered.map(|r| r.end).max()?);
+        let frames = ((limit - base) / FRAME_SIZE) as usize;
+        let map_size = align_up(frames as u64);
+        let carved = map_size + align_up(frames as u64 * metadata_size);
+        let map_start = usable
+            .clone()
+            .map(|r| align_up(r.start)..(r.end & !(FRAME_SIZE - 1)))
+            .filter(|r| r.start < r.end && !reserved.iter().any(|res| overlaps(r, res)))
+            .find(|r| r.end - r.start >= carved)?
+            .start;
+        let orders =
+            unsafe { core::slice::from_raw_parts_mut((offset + map_start) as *mut u8, frames) };
+        orders.fill(NOT_FREE);
+        let mut allocator = BuddyAllocator {
+            offset,
+            base,
+            limit,
+            map: map_start..map_start + carved,
+            metadata: map_start + map_size..map_start + carved,
+            orders,
+            heads: [NIL; MAX_ORDER + 1],
+            free: 0,
+            total: 0,
+        };
+        for region in usable {
+            unsafe {
+                let _ = allocator.add_region(region, reserved);
+            }
+        }
+        Some(allocator)
+    }
+
+    /// Adds the free frames in `region` to the allocator, skipping `reserved` ranges, the order map and anything the
+    /// order map does not cover. Returns the number of frames added.
+    ///
+    /// # Safety
+    ///
+    /// The frames must be free RAM that nothing references, and must not already belong to the allocator.
+    pub(crate) unsafe fn add_region(
+        &mut self,
+        region: Range<u64>,
+        reserved: &[Range<u64>],
+    ) -> usize {
+        let mut start = align_up(region.start.max(self.base));
+        let end = region.end.min(self.limit) & !(FRAME_SIZE - 1);
+        let map = self.map.clone();
+        let added = self.total;
+        while start < end {
+            // Skip over any reserved range the cursor falls into
+            if let Some(res) = reserved
+                .iter()
+                .chain(core::iter::once(&map))
+                .find(|res| res.contains(&start))
+            {
+                start = align_up(res.end);
+                continue;
+            }
+            // Stop at the next reserved range so it is never added
+            let stop = reserved
+                .iter()
+                .chain(core::iter::once(&map))
+                .map(|res| res.start & !(FRAME_SIZE - 1))
+                .filter(|&s| s > start && s < end)
+                .min()
+                .unwrap_or(end);
+            let order = (0..=MAX_ORDER)
+                .rev()
+                .find(|&o| start % order_size(o) == 0 && start + order_size(o) <= stop)
+                .unwrap_or(0);
+            // Merge with any free buddies, as if the block had been allocated and freed
+            let frame = PhysFrame::containing_address(PhysAddr::new(start));
+            unsafe {
+                self.free(frame, order);
+            }
+            self.total += 1 << order;
+            start += order_size(order);
+        }
+        self.total - added
+    }
+
+    /// Allocates a naturally aligned block of `1 << order` contiguous frames.
+    pub(crate) fn allocate(&mut self, order: usize) -> Option<PhysFrame> {
+        if order > MAX_ORDER {
+            return None;
+        }
+        let found = (order..=MAX_ORDER).find(|&o| self.heads[o] != NIL)?;
+        let addr = self.heads[found];
+        unsafe {
+            self.remove(addr, found);
+        }
+        // Split the block, returning each upper half to the free lists
+        for o in (order..found).rev() {
+            unsafe {
+                self.push(addr + order_size(o), o);
+            }
+        }
+        PhysFrame::from_start_address(PhysAddr::new(addr)).ok()
+    }
+
+    /// Allocates a naturally aligned block of `1 << order` contiguous frames that ends at or below `limit`.
+    #[inline]
+    pub(crate) fn allocate_below(&mut self, order: usize, limit: u64) -> Option<PhysFrame> {
+        self.allocate_in(order, 0..limit)
+    }
+
+    /// Allocates a naturally aligned block of `1 << order` contiguous frames that lies entirely within `range`.
+    pub(crate) fn allocate_in(&mut self, order: usize, range: Range<u64>) -> Option<PhysFrame> {
+        if order > MAX_ORDER {
+            return None;
+        }
+        let size = order_size(order);
+        // Any free block with a suitably aligned piece inside the range will do; the piece is carved out of it
+        let (found, addr, target) = (order..=MAX_ORDER).find_map(|o| {
+            let mut addr = self.heads[o];
+            while addr != NIL {
+                let target = align_up_to(addr.max(range.start), size);
+                if target + size <= (addr + order_size(o)).min(range.end) {
+                    return Some((o, addr, target));
+                }
+                addr = unsafe { self.node(addr).read().next };
+            }
+            None
+        })?;
+        unsafe {
+            self.remove(addr, found);
+        }
+        // Split the block down to the piece, returning the halves that do not contain it
+        let mut block = addr;
+        for o in (order..found).rev() {
+            let half = order_size(o);
+            let other = if target >= block + half {
+                let lower = block;
+                block += half;
+                lower
+            } else {
+                block + half
+            };
+            unsafe {
+                self.push(other, o);
+            }
+        }
+        PhysFrame::from_start_address(PhysAddr::new(target)).ok()
+    }
+
+    /// Returns a block of `1 << order` frames starting at `frame` to the allocator, merging it with its buddies.
+    ///
+    /// # Safety
+    ///
+    /// The block must have been returned by [`BuddyAllocator::allocate`] with the same order (or be a sub-block of
+    /// such an allocation) and must no longer be in use.
+    pub(crate) unsafe fn free(&mut self, frame: PhysFrame, order: usize) {
+        let mut addr = frame.start_address().as_u64();
+        let mut order = order.min(MAX_ORDER);
+        if addr < self.base || addr + order_size(order) > self.limit {
+            return;
+        }
+        while order < MAX_ORDER {
+            let buddy = addr ^ order_size(order);
+            if buddy < self.base
+                || buddy + order_size(order) > self.limit
+                || self.orders[self.index(buddy)] != order as u8
+            {
+                break;
+            }
+            unsafe {
+                self.remove(buddy, order);
+            }
+            addr = addr.min(buddy);
+            order += 1;
+        }
+        unsafe {
+            self.push(addr, order);
+        }
+    }
+
+    /// Returns whether the frame at `addr` is currently free.
+    pub(crate) fn is_free(&self, addr: u64) -> bool {
+        (0..=MAX_ORDER).any(|o| {
+            let head = addr & !(order_size(o) - 1);
+            head >= self.base
+                && head < self.limit
+                && self.orders[self.index(head)] == o as u8
+                && addr < head + order_size(o)
+        })
+    }
+
+    /// Returns the number of free frames.
+    #[inline]
+    pub(crate) fn free_frames(&self) -> usize {
+        self.free
+    }
+
+    /// Returns the physical range the order map covers.
+    #[inline]
+    pub(crate) fn span(&self) -> Range<u64> {
+        self.base..self.limit
+    }
+
+    /// Returns the physical range carved out for the caller's per-frame metadata when the allocator was created.
+    #[inline]
+    pub(crate) fn metadata(&self) -> Range<u64> {
+        self.metadata.clone()
+    }
+
+    /// Returns the number of frames the allocator manages.
+    #[inline]
+    pub(crate) fn total_frames(&self) -> usize {
+        self.total
+    }
+
+    #[inline]
+    fn index(&self, addr: u64) -> usize {
+        ((addr - self.base) / FRAME_SIZE) as usize
+    }
+
+    #[inline]
+    fn node(&self, addr: u64) -> *mut FreeBlock {
+        (self.offset + addr) as *mut FreeBlock
+    }
+
+    /// Pushes a free block onto the list for `order`.
+    unsafe fn push(&mut self, addr: u64, order: usize) {
+        let head = self.heads[order];
+        unsafe {
+            self.node(addr).write(FreeBlock {
+                next: head,
+                prev: NIL,
+            });
+            if head != NIL {
+                (*self.node(head)).prev = addr;
+            }
+        }
+        self.heads[order] = addr;
+        let idx = self.index(addr);
+        self.orders[idx] = order as u8;
+        self.free += 1 << order;
+    }
+
+    /// Unlinks a free block from the list for `order`.
+    unsafe fn remove(&mut self, addr: u64, order: usize) {
+        let FreeBlock { next, prev } = unsafe { self.node(addr).read() };
+        if prev == NIL {
+            self.heads[order] = next;
+        } else {
+            unsafe {
+                (*self.node(prev)).next = next;
+            }
+        }
+        if next != NIL {
+            unsafe {
+                (*self.node(next)).prev = prev;
+            }
+        }
+        let idx = self.index(addr);
+        self.orders[idx] = NOT_FREE;
+        self.free -= 1 << order;
+    }
+}
+
+#[inline]
+const fn align_up(addr: u64) -> u64 {
+    align_up_to(addr, FRAME_SIZE)
+}
+
+#[inline]
+const fn align_up_to(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
+
+#[inline]
+fn overlaps(a: &Range<u64>, b: &Range<u64>) -> bool {
+    a.start < b.end && b.start < a.end
+}
@@ -0,0 +1,270 @@
+// SPDX-License-Identifier: MPL-2.0
+use super::{
+    allocate_frames_below, allocate_frames_below_on,
+    buddy::{order_for_size, order_size},
+    frame::{self, FrameFlags},
+    free_frames,
+    numa::NodeId,
+    phys_to_virt, MAPPER,
+};
+use alloc::vec::Vec;
+use core::slice;
+use core::sync::atomic::{fence, Ordering};
+use log::*;
+use x86_64::{
+    structures::paging::{mapper::Translate, PageSize, PhysFrame, Size4KiB},
+    VirtAddr,
+};
+
+/// The direction data moves in during a streaming DMA transfer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DmaDirection {
+    /// The device reads the buffer.
+    ToDevice,
+    /// The device writes the buffer.
+    FromDevice,
+    /// The device both reads and writes the buffer.
+    Bidirectional,
+}
+
+/// A physically contiguous piece of a DMA mapping, as seen by the device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct DmaSegment {
+    /// The bus address of the segment.
+    pub addr: u64,
+    /// The length of the segment in bytes.
+    pub len: u64,
+}
+
+/// Returns the first physical address a device with the given address width cannot reach.
+#[inline]
+fn limit_for(address_bits: u8) -> u64 {
+    if address_bits >= 64 {
+        u64::MAX
+    } else {
+        1 << address_bits
+    }
+}
+
+/// A coherent DMA buffer: physically contiguous memory with a known bus address.
+///
+/// x86 keeps DMA coherent with the CPU caches, so the buffer is accessed through the ordinary cached higher-half
+/// mapping and needs no explicit synchronization. The buffer is zeroed when allocated and returned to the frame
+/// allocator when dropped.
+#[derive(Debug)]
+pub struct DmaBuffer {
+    frame: PhysFrame,
+    order: usize,
+    size: usize,
+}
+
+impl DmaBuffer {
+    /// Allocates a zeroed buffer of `size` bytes aligned to `align` (at least a page) that a device with an
+    /// `address_bits`-wide DMA address space can reach. Returns `None` if no such memory is free.
+    pub fn new(size: usize, align: usize, address_bits: u8) -> Option<DmaBuffer> {
+        DmaBuffer::allocate(size, align, address_bits, None)
+    }
+
+    /// Like [`DmaBuffer::new`], but places the buffer on NUMA node `node` (normally the device's) if it can.
+    pub fn new_on(size: usize, align: usize, address_bits: u8, node: NodeId) -> Option<DmaBuffer> {
+        DmaBuffer::allocate(size, align, address_bits, Some(node))
+    }
+
+    fn allocate(
+        size: usize,
+        align: usize,
+        address_bits: u8,
+        node: Option<NodeId>,
+    ) -> Option<DmaBuffer> {
+        if size == 0 || !align.is_power_of_two() {
+            return None;
+        }
+        let order = order_for_size(size as u64).max(order_for_size(align as u64));
+        let limit = limit_for(address_bits);
+        let frame = match node {
+            Some(node) => allocate_frames_below_on(order, limit, node),
+            None => allocate_frames_below(order, limit),
+        }?;
+        frame::mark(
+            frame.start_address().as_u64(),
+            order_size(order),
+            FrameFlags::DMA | FrameFlags::PINNED,
+        );
+        let buffer = DmaBuffer { frame, order, size };
+        unsafe {
+            buffer.as_mut_ptr().write_bytes(0, size);
+        }
+        Some(buffer)
+    }
+
+    /// Returns the address the device uses to reach the buffer. Without an IOMMU this is its physical address.
+    #[inline]
+    pub fn bus_addr(&self) -> u64 {
+        self.frame.start_address().as_u64()
+    }
+
+    /// Returns the size of the buffer in bytes.
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns a pointer to the start of the buffer.
+    #[inline]
+    pub fn as_ptr(&self) -> *const u8 {
+        phys_to_virt(self.frame.start_address()).as_ptr()
+    }
+
+    /// Returns a mutable pointer to the start of the buffer.
+    #[inline]
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        phys_to_virt(self.frame.start_address()).as_mut_ptr()
+    }
+
+    /// Returns the buffer as a byte slice.
+    #[inline]
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.as_ptr(), self.size) }
+    }
+
+    /// Returns the buffer as a mutable byte slice.
+    #[inline]
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.as_mut_ptr(), self.size) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            free_frames(self.frame, self.order);
+        }
+    }
+}
+
+/// A streaming DMA mapping of an existing buffer.
+///
+/// The buffer is borrowed for as long as the mapping exists, so the CPU cannot touch it while the device owns it.
+/// If any part of the buffer is out of the device's reach, the whole transfer goes through a bounce buffer below the
+/// device's address limit instead, and data is copied in and out of it as the direction requires. Dropping the
+/// mapping unmaps it.
+#[derive(Debug)]
+pub struct StreamingMapping<'a> {
+    buffer: &'a mut [u8],
+    direction: DmaDirection,
+    segments: Vec<DmaSegment>,
+    bounce: Option<DmaBuffer>,
+}
+
+/// Maps `buffer` for a streaming DMA transfer in the given direction by a device with an `address_bits`-wide DMA
+/// address space. Returns `None` if the buffer is not mapped or no bounce buffer could be allocated.
+pub fn map(
+    buffer: &mut [u8],
+    direction: DmaDirection,
+    address_bits: u8,
+) -> Option<StreamingMapping<'_>> {
+    if buffer.is_empty() {
+        return None;
+    }
+    let limit = limit_for(address_bits);
+    let start = buffer.as_ptr() as u64;
+    let end = start + buffer.len() as u64;
+    let pages = ((end - 1) / Size4KiB::SIZE - start / Size4KiB::SIZE + 1) as usize;
+    // Reserve up front: the heap may need the mapper to grow, and the mapper is locked below
+    let mut segments: Vec<DmaSegment> = Vec::with_capacity(pages);
+    {
+        let mapper = MAPPER.lock();
+        let mapper = mapper.as_ref()?;
+        let mut addr = start;
+        while addr < end {
+            let len = ((addr & !(Size4KiB::SIZE - 1)) + Size4KiB::SIZE).min(end) - addr;
+            let phys = mapper
+                .translate_addr(VirtAddr::new_truncate(addr))?
+                .as_u64();
+            match segments.last_mut() {
+                Some(last) if last.addr + last.len == phys => last.len += len,
+                _ => segments.push(DmaSegment { addr: phys, len }),
+            }
+            addr += len;
+        }
+    }
+    let bounce = if segments.iter().any(|s| s.addr + s.len > limit) {
+        let bounce = DmaBuffer::new(buffer.len(), Size4KiB::SIZE as usize, address_bits);
+        if bounce.is_none() {
+            warn!(
+                "Cannot allocate a {:X}-byte bounce buffer below {:X}h",
+                buffer.len(),
+                limit
+            );
+        }
+        let bounce = bounce?;
+        segments.clear();
+        segments.push(DmaSegment {
+            addr: bounce.bus_addr(),
+            len: buffer.len() as u64,
+        });
+        Some(bounce)
+    } else {
+        None
+    };
+    let mut mapping = StreamingMapping {
+        buffer,
+        direction,
+        segments,
+        bounce,
+    };
+    mapping.sync_for_device();
+    Some(mapping)
+}
+
+impl StreamingMapping<'_> {
+    /// Returns the segments to program into the device, in buffer order.
+    #[inline]
+    pub fn segments(&self) -> &[DmaSegment] {
+        &self.segments
+    }
+
+    /// Returns whether the transfer goes through a bounce buffer.
+    #[inline]
+    pub fn is_bounced(&self) -> bool {
+        self.bounce.is_some()
+    }
+
+    /// Returns the mapped buffer. Only touch it between [`StreamingMapping::sync_for_cpu`] and
+    /// [`StreamingMapping::sync_for_device`].
+    #[inline]
+    pub fn buffer_mut(&mut self) -> &mut [u8] {
+        self.buffer
+    }
+
+    /// Makes the CPU's writes to the buffer visible to the device. Call before starting each transfer after the
+    /// first.
+    pub fn sync_for_device(&mut self) {
+        if let Some(bounce) = self.bounce.as_mut() {
+            if self.direction != DmaDirection::FromDevice {
+                bounce.as_mut_slice().copy_from_slice(self.buffer);
+            }
+        }
+        fence(Ordering::SeqCst);
+    }
+
+    /// Makes the device's writes to the buffer visible to the CPU. Call after each transfer completes.
+    pub fn sync_for_cpu(&mut self) {
+        fence(Ordering::SeqCst);
+        if let Some(bounce) = self.bounce.as_ref() {
+            if self.direction != DmaDirection::ToDevice {
+                self.buffer.copy_from_slice(bounce.as_slice());
+            }
+        }
+    }
+
+    /// Unmaps the buffer, copying back anything the device wrote to a bounce buffer.
+    #[inline]
+    pub fn unmap(self) {}
+}
+
+impl Drop for StreamingMapping<'_> {
+    fn drop(&mut self) {
+        self.sync_for_cpu();
+    }
+}
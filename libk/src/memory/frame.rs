@@ -0,0 +1,212 @@
+// SPDX-License-Identifier: MPL-2.0
+use super::buddy::{BuddyAllocator, FRAME_SIZE};
+use core::mem::size_of;
+use core::ops::BitOr;
+use core::slice;
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering};
+use log::*;
+use spin::Once;
+use x86_64::PhysAddr;
+
+/// The owner recorded for frames nobody has claimed.
+pub const NO_OWNER: u64 = 0;
+
+/// The metadata table, and the physical address of the frame its first entry describes.
+static TABLE: Once<(u64, &'static [FrameInfo])> = Once::new();
+
+/// What a frame is used for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct FrameFlags(u8);
+
+impl FrameFlags {
+    /// The frame must stay where it is, because something other than the page tables holds its address.
+    pub const PINNED: FrameFlags = FrameFlags(1 << 0);
+    /// The frame is the target of device DMA.
+    pub const DMA: FrameFlags = FrameFlags(1 << 1);
+    /// The frame holds a page table.
+    pub const PAGE_TABLE: FrameFlags = FrameFlags(1 << 2);
+    /// The frame belongs to a slab of small kernel objects.
+    pub const SLAB: FrameFlags = FrameFlags(1 << 3);
+
+    /// Returns a set with no flags.
+    #[inline]
+    pub const fn empty() -> FrameFlags {
+        FrameFlags(0)
+    }
+
+    /// Returns the raw bits of the set.
+    #[inline]
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// Returns whether every flag in `other` is set.
+    #[inline]
+    pub const fn contains(self, other: FrameFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for FrameFlags {
+    type Output = FrameFlags;
+
+    #[inline]
+    fn bitor(self, rhs: FrameFlags) -> FrameFlags {
+        FrameFlags(self.0 | rhs.0)
+    }
+}
+
+/// The metadata kept for every frame of RAM, much like Linux's `struct page`.
+///
+/// A frame the frame allocator hands out starts with one reference, which belongs to whoever allocated it. Every
+/// further mapping of the frame takes another, and the frame only goes back to the allocator once the last one is
+/// dropped. Frames the allocator never handed out (firmware tables, the kernel image, free memory) have no
+/// references, and mapping or unmapping them leaves them alone.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct FrameInfo {
+    refcount: AtomicU32,
+    flags: AtomicU8,
+    owner: AtomicU64,
+}
+
+impl FrameInfo {
+    /// Returns the number of references to the frame. Zero means the frame is not allocated.
+    #[inline]
+    pub fn refcount(&self) -> u32 {
+        self.refcount.load(Ordering::Acquire)
+    }
+
+    /// Returns what the frame is used for.
+    #[inline]
+    pub fn flags(&self) -> FrameFlags {
+        FrameFlags(self.flags.load(Ordering::Relaxed))
+    }
+
+    /// Sets the given flags.
+    #[inline]
+    pub fn insert(&self, flags: FrameFlags) {
+        self.flags.fetch_or(flags.bits(), Ordering::Relaxed);
+    }
+
+    /// Clears the given flags.
+    #[inline]
+    pub fn remove(&self, flags: FrameFlags) {
+        self.flags.fetch_and(!flags.bits(), Ordering::Relaxed);
+    }
+
+    /// Returns the owner of the frame: an identifier chosen by whoever allocated it, or [`NO_OWNER`].
+    #[inline]
+    pub fn owner(&self) -> u64 {
+        self.owner.load(Ordering::Relaxed)
+    }
+
+    /// Records the owner of the frame.
+    #[inline]
+    pub fn set_owner(&self, owner: u64) {
+        self.owner.store(owner, Ordering::Relaxed);
+    }
+}
+
+/// Sets up the metadata table for every frame `buddy` covers, in the memory carved out for it when `buddy` was
+/// created. Must run before the allocator hands out any frame.
+#[cold]
+pub(crate) fn init(buddy: &BuddyAllocator, offset: u64) {
+    let span = buddy.span();
+    let frames = ((span.end - span.start) / FRAME_SIZE) as usize;
+    let table = buddy.metadata();
+    assert!(
+        table.end - table.start >= (frames * size_of::<FrameInfo>()) as u64,
+        "Frame metadata table does not fit the memory carved out for it"
+    );
+    let entries =
+        unsafe { slice::from_raw_parts_mut((offset + table.start) as *mut FrameInfo, frames) };
+    entries.iter_mut().for_each(|e| *e = FrameInfo::default());
+    let entries = &*entries;
+    TABLE.call_once(|| (span.start, entries));
+    mark(table.start, table.end - table.start, FrameFlags::PINNED);
+    info!(
+        "Frame metadata table at {:X}h, {:X} bytes",
+        table.start,
+        table.end - table.start
+    );
+}
+
+/// Returns the metadata of the frame containing `addr`, or `None` if it lies outside the RAM the frame allocator
+/// manages.
+pub fn info(addr: PhysAddr) -> Option<&'static FrameInfo> {
+    let (base, table) = TABLE.get()?;
+    let index = addr.as_u64().checked_sub(*base)? / FRAME_SIZE;
+    table.get(index as usize)
+}
+
+/// Takes another reference to the allocated frame containing `addr`, keeping it from being freed until the reference
+/// is dropped again with [`free_frames`](super::free_frames). Returns false, taking nothing, if the frame is not
+/// allocated.
+pub fn get(addr: PhysAddr) -> bool {
+    info(addr).map_or(false, |info| {
+        update(&info.refcount, |c| if c == 0 { None } else { Some(c + 1) }).is_some()
+    })
+}
+
+/// Sets `flags` on every frame in `[phys, phys + len)`.
+pub fn mark(phys: u64, len: u64, flags: FrameFlags) {
+    (phys..phys + len)
+        .step_by(FRAME_SIZE as usize)
+        .filter_map(|addr| info(PhysAddr::new(addr)))
+        .for_each(|info| info.insert(flags));
+}
+
+/// Takes another reference to every allocated frame in `[phys, phys + len)`, for a new mapping of them.
+pub(crate) fn get_range(phys: u64, len: u64) {
+    (phys..phys + len)
+        .step_by(FRAME_SIZE as usize)
+        .for_each(|addr| {
+            let _ = get(PhysAddr::new(addr));
+        });
+}
+
+/// Drops a reference to the frame at `addr`. Returns true if it was the last one, in which case the caller must
+/// return the frame to the frame allocator.
+pub(crate) fn put(addr: u64) -> bool {
+    info(PhysAddr::new(addr)).map_or(false, |info| {
+        update(&info.refcount, |c| c.checked_sub(1)) == Some(1)
+    })
+}
+
+/// Resets the metadata of `count` frames starting at `phys` as they are handed out by the frame allocator, giving each
+/// the allocation's reference.
+pub(crate) fn claim(phys: u64, count: u64) {
+    each(phys, count, |info| {
+        info.flags.store(0, Ordering::Relaxed);
+        info.owner.store(NO_OWNER, Ordering::Relaxed);
+        info.refcount.store(1, Ordering::Release);
+    });
+}
+
+/// Clears the metadata of `count` frames starting at `phys` as they go back to the frame allocator.
+pub(crate) fn release(phys: u64, count: u64) {
+    each(phys, count, |info| {
+        info.refcount.store(0, Ordering::Release);
+        info.flags.store(0, Ordering::Relaxed);
+    });
+}
+
+/// Replaces the value of `counter` with `f` of it until that sticks, or until `f` returns `None`. Returns the value
+/// that was replaced.
+fn update(counter: &AtomicU32, f: impl Fn(u32) -> Option<u32>) -> Option<u32> {
+    let mut current = counter.load(Ordering::Acquire);
+    loop {
+        let new = f(current)?;
+        match counter.compare_exchange_weak(current, new, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(old) => return Some(old),
+            Err(actual) => current = actual,
+        }
+    }
+}
+
+fn each(phys: u64, count: u64, f: impl Fn(&FrameInfo)) {
+    (0..count)
+        .filter_map(|i| info(PhysAddr::new(phys + i * FRAME_SIZE)))
+        .for_each(f);
+}
@@ -0,0 +1,366 @@
+// SPDX-License-Identifier: MPL-2.0
+#[cfg(feature = "kasan")]
+use super::kasan;
+use super::{
+    image::no_execute,
+    lock::{OwnedMutex, OwnedMutexGuard},
+    map_range, release_frames, unaccount, unmap_range, FRAME_ALLOCATOR, MAPPER, SMUSE,
+};
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem::size_of;
+use core::ptr::null_mut;
+use core::sync::atomic::Ordering;
+use x86_64::{
+    addr::align_up,
+    structures::paging::{PageSize, PageTableFlags, Size2MiB, Size4KiB},
+};
+
+/// Start of the virtual window the kernel heap grows into.
+pub const HEAP_START: u64 = 0xFFFF_E000_0000_0000;
+/// End (exclusive) of the virtual window the kernel heap grows into.
+pub const HEAP_END: u64 = HEAP_START + 0x10_0000_0000;
+/// The heap grows in multiples of this, so that growth can use 2 MiB pages.
+const GROW_STEP: u64 = Size2MiB::SIZE;
+/// Free bytes kept mapped at the top of the heap when it shrinks on its own.
+const HEAP_SLACK: u64 = 2 * Size2MiB::SIZE;
+/// The heap shrinks on its own once this many bytes at its top are free.
+const TRIM_THRESHOLD: u64 = 4 * Size2MiB::SIZE;
+/// The smallest block the heap hands out; also the granularity of every block.
+const MIN_BLOCK: usize = size_of::<FreeBlock>();
+
+/// The heap's state. Locks are taken in the order HEAP, MAPPER, FRAME_ALLOCATOR: growing and trimming the heap take the
+/// other two with this one held, so nothing may allocate or free heap memory while holding MAPPER or FRAME_ALLOCATOR.
+static HEAP: OwnedMutex<Heap> = OwnedMutex::new(Heap {
+    head: null_mut(),
+    top: HEAP_START,
+    used: 0,
+    #[cfg(feature = "kasan")]
+    quarantine: kasan::Quarantine::new(),
+});
+
+/// A free block. Lives in the first bytes of the free memory it describes.
+#[repr(C)]
+#[derive(Debug)]
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+impl FreeBlock {
+    #[inline]
+    #[cfg_attr(feature = "kasan", no_sanitize(address))]
+    fn end(&self) -> usize {
+        let this: *const FreeBlock = self;
+        this as usize + self.size
+    }
+}
+
+/// The heap's state: a free list sorted by address and the top of the mapped part of the window.
+#[derive(Debug)]
+struct Heap {
+    head: *mut FreeBlock,
+    top: u64,
+    used: usize,
+    #[cfg(feature = "kasan")]
+    quarantine: kasan::Quarantine,
+}
+
+// The free list is only ever touched with the heap lock held
+unsafe impl Send for Heap {}
+
+impl Heap {
+    /// Takes a block of `size` bytes aligned to `align` from the free list, first fit.
+    #[cfg_attr(feature = "kasan", no_sanitize(address))]
+    unsafe fn take(&mut self, size: usize, align: usize) -> Option<*mut u8> {
+        let mut prev: *mut *mut FreeBlock = &mut self.head;
+        unsafe {
+            while !(*prev).is_null() {
+                let block = *prev;
+                let start = block as usize;
+                let end = (*block).end();
+                let mut addr = align_up(start as u64, align as u64) as usize;
+                // Leave room for a free block in front of the allocation if it does not start the block
+                if addr != start && addr - start < MIN_BLOCK {
+                    addr = align_up((start + MIN_BLOCK) as u64, align as u64) as usize;
+                }
+                if addr + size <= end {
+                    let mut link = (*block).next;
+                    // Every address and size is a multiple of MIN_BLOCK, so what is left on either side is either
+                    // nothing or large enough to hold a free block
+                    if addr + size < end {
+                        let back = (addr + size) as *mut FreeBlock;
+                        back.write(FreeBlock {
+                            size: end - (addr + size),
+                            next: link,
+                        });
+                        link = back;
+                    }
+                    if addr > start {
+                        (*block).size = addr - start;
+                        (*block).next = link;
+                    } else {
+                        *prev = link;
+                    }
+                    self.used += size;
+                    return Some(addr as *mut u8);
+                }
+                prev = &mut (*block).next;
+            }
+        }
+        None
+    }
+
+    /// Returns `[addr, addr + size)` to the free list, merging it with its neighbours. Returns the block it ended up
+    /// in.
+    #[cfg_attr(feature = "kasan", no_sanitize(address))]
+    unsafe fn give(&mut self, addr: usize, size: usize) -> *mut FreeBlock {
+        let mut prev: *mut FreeBlock = null_mut();
+        let mut cur = self.head;
+        unsafe {
+            while !cur.is_null() && (cur as usize) < addr {
+                prev = cur;
+                cur = (*cur).next;
+            }
+            let node = addr as *mut FreeBlock;
+            node.write(FreeBlock { size, next: cur });
+            if !cur.is_null() && addr + size == cur as usize {
+                (*node).size += (*cur).size;
+                (*node).next = (*cur).next;
+            }
+            if prev.is_null() {
+                self.head = node;
+                node
+            } else if (*prev).end() == addr {
+                (*prev).size += (*node).size;
+                (*prev).next = (*node).next;
+                prev
+            } else {
+                (*prev).next = node;
+                node
+            }
+        }
+    }
+
+    /// Maps enough new pages at the top of the heap to satisfy an allocation of `size` bytes aligned to `align`.
+    fn grow(&mut self, size: usize, align: usize) -> bool {
+        let len = align_up((size + align) as u64, GROW_STEP);
+        if self.top + len > HEAP_END {
+            return false;
+        }
+        // HEAP, then MAPPER, then FRAME_ALLOCATOR
+        debug_assert!(HEAP.held_here(), "Heap grown without the heap lock");
+        match (MAPPER.lock().as_mut(), FRAME_ALLOCATOR.lock().as_mut()) {
+            (Some(m), Some(a)) => {
+                // Leave a little room for the page tables the mapping needs
+                if (a.0.free_frames() as u64) < len / Size4KiB::SIZE + 8 {
+                    return false;
+                }
+                #[cfg(feature = "kasan")]
+                if unsafe { kasan::grow(m, a, self.top, self.top + len) }.is_err() {
+                    return false;
+                }
+                let mapped = unsafe {
+                    map_range(
+                        m,
+                        a,
+                        self.top,
+                        None,
+                        len,
+                        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | no_execute(),
+                    )
+                };
+                if mapped.is_err() {
+                    return false;
+                }
+            }
+            _ => return false,
+        }
+        unsafe {
+            let _ = self.give(self.top as usize, len as usize);
+        }
+        self.top += len;
+        SMUSE.fetch_add(len, Ordering::Relaxed);
+        true
+    }
+
+    /// Takes a block of `size` bytes aligned to `align`, growing the heap if no free block is large enough.
+    unsafe fn take_or_grow(&mut self, size: usize, align: usize) -> *mut u8 {
+        unsafe {
+            self.take(size, align)
+                .or_else(|| {
+                    if self.grow(size, align) {
+                        self.take(size, align)
+                    } else {
+                        None
+                    }
+                })
+                .unwrap_or(null_mut())
+        }
+    }
+
+    /// Returns the block `[addr, addr + size)` to the free list, unmapping the top of the heap if enough of it is
+    /// free.
+    unsafe fn release(&mut self, addr: usize, size: usize) {
+        self.used -= size;
+        #[cfg(feature = "kasan")]
+        kasan::on_release(addr, size);
+        let block = unsafe { self.give(addr, size) };
+        let (start, end) = unsafe { (block as u64, (*block).end() as u64) };
+        if end == self.top && end - start >= TRIM_THRESHOLD {
+            let _ = self.trim(HEAP_SLACK);
+        }
+    }
+
+    #[cfg(not(feature = "kasan"))]
+    unsafe fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = block_layout(layout);
+        unsafe { self.take_or_grow(size, align) }
+    }
+
+    #[cfg(not(feature = "kasan"))]
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = block_layout(layout);
+        unsafe { self.release(ptr as usize, size) }
+    }
+
+    /// Allocates with redzones on either side of the allocation.
+    #[cfg(feature = "kasan")]
+    unsafe fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        match kasan::pad(layout) {
+            Some((padded, left)) => {
+                let (size, align) = block_layout(padded);
+                unsafe {
+                    kasan::on_alloc(self.take_or_grow(size, align), size, left, layout.size())
+                }
+            }
+            None => null_mut(),
+        }
+    }
+
+    /// Puts the allocation in quarantine, and returns whatever has been there longest to the free list.
+    #[cfg(feature = "kasan")]
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        if !unsafe { kasan::on_free(ptr as usize, layout.size()) } {
+            return;
+        }
+        // The layout was padded when the allocation was made, so it pads now
+        if let Some((padded, left)) = kasan::pad(layout) {
+            self.quarantine
+                .push(ptr as usize - left, block_layout(padded).0);
+        }
+        while let Some((addr, size)) = self.quarantine.evict() {
+            unsafe { self.release(addr, size) }
+        }
+    }
+
+    /// Unmaps the free pages at the top of the heap, keeping `keep` free bytes mapped. Returns the number of bytes
+    /// returned to the frame allocator.
+    #[cfg_attr(feature = "kasan", no_sanitize(address))]
+    fn trim(&mut self, keep: u64) -> u64 {
+        let mut prev: *mut FreeBlock = null_mut();
+        let mut last = self.head;
+        unsafe {
+            if last.is_null() {
+                return 0;
+            }
+            while !(*last).next.is_null() {
+                prev = last;
+                last = (*last).next;
+            }
+            if (*last).end() as u64 != self.top {
+                return 0;
+            }
+        }
+        let start = last as u64;
+        let new_top = align_up((start + keep).min(self.top), Size4KiB::SIZE);
+        if new_top >= self.top {
+            return 0;
+        }
+        let mut unmapped = 0;
+        // HEAP, then MAPPER, then FRAME_ALLOCATOR
+        debug_assert!(HEAP.held_here(), "Heap trimmed without the heap lock");
+        match (MAPPER.lock().as_mut(), FRAME_ALLOCATOR.lock().as_mut()) {
+            (Some(m), Some(a)) => unsafe {
+                let _ = unmap_range(m, a, new_top..self.top, |a, _, phys, len| {
+                    unmapped += len;
+                    release_frames(a, phys, len)
+                });
+            },
+            _ => return 0,
+        }
+        unsafe {
+            if new_top == start {
+                if prev.is_null() {
+                    self.head = null_mut();
+                } else {
+                    (*prev).next = null_mut();
+                }
+            } else {
+                (*last).size = (new_top - start) as usize;
+            }
+        }
+        let freed = self.top - new_top;
+        self.top = new_top;
+        unaccount(unmapped);
+        freed
+    }
+}
+
+/// Rounds a layout up to the block size and alignment the heap works with.
+#[inline]
+fn block_layout(layout: Layout) -> (usize, usize) {
+    let size = align_up(layout.size().max(MIN_BLOCK) as u64, MIN_BLOCK as u64) as usize;
+    (size, layout.align().max(MIN_BLOCK))
+}
+
+/// The kernel heap.
+///
+/// The heap lives in its own window of virtual address space ([`HEAP_START`] to [`HEAP_END`]) and starts out empty.
+/// When no free block can satisfy an allocation, more pages are mapped at the top of the heap; when enough memory at
+/// the top is free again, it is unmapped and its frames go back to the frame allocator. Free blocks are kept on a
+/// single address-ordered list and merged with their neighbours when freed. The memory subsystem must be initialized
+/// before the first allocation.
+#[derive(Clone, Copy, Debug)]
+pub struct KernelHeap;
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        unsafe { lock_heap().allocate(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { lock_heap().deallocate(ptr, layout) }
+    }
+}
+
+/// Takes the heap lock for an operation that may grow or trim the heap, and so take MAPPER and FRAME_ALLOCATOR after
+/// it. Holding either of those here would deadlock as soon as the heap needs them.
+#[inline]
+fn lock_heap() -> OwnedMutexGuard<'static, Heap> {
+    debug_assert!(
+        !MAPPER.held_here() && !FRAME_ALLOCATOR.held_here(),
+        "Heap used with the page table mapper or frame allocator locked"
+    );
+    HEAP.lock()
+}
+
+/// Returns every free page at the top of the heap to the frame allocator. Called when the system runs low on memory.
+/// Returns the number of bytes released.
+pub fn trim() -> u64 {
+    let mut heap = lock_heap();
+    #[cfg(feature = "kasan")]
+    while let Some((addr, size)) = heap.quarantine.pop() {
+        unsafe { heap.release(addr, size) }
+    }
+    heap.trim(0)
+}
+
+/// Returns the number of bytes of the heap window that are currently mapped.
+pub fn size() -> u64 {
+    HEAP.lock().top - HEAP_START
+}
+
+/// Returns the number of bytes currently allocated from the heap.
+pub fn used() -> u64 {
+    HEAP.lock().used as u64
+}
@@ -0,0 +1,261 @@
+// SPDX-License-Identifier: MPL-2.0
+use super::{
+    flush_tlb_all,
+    frame::{self, FrameFlags},
+    phys_to_virt, GlobalFrameAllocator, MemoryError, FRAME_ALLOCATOR, MAPPER,
+};
+use core::arch::asm;
+use core::ops::Range;
+use core::ptr::addr_of;
+use core::sync::atomic::{AtomicBool, Ordering};
+use log::*;
+use raw_cpuid::CpuId;
+use x86_64::{
+    registers::{
+        control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::{
+        page_table::PageTableEntry, FrameAllocator, OffsetPageTable, PageSize, PageTable,
+        PageTableFlags, PhysFrame, Size4KiB,
+    },
+    PhysAddr,
+};
+
+extern "C" {
+    static __text_start: u8;
+    static __text_end: u8;
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __data_start: u8;
+    static __data_end: u8;
+}
+
+static NX_ENABLED: AtomicBool = AtomicBool::new(false);
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Returns `NO_EXECUTE` if the processor honours it, or no flags otherwise. Setting the bit without EFER.NXE makes
+/// the entry invalid, so mappings of data should add this rather than `NO_EXECUTE` itself.
+#[inline]
+pub fn no_execute() -> PageTableFlags {
+    if NX_ENABLED.load(Ordering::Relaxed) {
+        PageTableFlags::NO_EXECUTE
+    } else {
+        PageTableFlags::empty()
+    }
+}
+
+/// Enables no-execute pages (EFER.NXE) and the supervisor protection features (CR0.WP, SMEP, SMAP and UMIP) that
+/// the processor supports. Must run on every processor.
+#[cold]
+pub fn enable_protections() {
+    let id = CpuId::new();
+    if id
+        .get_extended_processor_and_feature_identifiers()
+        .map_or(false, |f| f.has_execute_disable())
+    {
+        unsafe {
+            Efer::update(|f| f.insert(EferFlags::NO_EXECUTE_ENABLE));
+        }
+        NX_ENABLED.store(true, Ordering::Relaxed);
+    } else {
+        warn!("Processor does not support no-execute pages");
+    }
+    unsafe {
+        Cr0::update(|f| f.insert(Cr0Flags::WRITE_PROTECT));
+    }
+    let features = id.get_extended_feature_info();
+    let has = |f: fn(&raw_cpuid::ExtendedFeatures) -> bool| features.as_ref().map_or(false, f);
+    unsafe {
+        Cr4::update(|f| {
+            if has(|f| f.has_smep()) {
+                f.insert(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION);
+            }
+            if has(|f| f.has_smap()) {
+                f.insert(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION);
+            }
+            if has(|f| f.has_umip()) {
+                f.insert(Cr4Flags::USER_MODE_INSTRUCTION_PREVENTION);
+            }
+        });
+    }
+    SMAP_ENABLED.store(
+        Cr4::read().contains(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION),
+        Ordering::Relaxed,
+    );
+    info!("Supervisor protections enabled: {:?}", Cr4::read());
+}
+
+/// Runs `f` with access to user pages allowed. With SMAP enabled, the kernel faults on any access to a user page
+/// outside of this.
+#[inline]
+pub fn with_user_access<R>(f: impl FnOnce() -> R) -> R {
+    let smap = SMAP_ENABLED.load(Ordering::Relaxed);
+    if smap {
+        unsafe {
+            asm!("stac", options(nomem, nostack));
+        }
+    }
+    let ret = f();
+    if smap {
+        unsafe {
+            asm!("clac", options(nomem, nostack));
+        }
+    }
+    ret
+}
+
+/// Returns the page-aligned range between two linker symbols.
+#[inline]
+fn section(start: *const u8, end: *const u8) -> Range<u64> {
+    (start as u64 & !0xFFF)..((end as u64 + 0xFFF) & !0xFFF)
+}
+
+/// Enables the processor's protection features and remaps the kernel image so that `.text` is read-only and
+/// executable, `.rodata` is read-only and `.data` and `.bss` are writable but not executable.
+#[cold]
+pub(crate) fn init(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut GlobalFrameAllocator,
+) {
+    enable_protections();
+    let (text, rodata, data) = unsafe {
+        (
+            section(addr_of!(__text_start), addr_of!(__text_end)),
+            section(addr_of!(__rodata_start), addr_of!(__rodata_end)),
+            section(addr_of!(__data_start), addr_of!(__data_end)),
+        )
+    };
+    info!(
+        "Kernel image: text {:X?}, rodata {:X?}, data {:X?}",
+        text, rodata, data
+    );
+    let l4 = mapper.level_4_table();
+    unsafe {
+        protect(l4, frame_allocator, text, false, true);
+        protect(l4, frame_allocator, rodata, false, false);
+        protect(l4, frame_allocator, data, true, false);
+    }
+    flush_tlb_all();
+}
+
+/// Sets the write and execute permissions of every page mapped in `span`.
+unsafe fn protect(
+    l4: &mut PageTable,
+    frame_allocator: &mut GlobalFrameAllocator,
+    span: Range<u64>,
+    writable: bool,
+    executable: bool,
+) {
+    unsafe {
+        for_each_leaf(l4, frame_allocator, span, |_, entry| {
+            let mut flags = entry.flags();
+            flags.set(PageTableFlags::WRITABLE, writable);
+            flags.set(PageTableFlags::NO_EXECUTE, false);
+            if !executable {
+                flags |= no_execute();
+            }
+            entry.set_flags(flags);
+        });
+    }
+}
+
+/// Unmaps the pages of the kernel image in `span` without touching anything around them, so that they act as guard
+/// pages. The frames behind them stay with the kernel image.
+#[cold]
+pub(crate) fn unmap_guard(span: Range<u64>) {
+    match (MAPPER.lock().as_mut(), FRAME_ALLOCATOR.lock().as_mut()) {
+        (Some(m), Some(a)) => unsafe {
+            for_each_leaf(m.level_4_table(), a, span, |_, entry| entry.set_unused());
+        },
+        _ => panic!("Memory allocator or frame allocator are not set"),
+    }
+    flush_tlb_all();
+}
+
+/// Calls `f` on every leaf entry mapping part of `span`, with the address the entry maps. Huge pages that straddle
+/// either end of `span` are first split in place, without ever unmapping what they map, so this is safe to use on the
+/// memory the caller is running from. Unmapped pages are skipped. The caller must flush the TLB afterwards.
+unsafe fn for_each_leaf(
+    l4: &mut PageTable,
+    frame_allocator: &mut GlobalFrameAllocator,
+    span: Range<u64>,
+    f: impl FnMut(u64, &mut PageTableEntry),
+) {
+    unsafe { try_for_each_leaf(l4, frame_allocator, span, f) }
+        .expect("Cannot split kernel huge page: no more frames");
+}
+
+/// Like [`for_each_leaf`], but returns the first unmapped address in `span`, if any, and fails with
+/// [`MemoryError::OutOfFrames`] if a huge page cannot be split, leaving the leaves before it already visited.
+pub(super) unsafe fn try_for_each_leaf(
+    l4: &mut PageTable,
+    frame_allocator: &mut GlobalFrameAllocator,
+    span: Range<u64>,
+    mut f: impl FnMut(u64, &mut PageTableEntry),
+) -> Result<Option<u64>, MemoryError> {
+    let mut unmapped = None;
+    let mut addr = span.start;
+    while addr < span.end {
+        let mut table: *mut PageTable = l4;
+        let mut level = 4;
+        loop {
+            let shift = 12 + 9 * (level - 1);
+            let size = 1u64 << shift;
+            let base = addr & !(size - 1);
+            let entry = &mut unsafe { &mut *table }[((addr >> shift) & 0x1FF) as usize];
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) {
+                let _ = unmapped.get_or_insert(addr);
+                addr = base + size;
+                break;
+            }
+            if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+                if base >= span.start && base + size <= span.end {
+                    f(base, entry);
+                    addr = base + size;
+                    break;
+                }
+                let child = unsafe { split(entry.addr(), flags, level, frame_allocator) }
+                    .ok_or(MemoryError::OutOfFrames)?;
+                entry.set_addr(
+                    child,
+                    PageTableFlags::PRESENT
+                        | PageTableFlags::WRITABLE
+                        | (flags & PageTableFlags::USER_ACCESSIBLE),
+                );
+            }
+            table = phys_to_virt(entry.addr()).as_mut_ptr();
+            level -= 1;
+        }
+    }
+    Ok(unmapped)
+}
+
+/// Builds a table of pages one level below `level` that map the same memory as the huge page at `phys`, with the
+/// same flags. Returns the address of the new table, or `None` if no frame is left for it.
+unsafe fn split(
+    phys: PhysAddr,
+    flags: PageTableFlags,
+    level: u64,
+    frame_allocator: &mut GlobalFrameAllocator,
+) -> Option<PhysAddr> {
+    let frame: PhysFrame = frame_allocator.allocate_frame()?;
+    frame::mark(
+        frame.start_address().as_u64(),
+        Size4KiB::SIZE,
+        FrameFlags::PAGE_TABLE,
+    );
+    let child_size = 1u64 << (12 + 9 * (level - 2));
+    let child_flags = if level == 2 {
+        flags & !PageTableFlags::HUGE_PAGE
+    } else {
+        flags
+    };
+    let table: &mut PageTable = unsafe { &mut *phys_to_virt(frame.start_address()).as_mut_ptr() };
+    table
+        .iter_mut()
+        .enumerate()
+        .for_each(|(i, e)| e.set_addr(phys + (i as u64) * child_size, child_flags));
+    Some(frame.start_address())
+}
@@ -0,0 +1,523 @@
+// SPDX-License-Identifier: MPL-2.0
+use super::{
+    heap::{HEAP_END, HEAP_START},
+    image::no_execute,
+    map_range, FrameAllocatorAllSizes, MemoryError,
+};
+use core::alloc::Layout;
+use core::arch::asm;
+use core::fmt;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use log::*;
+use x86_64::{
+    addr::align_up,
+    structures::paging::{mapper::MapperAllSizes, PageSize, PageTableFlags, Size4KiB, Translate},
+};
+
+/// Start of the shadow of the heap window.
+pub const SHADOW_START: u64 = HEAP_END;
+/// End (exclusive) of the shadow of the heap window.
+pub const SHADOW_END: u64 = SHADOW_START + ((HEAP_END - HEAP_START) >> SHADOW_SHIFT);
+/// Bytes of heap described by one shadow byte.
+const GRANULE: usize = 8;
+const SHADOW_SHIFT: u32 = 3;
+
+/// Shadow values of granules no byte of which may be accessed.
+const LEFT_REDZONE: u8 = 0xF1;
+const RIGHT_REDZONE: u8 = 0xFA;
+const FREED: u8 = 0xFB;
+const UNALLOCATED: u8 = 0xFC;
+
+/// Smallest redzone in front of an allocation. It holds the allocation header.
+const LEFT_REDZONE_SIZE: usize = 128;
+/// Smallest redzone behind an allocation.
+const RIGHT_REDZONE_SIZE: usize = 32;
+/// Return addresses recorded for allocation and free sites.
+const TRACE_DEPTH: usize = 6;
+/// Frame pointers further than this above the stack pointer are not followed.
+const MAX_STACK_WALK: usize = 1 << 20;
+/// Freed allocations are held back from reuse until more than this many bytes or entries are in quarantine.
+const QUARANTINE_BYTES: usize = 4 << 20;
+const QUARANTINE_ENTRIES: usize = 1024;
+/// Magic values in the allocation header.
+const LIVE: u64 = 0x4B41_5341_4E4C_4956;
+const DEAD: u64 = 0x4B41_5341_4E44_4541;
+
+/// End (exclusive) of the shadow that is mapped.
+static SHADOW_TOP: AtomicU64 = AtomicU64::new(SHADOW_START);
+/// End (exclusive) of the part of the heap window the shadow describes.
+static COVERED_END: AtomicU64 = AtomicU64::new(HEAP_START);
+/// Set while a report is being printed, so that accesses made while printing it are not reported in turn.
+static REPORTING: AtomicBool = AtomicBool::new(false);
+
+/// The header kept at the end of the left redzone of every allocation.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct AllocInfo {
+    state: u64,
+    size: usize,
+    alloc_site: [u64; TRACE_DEPTH],
+    free_site: [u64; TRACE_DEPTH],
+}
+
+/// Freed allocations waiting to be returned to the heap, oldest first.
+#[derive(Debug)]
+pub(crate) struct Quarantine {
+    entries: [(usize, usize); QUARANTINE_ENTRIES],
+    head: usize,
+    len: usize,
+    bytes: usize,
+}
+
+impl Quarantine {
+    pub(crate) const fn new() -> Quarantine {
+        Quarantine {
+            entries: [(0, 0); QUARANTINE_ENTRIES],
+            head: 0,
+            len: 0,
+            bytes: 0,
+        }
+    }
+
+    /// Adds the heap block `[addr, addr + size)`. The quarantine must have been evicted down to size before.
+    pub(crate) fn push(&mut self, addr: usize, size: usize) {
+        self.entries[(self.head + self.len) % QUARANTINE_ENTRIES] = (addr, size);
+        self.len += 1;
+        self.bytes += size;
+    }
+
+    /// Takes the oldest block out of the quarantine if it holds too much.
+    pub(crate) fn evict(&mut self) -> Option<(usize, usize)> {
+        if self.len < QUARANTINE_ENTRIES && self.bytes <= QUARANTINE_BYTES {
+            return None;
+        }
+        self.pop()
+    }
+
+    /// Takes the oldest block out of the quarantine.
+    pub(crate) fn pop(&mut self) -> Option<(usize, usize)> {
+        if self.len == 0 {
+            return None;
+        }
+        let (addr, size) = self.entries[self.head];
+        self.head = (self.head + 1) % QUARANTINE_ENTRIES;
+        self.len -= 1;
+        self.bytes -= size;
+        Some((addr, size))
+    }
+}
+
+/// Return addresses of a call stack, innermost first; printed as a list of addresses to resolve with `addr2line`.
+#[derive(Clone, Copy, Debug)]
+struct Trace<'a>(&'a [u64]);
+
+impl fmt::Display for Trace<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut frames = self.0.iter().take_while(|&&ret| ret != 0);
+        match frames.next() {
+            Some(ret) => write!(f, "{:X}h", ret)?,
+            None => return write!(f, "unknown"),
+        }
+        frames.try_for_each(|ret| write!(f, " <- {:X}h", ret))
+    }
+}
+
+/// Maps shadow for the heap window up to `end` and marks `[start, end)` as unallocated. Called as the heap grows,
+/// before the new part of the window is mapped.
+///
+/// # Safety
+///
+/// The caller must hold the heap lock.
+#[no_sanitize(address)]
+pub(crate) unsafe fn grow(
+    mapper: &mut (impl MapperAllSizes + Translate),
+    frame_allocator: &mut impl FrameAllocatorAllSizes,
+    start: u64,
+    end: u64,
+) -> Result<(), MemoryError> {
+    let shadow_end = align_up(shadow_addr(end as usize) as u64, Size4KiB::SIZE);
+    let top = SHADOW_TOP.load(Ordering::Relaxed);
+    if shadow_end > top {
+        unsafe {
+            map_range(
+                mapper,
+                frame_allocator,
+                top,
+                None,
+                shadow_end - top,
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE | no_execute(),
+            )?;
+        }
+        SHADOW_TOP.store(shadow_end, Ordering::Relaxed);
+    }
+    poison(start as usize, (end - start) as usize, UNALLOCATED);
+    if end > COVERED_END.load(Ordering::Relaxed) {
+        COVERED_END.store(end, Ordering::Release);
+    }
+    Ok(())
+}
+
+/// Returns the layout of the heap block that holds an allocation of `layout` with its redzones, and the offset of the
+/// allocation in it.
+pub(crate) fn pad(layout: Layout) -> Option<(Layout, usize)> {
+    let align = layout.align().max(2 * GRANULE);
+    let left = align_up(LEFT_REDZONE_SIZE as u64, align as u64) as usize;
+    let size = left
+        .checked_add(align_up(layout.size() as u64, GRANULE as u64) as usize)?
+        .checked_add(RIGHT_REDZONE_SIZE)?;
+    Some((Layout::from_size_align(size, align).ok()?, left))
+}
+
+/// Sets up the redzones and header of an allocation of `size` bytes at offset `left` in the heap block `[block,
+/// block + len)`, and returns the address of the allocation. Returns null if `block` is.
+///
+/// # Safety
+///
+/// The block must have just been taken from the heap, and be laid out as [`pad`] says.
+#[no_sanitize(address)]
+pub(crate) unsafe fn on_alloc(block: *mut u8, len: usize, left: usize, size: usize) -> *mut u8 {
+    if block.is_null() {
+        return block;
+    }
+    let addr = block as usize + left;
+    let body = align_up(size as u64, GRANULE as u64) as usize;
+    poison(block as usize, left, LEFT_REDZONE);
+    unpoison(addr, size);
+    poison(addr + body, len - left - body, RIGHT_REDZONE);
+    unsafe {
+        header(addr).write(AllocInfo {
+            state: LIVE,
+            size,
+            alloc_site: backtrace(),
+            free_site: [0; TRACE_DEPTH],
+        });
+    }
+    addr as *mut u8
+}
+
+/// Poisons the allocation of `size` bytes at `addr` as freed and records where it was freed. Returns false, after
+/// reporting it, if `addr` is not a live allocation, in which case the heap must leave it alone.
+///
+/// # Safety
+///
+/// `addr` must lie in the heap window.
+#[no_sanitize(address)]
+pub(crate) unsafe fn on_free(addr: usize, size: usize) -> bool {
+    let allocated = addr % GRANULE == 0
+        && addr as u64 >= HEAP_START + LEFT_REDZONE_SIZE as u64
+        && covered(addr - 1, 1)
+        && shadow(addr - 1) == LEFT_REDZONE;
+    if !allocated {
+        report_free(addr, false);
+        return false;
+    }
+    let info = unsafe { &mut *header(addr) };
+    if info.state != LIVE {
+        report_free(addr, info.state == DEAD);
+        return false;
+    }
+    info.state = DEAD;
+    info.free_site = backtrace();
+    poison(addr, align_up(size as u64, GRANULE as u64) as usize, FREED);
+    true
+}
+
+/// Marks the heap block `[addr, addr + len)` as unallocated, as it goes back to the heap's free list.
+pub(crate) fn on_release(addr: usize, len: usize) {
+    poison(addr, len, UNALLOCATED);
+}
+
+/// Checks an access of `size` bytes at `addr`, reporting it if any of them is poisoned. Accesses outside the heap are
+/// not checked.
+///
+/// Every 8 bytes of the heap window are described by one byte of shadow memory, mapped right above the window as the
+/// heap grows. A shadow byte of 0 means all 8 bytes are accessible, 1 to 7 that only that many leading bytes are,
+/// and anything else that none are and why. Allocations are surrounded by redzones, and freed allocations sit in a
+/// quarantine for a while before the heap hands their memory out again, so that overflows and use-after-free hit
+/// poisoned memory. Reports go to the log, and so to the serial port, with the allocation and free sites recorded in
+/// the allocation's header.
+#[no_sanitize(address)]
+pub fn check(addr: usize, size: usize, write: bool) {
+    if size == 0 || !covered(addr, size) {
+        return;
+    }
+    if let Some((bad, value)) = first_poisoned(addr, size) {
+        report(addr, size, write, bad, value);
+    }
+}
+
+#[inline]
+fn covered(addr: usize, size: usize) -> bool {
+    addr as u64 >= HEAP_START
+        && (addr as u64)
+            .checked_add(size as u64)
+            .map_or(false, |end| end <= COVERED_END.load(Ordering::Acquire))
+}
+
+/// Returns the first poisoned byte of `[addr, addr + size)` and its shadow value.
+#[no_sanitize(address)]
+fn first_poisoned(addr: usize, size: usize) -> Option<(usize, u8)> {
+    let end = addr + size;
+    let mut start = addr;
+    while start < end {
+        let granule = start & !(GRANULE - 1);
+        let last = (granule + GRANULE).min(end) - 1;
+        let value = shadow(start);
+        // Accessible prefixes are 1-7 bytes long; every larger value poisons the whole granule
+        if value != 0 && (value as usize >= GRANULE || last - granule >= value as usize) {
+            let bad = if (value as usize) < GRANULE {
+                (granule + value as usize).max(start)
+            } else {
+                start
+            };
+            return Some((bad, value));
+        }
+        start = granule + GRANULE;
+    }
+    None
+}
+
+#[inline]
+fn shadow_addr(addr: usize) -> usize {
+    SHADOW_START as usize + ((addr - HEAP_START as usize) >> SHADOW_SHIFT)
+}
+
+#[inline]
+#[no_sanitize(address)]
+fn shadow(addr: usize) -> u8 {
+    unsafe { *(shadow_addr(addr) as *const u8) }
+}
+
+/// Sets the shadow of `[addr, addr + len)`, both of which must be multiples of the granule, to `value`.
+#[no_sanitize(address)]
+fn poison(addr: usize, len: usize, value: u8) {
+    unsafe {
+        (shadow_addr(addr) as *mut u8).write_bytes(value, len / GRANULE);
+    }
+}
+
+/// Marks the `size` bytes at `addr`, which must be a multiple of the granule, as accessible.
+#[no_sanitize(address)]
+fn unpoison(addr: usize, size: usize) {
+    poison(addr, size & !(GRANULE - 1), 0);
+    if size % GRANULE != 0 {
+        unsafe {
+            *(shadow_addr(addr + size) as *mut u8) = (size % GRANULE) as u8;
+        }
+    }
+}
+
+#[inline]
+fn header(addr: usize) -> *mut AllocInfo {
+    (addr - size_of::<AllocInfo>()) as *mut AllocInfo
+}
+
+/// Finds the allocation `addr` is in or next to, by walking the shadow back to the left redzone in front of it.
+#[no_sanitize(address)]
+fn find_allocation(addr: usize) -> Option<(usize, AllocInfo)> {
+    let limit = addr
+        .saturating_sub(QUARANTINE_BYTES)
+        .max(HEAP_START as usize);
+    let mut granule = addr & !(GRANULE - 1);
+    if shadow(granule) == LEFT_REDZONE {
+        // In front of an allocation: it is the one that follows
+        while shadow(granule) == LEFT_REDZONE {
+            granule += GRANULE;
+            if granule as u64 >= COVERED_END.load(Ordering::Acquire) {
+                return None;
+            }
+        }
+    } else {
+        loop {
+            if granule < limit + GRANULE || shadow(granule) == UNALLOCATED {
+                return None;
+            }
+            granule -= GRANULE;
+            if shadow(granule) == LEFT_REDZONE {
+                break;
+            }
+        }
+        granule += GRANULE;
+    }
+    let info = unsafe { header(granule).read() };
+    if info.state == LIVE || info.state == DEAD {
+        Some((granule, info))
+    } else {
+        None
+    }
+}
+
+/// Returns the return addresses of the caller's call stack, found by following frame pointers.
+#[inline(always)]
+fn backtrace() -> [u64; TRACE_DEPTH] {
+    let mut trace = [0; TRACE_DEPTH];
+    let (mut rbp, rsp): (usize, usize);
+    unsafe {
+        asm!("mov {}, rbp", "mov {}, rsp", out(reg) rbp, out(reg) rsp, options(nomem, nostack, preserves_flags));
+    }
+    for ret in trace.iter_mut() {
+        // Only follow frame pointers that point a little further up the current stack
+        if rbp < rsp || rbp - rsp >= MAX_STACK_WALK || rbp % size_of::<usize>() != 0 {
+            break;
+        }
+        let frame = rbp as *const u64;
+        let (next, addr) = unsafe { (*frame as usize, *frame.add(1)) };
+        if addr == 0 {
+            break;
+        }
+        *ret = addr;
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+    trace
+}
+
+#[cold]
+#[no_sanitize(address)]
+fn report(addr: usize, size: usize, write: bool, bad: usize, value: u8) {
+    if REPORTING.swap(true, Ordering::Acquire) {
+        return;
+    }
+    let kind = match value {
+        FREED => "use-after-free",
+        UNALLOCATED => "access to unallocated heap memory",
+        _ => "heap-out-of-bounds",
+    };
+    error!(
+        "KASAN: {} {} of {} bytes at {:X}h (first bad byte {:X}h)",
+        kind,
+        if write { "write" } else { "read" },
+        size,
+        addr,
+        bad
+    );
+    error!("Accessed from {}", Trace(&backtrace()));
+    if let Some((start, info)) = find_allocation(bad) {
+        let (distance, place) = if bad < start {
+            (start - bad, "to the left of")
+        } else if bad >= start + info.size {
+            (bad - (start + info.size), "to the right of")
+        } else {
+            (bad - start, "inside")
+        };
+        error!(
+            "The address is {} bytes {} the {}-byte allocation at {:X}h",
+            distance, place, info.size, start
+        );
+        error!("Allocated at {}", Trace(&info.alloc_site));
+        if info.state == DEAD {
+            error!("Freed at {}", Trace(&info.free_site));
+        }
+    }
+    REPORTING.store(false, Ordering::Release);
+}
+
+#[cold]
+#[no_sanitize(address)]
+fn report_free(addr: usize, double: bool) {
+    if REPORTING.swap(true, Ordering::Acquire) {
+        return;
+    }
+    if double {
+        error!("KASAN: double free of {:X}h", addr);
+    } else {
+        error!("KASAN: free of {:X}h, which is not a heap allocation", addr);
+    }
+    error!("Freed from {}", Trace(&backtrace()));
+    if let Some((_, info)) = find_allocation(addr).filter(|&(start, _)| start == addr) {
+        error!("Allocated at {}", Trace(&info.alloc_site));
+        if info.state == DEAD {
+            error!("First freed at {}", Trace(&info.free_site));
+        }
+    }
+    REPORTING.store(false, Ordering::Release);
+}
+
+// The hooks code built with `-Zsanitizer=kernel-address -Cllvm-args=-asan-instrumentation-with-call-threshold=0`
+// calls for every load and store. Allocation and free sites are found by walking frame pointers, so such builds
+// should also use `-Cforce-frame-pointers=yes`.
+macro_rules! hooks {
+    ($($size:literal => $load:ident, $store:ident, $load_noabort:ident, $store_noabort:ident;)*) => {
+        $(
+            #[doc = concat!("Checks a ", stringify!($size), "-byte load made by instrumented code.")]
+            #[no_mangle]
+            #[no_sanitize(address)]
+            pub extern "C" fn $load(addr: usize) {
+                check(addr, $size, false);
+            }
+
+            #[doc = concat!("Checks a ", stringify!($size), "-byte store made by instrumented code.")]
+            #[no_mangle]
+            #[no_sanitize(address)]
+            pub extern "C" fn $store(addr: usize) {
+                check(addr, $size, true);
+            }
+
+            #[doc = concat!("Checks a ", stringify!($size), "-byte load made by instrumented code.")]
+            #[no_mangle]
+            #[no_sanitize(address)]
+            pub extern "C" fn $load_noabort(addr: usize) {
+                check(addr, $size, false);
+            }
+
+            #[doc = concat!("Checks a ", stringify!($size), "-byte store made by instrumented code.")]
+            #[no_mangle]
+            #[no_sanitize(address)]
+            pub extern "C" fn $store_noabort(addr: usize) {
+                check(addr, $size, true);
+            }
+        )*
+    };
+}
+
+hooks! {
+    1 => __asan_load1, __asan_store1, __asan_load1_noabort, __asan_store1_noabort;
+    2 => __asan_load2, __asan_store2, __asan_load2_noabort, __asan_store2_noabort;
+    4 => __asan_load4, __asan_store4, __asan_load4_noabort, __asan_store4_noabort;
+    8 => __asan_load8, __asan_store8, __asan_load8_noabort, __asan_store8_noabort;
+    16 => __asan_load16, __asan_store16, __asan_load16_noabort, __asan_store16_noabort;
+}
+
+/// Checks a load of `size` bytes made by instrumented code.
+#[no_mangle]
+#[no_sanitize(address)]
+pub extern "C" fn __asan_loadN(addr: usize, size: usize) {
+    check(addr, size, false);
+}
+
+/// Checks a store of `size` bytes made by instrumented code.
+#[no_mangle]
+#[no_sanitize(address)]
+pub extern "C" fn __asan_storeN(addr: usize, size: usize) {
+    check(addr, size, true);
+}
+
+/// Checks a load of `size` bytes made by instrumented code.
+#[no_mangle]
+#[no_sanitize(address)]
+pub extern "C" fn __asan_loadN_noabort(addr: usize, size: usize) {
+    check(addr, size, false);
+}
+
+/// Checks a store of `size` bytes made by instrumented code.
+#[no_mangle]
+#[no_sanitize(address)]
+pub extern "C" fn __asan_storeN_noabort(addr: usize, size: usize) {
+    check(addr, size, true);
+}
+
+/// Called by instrumented code before calls that do not return. Only the heap is checked, so there is no stack
+/// shadow to clean up.
+#[no_mangle]
+pub extern "C" fn __asan_handle_no_return() {}
+
+/// Called by instrumented code to register its globals. Globals are not checked.
+#[no_mangle]
+pub extern "C" fn __asan_register_globals(_globals: usize, _count: usize) {}
+
+/// Called by instrumented code to unregister its globals. Globals are not checked.
+#[no_mangle]
+pub extern "C" fn __asan_unregister_globals(_globals: usize, _count: usize) {}
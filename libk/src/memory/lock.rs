@@ -0,0 +1,92 @@
+// SPDX-License-Identifier: MPL-2.0
+use bit_field::BitField;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::mutex::ticket::{TicketMutex, TicketMutexGuard};
+use x86_64::registers::model_specific::Msr;
+
+/// The owner of a lock nobody holds.
+const NO_OWNER: u32 = u32::MAX;
+
+/// A ticket lock that records which processor holds it.
+///
+/// Code that may have interrupted the holder, such as the page fault handler, uses this to tell a lock it would
+/// deadlock on (held by the processor it runs on) from one that is merely busy (held by another processor).
+#[derive(Debug)]
+pub(crate) struct OwnedMutex<T> {
+    inner: TicketMutex<T>,
+    owner: AtomicU32,
+}
+
+/// A guard for an [`OwnedMutex`]. The owner is forgotten before the lock is released.
+#[derive(Debug)]
+pub(crate) struct OwnedMutexGuard<'a, T> {
+    guard: TicketMutexGuard<'a, T>,
+    owner: &'a AtomicU32,
+}
+
+impl<T> OwnedMutex<T> {
+    /// Creates an unlocked lock around `value`.
+    pub(crate) const fn new(value: T) -> Self {
+        OwnedMutex {
+            inner: TicketMutex::new(value),
+            owner: AtomicU32::new(NO_OWNER),
+        }
+    }
+
+    /// Takes the lock, waiting for as long as another processor holds it.
+    pub(crate) fn lock(&self) -> OwnedMutexGuard<'_, T> {
+        let guard = self.inner.lock();
+        self.owner.store(cpu_id(), Ordering::Relaxed);
+        OwnedMutexGuard {
+            guard,
+            owner: &self.owner,
+        }
+    }
+
+    /// Takes the lock, unless the processor this runs on already holds it, in which case waiting would never end.
+    pub(crate) fn lock_unless_held(&self) -> Option<OwnedMutexGuard<'_, T>> {
+        if self.held_here() {
+            None
+        } else {
+            Some(self.lock())
+        }
+    }
+
+    /// Returns true if the processor this runs on holds the lock. Only this processor ever records itself as the
+    /// owner, so the answer cannot change under the caller's feet.
+    pub(crate) fn held_here(&self) -> bool {
+        self.owner.load(Ordering::Relaxed) == cpu_id()
+    }
+}
+
+impl<T> Deref for OwnedMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for OwnedMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for OwnedMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.owner.store(NO_OWNER, Ordering::Relaxed);
+    }
+}
+
+/// Returns an identifier for the processor this runs on: its x2APIC ID once x2APIC mode is on, and 0 before then,
+/// when the bootstrap processor is the only one running.
+fn cpu_id() -> u32 {
+    let apic_base = unsafe { Msr::new(0x1B).read() };
+    if apic_base.get_bit(10) {
+        unsafe { Msr::new(0x802).read() as u32 }
+    } else {
+        0
+    }
+}
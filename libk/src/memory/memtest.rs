@@ -0,0 +1,204 @@
+// SPDX-License-Identifier: MPL-2.0
+use super::buddy::FRAME_SIZE;
+use core::ops::Range;
+use core::ptr::{read_volatile, write_volatile};
+use heapless::Vec;
+use log::*;
+use spin::Once;
+use stivale_boot::v2::{StivaleMemoryMapEntry, StivaleMemoryMapEntryType};
+use x86_64::instructions::random::RdRand;
+
+/// The most ranges of bad frames that are told apart. Past that, the two closest ranges are merged to make room.
+pub const MAX_BAD_RANGES: usize = 64;
+/// The pattern moving inversions runs with when no random one can be had, besides its complement.
+const FALLBACK_PATTERN: u64 = 0x5A5A_5A5A_5A5A_5A5A;
+/// The pattern moving inversions always runs with, besides its complement.
+const FIXED_PATTERN: u64 = 0x5555_5555_5555_5555;
+/// Bits in a word.
+const WORD_BITS: u64 = u64::BITS as u64;
+
+/// The frames found faulty by [`run`], sorted by address.
+static BAD: Once<Vec<Range<u64>, MAX_BAD_RANGES>> = Once::new();
+
+/// Tests the usable regions of the memory map handed over by the bootloader, `passes` times, and records the frames
+/// that failed so that [`init_memory_map`](super::init_memory_map) reports them as bad memory and the frame allocator
+/// never hands them out. Returns the number of bad bytes found.
+///
+/// Each pass runs, in order:
+///
+/// - walking ones (and zeros) over the first 64 words of every frame, which catches stuck or shorted data lines;
+/// - address-in-address, which writes every word's own address (and then its complement) into it and catches
+///   address lines that are stuck or shorted, so that two addresses land on the same cell;
+/// - moving inversions with a fixed and a random pattern, which fills memory with the pattern, then checks and
+///   inverts every word going up and checks and restores it going down, catching cells disturbed by their neighbours.
+///
+/// Must run before [`init_memory_map`](super::init_memory_map), while nothing uses usable memory yet; `offset` is the
+/// offset of the bootloader's higher-half mapping of physical memory. Only the first call has any effect. Testing
+/// takes several seconds per gigabyte per pass.
+#[cold]
+pub fn run(map: &[StivaleMemoryMapEntry], offset: u64, passes: u32) -> u64 {
+    if BAD.is_completed() {
+        return 0;
+    }
+    let regions = || {
+        map.iter()
+            .filter(|e| e.entry_type() == StivaleMemoryMapEntryType::Usable)
+            .map(|e| align_up(e.base)..(e.end_address() & !(FRAME_SIZE - 1)))
+            .filter(|r| r.start < r.end)
+    };
+    let random = RdRand::new()
+        .and_then(|r| r.get_u64())
+        .unwrap_or(FALLBACK_PATTERN);
+    let mut tester = Tester {
+        offset,
+        bad: Vec::new(),
+    };
+    let total: u64 = regions().map(|r| r.end - r.start).sum();
+    info!("Testing {:X} bytes of memory, {} pass(es)", total, passes);
+    for pass in 1..=passes {
+        regions().for_each(|r| tester.walking_ones(r));
+        regions().for_each(|r| tester.address_in_address(r));
+        regions().for_each(|r| tester.moving_inversions(r, FIXED_PATTERN));
+        regions().for_each(|r| tester.moving_inversions(r, random));
+        info!(
+            "Memory test pass {} of {} done, {} bad range(s) so far",
+            pass,
+            passes,
+            tester.bad.len()
+        );
+    }
+    let mut bad = tester.bad;
+    bad.sort_unstable_by_key(|r| r.start);
+    let bytes = bad.iter().map(|r| r.end - r.start).sum();
+    if bytes == 0 {
+        info!("Memory test found no faults");
+    } else {
+        bad.iter()
+            .for_each(|r| warn!("Bad memory: {:X}h-{:X}h", r.start, r.end));
+        warn!("Memory test found {:X} bad bytes", bytes);
+    }
+    BAD.call_once(|| bad);
+    bytes
+}
+
+/// Returns the ranges of frames found faulty by [`run`], sorted by address. Empty if the memory was not tested.
+pub fn bad_ranges() -> &'static [Range<u64>] {
+    BAD.get().map_or(&[], |b| b.as_slice())
+}
+
+/// Runs the tests over physical memory through the higher-half mapping and collects the frames that fail.
+#[derive(Debug)]
+struct Tester {
+    offset: u64,
+    bad: Vec<Range<u64>, MAX_BAD_RANGES>,
+}
+
+impl Tester {
+    #[inline]
+    fn write(&self, addr: u64, value: u64) {
+        unsafe { write_volatile((self.offset + addr) as *mut u64, value) }
+    }
+
+    #[inline]
+    fn check(&mut self, addr: u64, expected: u64) {
+        let found = unsafe { read_volatile((self.offset + addr) as *const u64) };
+        if found != expected {
+            self.fault(addr, expected, found);
+        }
+    }
+
+    /// Writes a single set bit into each of the first 64 words of every frame, checks them, then does the same with
+    /// a single clear bit.
+    fn walking_ones(&mut self, region: Range<u64>) {
+        let words = WORD_BITS.min(FRAME_SIZE / 8);
+        for invert in [0, u64::MAX] {
+            for frame in region.clone().step_by(FRAME_SIZE as usize) {
+                (0..words).for_each(|bit| self.write(frame + bit * 8, (1 << bit) ^ invert));
+                (0..words).for_each(|bit| self.check(frame + bit * 8, (1 << bit) ^ invert));
+            }
+        }
+    }
+
+    /// Fills every word with its own address and checks it, then does the same with the complement of the address.
+    fn address_in_address(&mut self, region: Range<u64>) {
+        for invert in [0, u64::MAX] {
+            words(&region).for_each(|addr| self.write(addr, addr ^ invert));
+            words(&region).for_each(|addr| self.check(addr, addr ^ invert));
+        }
+    }
+
+    /// Fills the region with `pattern`, then checks every word and inverts it in ascending order, and checks every
+    /// word and restores it in descending order.
+    fn moving_inversions(&mut self, region: Range<u64>, pattern: u64) {
+        words(&region).for_each(|addr| self.write(addr, pattern));
+        words(&region).for_each(|addr| {
+            self.check(addr, pattern);
+            self.write(addr, !pattern);
+        });
+        words(&region).rev().for_each(|addr| {
+            self.check(addr, !pattern);
+            self.write(addr, pattern);
+        });
+        words(&region).for_each(|addr| self.check(addr, pattern));
+    }
+
+    /// Records the frame containing `addr` as bad.
+    fn fault(&mut self, addr: u64, expected: u64, found: u64) {
+        let frame = addr & !(FRAME_SIZE - 1);
+        let end = frame + FRAME_SIZE;
+        if let Some(range) = self
+            .bad
+            .iter_mut()
+            .find(|r| r.start <= end && frame <= r.end)
+        {
+            if range.contains(&frame) {
+                return;
+            }
+            range.start = range.start.min(frame);
+            range.end = range.end.max(end);
+        } else {
+            if self.bad.is_full() {
+                self.merge_closest();
+            }
+            let _ = self.bad.push(frame..end);
+        }
+        warn!(
+            "Memory test: {:X}h read {:X}h, expected {:X}h",
+            addr, found, expected
+        );
+    }
+
+    /// Merges the two recorded ranges with the smallest gap between them, so that the frames in that gap are the only
+    /// good memory given up.
+    fn merge_closest(&mut self) {
+        self.bad.sort_unstable_by_key(|r| r.start);
+        let closest = self
+            .bad
+            .windows(2)
+            .enumerate()
+            .min_by_key(|(_, pair)| pair[1].start - pair[0].end)
+            .map(|(i, _)| i);
+        if let Some(i) = closest {
+            let next = self.bad.remove(i + 1);
+            warn!(
+                "Too many bad memory ranges; giving up {:X} good bytes between {:X}h and {:X}h",
+                next.start - self.bad[i].end,
+                self.bad[i].end,
+                next.start
+            );
+            self.bad[i].end = next.end;
+        }
+    }
+}
+
+/// Returns the address of every word in `region`, in ascending order.
+#[inline]
+fn words(region: &Range<u64>) -> impl DoubleEndedIterator<Item = u64> {
+    let start = region.start;
+    (0..(region.end - region.start) / 8).map(move |i| start + i * 8)
+}
+
+#[inline]
+fn align_up(addr: u64) -> u64 {
+    (addr + FRAME_SIZE - 1) & !(FRAME_SIZE - 1)
+}
@@ -0,0 +1,299 @@
+// SPDX-License-Identifier: MPL-2.0
+#[cfg(feature = "kasan")]
+use super::kasan;
+use super::{
+    flush_tlb_all, frame, image::no_execute, map_range, release_frames, unmap_range, vspace,
+    FRAME_ALLOCATOR, MAPPER,
+};
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::ptr::{read_volatile, write_volatile};
+use log::*;
+use raw_cpuid::CpuId;
+use voladdress::{Safe, VolAddress};
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    registers::model_specific::Msr,
+    structures::paging::{PageSize, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+/// The IA32_PAT MSR.
+const IA32_PAT: u32 = 0x277;
+/// PAT memory type encodings.
+const PAT_UC: u64 = 0x00;
+const PAT_WC: u64 = 0x01;
+const PAT_WT: u64 = 0x04;
+const PAT_WB: u64 = 0x06;
+const PAT_UC_MINUS: u64 = 0x07;
+/// The PAT layout the kernel uses. Entries 0-3 keep their power-on values (WB, WT, UC-, UC), so mappings that only set
+/// PWT and PCD, such as the bootloader's and the firmware's, mean what they always did. Entry 4 is write-combining
+/// and entry 5 write-through; both are only reachable through the PAT bit.
+const PAT_VALUE: u64 = {
+    let low = PAT_WB | PAT_WT << 8 | PAT_UC_MINUS << 16 | PAT_UC << 24;
+    let high = PAT_WC | PAT_WT << 8 | PAT_UC_MINUS << 16 | PAT_UC << 24;
+    low | high << 32
+};
+/// The PAT bit of a 4 KiB page table entry. Larger pages keep [`PageTableFlags::HUGE_PAGE`] in the same place, so
+/// mappings that use it are always made of 4 KiB pages.
+pub(crate) const PAT_4K: PageTableFlags = PageTableFlags::HUGE_PAGE;
+
+/// The memory type of a mapping, selected through the Page Attribute Table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CacheType {
+    /// Strongly uncached. Every access goes to the device, in program order. The right choice for registers.
+    Uncached,
+    /// Uncached, but writes may be combined and reordered in the write-combining buffers. Suits framebuffers.
+    WriteCombining,
+    /// Reads are cached; writes go straight through to the device.
+    WriteThrough,
+    /// Fully cached. Only suitable for memory that behaves like RAM.
+    WriteBack,
+}
+
+impl CacheType {
+    /// Returns the 4 KiB page table flags that select this memory type under the kernel's PAT layout.
+    pub fn flags(self) -> PageTableFlags {
+        match self {
+            CacheType::WriteBack => PageTableFlags::empty(),
+            CacheType::WriteCombining => PAT_4K,
+            CacheType::WriteThrough => PAT_4K | PageTableFlags::WRITE_THROUGH,
+            CacheType::Uncached => PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_CACHE,
+        }
+    }
+}
+
+/// Programs the PAT with the kernel's layout. Must run on every processor before it uses [`CacheType`] mappings.
+#[cold]
+pub fn init_pat() {
+    if !CpuId::new()
+        .get_feature_info()
+        .map_or(false, |f| f.has_pat())
+    {
+        warn!("Processor has no PAT; write-combining mappings will be uncached");
+        return;
+    }
+    without_interrupts(|| unsafe {
+        core::arch::asm!("wbinvd", options(nostack, preserves_flags));
+        Msr::new(IA32_PAT).write(PAT_VALUE);
+        core::arch::asm!("wbinvd", options(nostack, preserves_flags));
+    });
+    flush_tlb_all();
+}
+
+/// A mapping of a physical device range into kernel virtual address space.
+///
+/// The range is mapped at a randomly placed kernel virtual address, with a guard page on either side, using the
+/// requested memory type. It is unmapped, and its address range released, when the mapping is dropped. Accesses are
+/// volatile and bounds-checked.
+#[derive(Debug)]
+pub struct IoMapping {
+    phys: u64,
+    virt: u64,
+    len: u64,
+    /// Start of the page-aligned span that is actually mapped.
+    span_start: u64,
+    /// Length of the page-aligned span that is actually mapped.
+    span_len: u64,
+    cache: CacheType,
+}
+
+impl IoMapping {
+    /// Maps `len` bytes of device memory starting at physical address `phys` with the given memory type. Returns
+    /// `None` if no kernel virtual address space is left or the range could not be mapped.
+    pub fn new(phys: u64, len: u64, cache: CacheType) -> Option<IoMapping> {
+        if len == 0 {
+            return None;
+        }
+        let offset = phys & (Size4KiB::SIZE - 1);
+        let phys_start = phys - offset;
+        let span_len = (offset + len + Size4KiB::SIZE - 1) & !(Size4KiB::SIZE - 1);
+        let span_start = vspace::reserve(span_len, Size4KiB::SIZE, Size4KiB::SIZE, true)?.as_u64();
+        let flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | no_execute() | cache.flags();
+        let mapped = match (MAPPER.lock().as_mut(), FRAME_ALLOCATOR.lock().as_mut()) {
+            (Some(m), Some(a)) => unsafe {
+                map_range(m, a, span_start, Some(phys_start), span_len, flags)
+            },
+            _ => panic!("Memory allocator or frame allocator are not set"),
+        };
+        if let Err(e) = mapped {
+            warn!(
+                "Cannot map device memory at {:X}h, length {:X}: {}",
+                phys, len, e
+            );
+            let _ = vspace::release(VirtAddr::new_truncate(span_start));
+            return None;
+        }
+        frame::get_range(phys_start, span_len);
+        Some(IoMapping {
+            phys,
+            virt: span_start + offset,
+            len,
+            span_start,
+            span_len,
+            cache,
+        })
+    }
+
+    /// Gives up the mapping without unmapping it, and returns the virtual address it starts at. The mapping stays in
+    /// place until [`IoMapping::from_raw`] takes it back and it is dropped.
+    pub fn into_raw(self) -> VirtAddr {
+        let virt = self.virt_addr();
+        core::mem::forget(self);
+        virt
+    }
+
+    /// Takes back a mapping given up with [`IoMapping::into_raw`].
+    ///
+    /// # Safety
+    ///
+    /// `phys`, `virt`, `len` and `cache` must be what the mapping was made with and returned, and it must not have been
+    /// taken back already.
+    pub unsafe fn from_raw(phys: u64, virt: VirtAddr, len: u64, cache: CacheType) -> IoMapping {
+        let offset = phys & (Size4KiB::SIZE - 1);
+        IoMapping {
+            phys,
+            virt: virt.as_u64(),
+            len,
+            span_start: virt.as_u64() - offset,
+            span_len: (offset + len + Size4KiB::SIZE - 1) & !(Size4KiB::SIZE - 1),
+            cache,
+        }
+    }
+
+    /// Returns the physical address the mapping starts at.
+    #[inline]
+    pub fn phys_addr(&self) -> u64 {
+        self.phys
+    }
+
+    /// Returns the virtual address the mapping starts at.
+    #[inline]
+    pub fn virt_addr(&self) -> VirtAddr {
+        VirtAddr::new_truncate(self.virt)
+    }
+
+    /// Returns the size of the mapping in bytes.
+    #[inline]
+    pub fn size(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns the memory type the mapping was made with.
+    #[inline]
+    pub fn cache_type(&self) -> CacheType {
+        self.cache
+    }
+
+    /// Returns the address of a `T` at `offset`, panicking if it is out of bounds or misaligned.
+    #[inline]
+    fn addr_of<T>(&self, offset: usize) -> usize {
+        assert!(
+            offset as u64 + size_of::<T>() as u64 <= self.len,
+            "MMIO access at offset {:X} is past the end of the mapping",
+            offset
+        );
+        let addr = self.virt as usize + offset;
+        assert!(
+            addr % align_of::<T>() == 0,
+            "MMIO access at offset {:X} is misaligned",
+            offset
+        );
+        addr
+    }
+
+    /// Reads the register of type `T` at `offset` bytes into the mapping.
+    #[inline]
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        let addr = self.addr_of::<T>(offset);
+        #[cfg(feature = "kasan")]
+        kasan::check(addr, size_of::<T>(), false);
+        unsafe { read_volatile(addr as *const T) }
+    }
+
+    /// Writes the register of type `T` at `offset` bytes into the mapping.
+    #[inline]
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        let addr = self.addr_of::<T>(offset);
+        #[cfg(feature = "kasan")]
+        kasan::check(addr, size_of::<T>(), true);
+        unsafe { write_volatile(addr as *mut T, value) }
+    }
+
+    /// Returns a volatile address for the register of type `T` at `offset` bytes into the mapping.
+    ///
+    /// # Safety
+    ///
+    /// The returned address must not be used after the mapping is dropped.
+    #[inline]
+    pub unsafe fn reg<T: Copy>(&self, offset: usize) -> VolAddress<T, Safe, Safe> {
+        let addr = self.addr_of::<T>(offset);
+        // Accesses through the address cannot be seen, so check it as it is handed out
+        #[cfg(feature = "kasan")]
+        kasan::check(addr, size_of::<T>(), true);
+        unsafe { VolAddress::new(addr) }
+    }
+}
+
+impl Drop for IoMapping {
+    fn drop(&mut self) {
+        match (MAPPER.lock().as_mut(), FRAME_ALLOCATOR.lock().as_mut()) {
+            // Device memory has no references, so only RAM mapped through here is ever released
+            (Some(m), Some(a)) => unsafe {
+                let _ = unmap_range(
+                    m,
+                    a,
+                    self.span_start..self.span_start + self.span_len,
+                    |a, _, phys, len| release_frames(a, phys, len),
+                );
+            },
+            _ => panic!("Memory allocator or frame allocator are not set"),
+        }
+        let _ = vspace::release(VirtAddr::new_truncate(self.span_start));
+    }
+}
+
+/// A typed register (or block of registers laid out as `T`) in device memory.
+#[derive(Debug)]
+pub struct Mmio<T: Copy> {
+    mapping: IoMapping,
+    _reg: PhantomData<T>,
+}
+
+impl<T: Copy> Mmio<T> {
+    /// Maps the `T` at physical address `phys` with the given memory type.
+    pub fn new(phys: u64, cache: CacheType) -> Option<Mmio<T>> {
+        if phys % align_of::<T>() as u64 != 0 {
+            return None;
+        }
+        Some(Mmio {
+            mapping: IoMapping::new(phys, size_of::<T>() as u64, cache)?,
+            _reg: PhantomData,
+        })
+    }
+
+    /// Reads the register.
+    #[inline]
+    pub fn read(&self) -> T {
+        self.mapping.read(0)
+    }
+
+    /// Writes the register.
+    #[inline]
+    pub fn write(&self, value: T) {
+        self.mapping.write(0, value)
+    }
+
+    /// Reads the register, applies `f` to its value and writes the result back.
+    #[inline]
+    pub fn modify(&self, f: impl FnOnce(T) -> T) {
+        self.write(f(self.read()))
+    }
+
+    /// Returns the underlying mapping.
+    #[inline]
+    pub fn mapping(&self) -> &IoMapping {
+        &self.mapping
+    }
+}
@@ -0,0 +1,1290 @@
+// SPDX-License-Identifier: MPL-2.0
+mod buddy;
+/// The dma module allocates and maps memory that devices can access directly.
+pub mod dma;
+/// The frame module keeps reference counts and other metadata for every frame of RAM.
+pub mod frame;
+/// The heap module contains the growable kernel heap.
+pub mod heap;
+/// The image module enforces W^X on the kernel image and enables the processor's memory protection features.
+pub mod image;
+/// The kasan module is a kernel address sanitizer that catches out-of-bounds and use-after-free accesses to the heap.
+#[cfg(feature = "kasan")]
+pub mod kasan;
+mod lock;
+/// The memtest module tests usable memory at boot and records the frames that fail.
+pub mod memtest;
+/// The mmio module maps device memory with a chosen cache type.
+pub mod mmio;
+/// The numa module records which NUMA node memory and processors belong to.
+pub mod numa;
+/// The pkey module tags pages with protection keys, whose access rights can be switched without touching page tables.
+pub mod pkey;
+/// The shared module contains memory regions that can be mapped into several address spaces at once.
+pub mod shared;
+/// The space module contains per-process address spaces.
+pub mod space;
+/// The stack module allocates kernel stacks with guard pages.
+pub mod stack;
+/// The vm module contains virtual memory regions that are backed on demand.
+pub mod vm;
+/// The vspace module allocates ranges of kernel virtual address space.
+pub mod vspace;
+use buddy::BuddyAllocator;
+use core::fmt;
+use core::mem::size_of;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use heapless::Vec;
+use lock::OwnedMutex;
+use log::*;
+use numa::NodeId;
+use rand_core::SeedableRng;
+use rand_hc::Hc128Rng;
+use raw_cpuid::CpuId;
+use spin::{mutex::ticket::TicketMutex, Lazy, Once};
+use stivale_boot::v2::*;
+use x86_64::{
+    instructions::random::RdRand,
+    registers::control::*,
+    structures::paging::{
+        mapper::{MapToError, MappedFrame, MapperAllSizes, TranslateResult, UnmapError},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+        PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// The page table mapper (PTM) used by the kernel global memory allocator. With 5-level paging it only reaches the
+/// higher half; see [`with_kernel_mapper`].
+static MAPPER: OwnedMutex<Option<OffsetPageTable<'static>>> = OwnedMutex::new(None);
+/// The global frame allocator (GFA); works in conjunction with the PTM.
+static FRAME_ALLOCATOR: OwnedMutex<Option<GlobalFrameAllocator>> = OwnedMutex::new(None);
+static MMAP: Once<Vec<MemoryRegion, 1024>> = Once::new();
+static ADDRRNG: Lazy<TicketMutex<Hc128Rng>> = Lazy::new(|| {
+    TicketMutex::new({
+        let rand = RdRand::new().unwrap();
+        let mut seed = [0u8; 32768];
+        let seeds = (0..1024)
+            .map(|_| rand.get_u64().unwrap().to_ne_bytes())
+            .collect::<Vec<[u8; 8], 1024>>();
+        seed.iter_mut()
+            .zip(seeds.iter().flatten())
+            .for_each(|(i, j)| *i = *j);
+        Hc128Rng::from_seed(*blake3::hash(&seed).as_bytes())
+    })
+});
+/// Start of the kernel (higher) half of the address space.
+const HIGHER_HALF: u64 = 0xFFFF_8000_0000_0000;
+static MUSE: AtomicU64 = AtomicU64::new(0);
+static SMUSE: AtomicU64 = AtomicU64::new(0);
+static STOTAL: AtomicU64 = AtomicU64::new(0);
+static GIB_PAGES: Lazy<bool> = Lazy::new(|| {
+    CpuId::new()
+        .get_extended_processor_and_feature_identifiers()
+        .map_or(false, |f| f.has_1gib_pages())
+});
+static PHYS_OFFSET: AtomicU64 = AtomicU64::new(0);
+/// Set once bootloader-reclaimable memory has been handed to the frame allocator.
+static RECLAIMED_BOOT: AtomicBool = AtomicBool::new(false);
+/// Set once ACPI-reclaimable memory has been handed to the frame allocator.
+static RECLAIMED_ACPI: AtomicBool = AtomicBool::new(false);
+/// Set when the processor runs with 5-level paging (CR4.LA57).
+static LA57: AtomicBool = AtomicBool::new(false);
+static RSDP: AtomicU64 = AtomicU64::new(0);
+
+/// Initializes a memory heap for the global memory allocator. Requires a PMO to start with.
+#[cold]
+unsafe fn init_mapper(physical_memory_offset: u64) -> OffsetPageTable<'static> {
+    // Get active L4 table
+    trace!(
+        "Retrieving active L4 table with memoffset {:X}",
+        physical_memory_offset
+    );
+    unsafe {
+        let (level_4_table, _) = get_active_l4_table(physical_memory_offset);
+        // initialize the mapper
+        OffsetPageTable::new(
+            level_4_table,
+            VirtAddr::new_truncate(physical_memory_offset),
+        )
+    }
+}
+
+/// A frame allocator able to hand out and take back frames of every page size.
+pub trait FrameAllocatorAllSizes:
+    FrameAllocator<Size4KiB>
+    + FrameAllocator<Size2MiB>
+    + FrameAllocator<Size1GiB>
+    + FrameDeallocator<Size4KiB>
+    + FrameDeallocator<Size2MiB>
+    + FrameDeallocator<Size1GiB>
+{
+}
+
+impl<T> FrameAllocatorAllSizes for T where
+    T: FrameAllocator<Size4KiB>
+        + FrameAllocator<Size2MiB>
+        + FrameAllocator<Size1GiB>
+        + FrameDeallocator<Size4KiB>
+        + FrameDeallocator<Size2MiB>
+        + FrameDeallocator<Size1GiB>
+{
+}
+
+/// Errors reported by the functions that map and unmap memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MemoryError {
+    /// The page mapper or the frame allocator has not been set up yet.
+    NotInitialized,
+    /// The range ends before it starts, or lies where it may not be mapped.
+    InvalidRange {
+        /// The start of the range.
+        start: u64,
+        /// The end of the range.
+        end: u64,
+    },
+    /// The physical range is not usable RAM, and mapping it was not forced.
+    NotUsable {
+        /// The start of the range.
+        start: u64,
+        /// The end of the range.
+        end: u64,
+    },
+    /// No frame was left for a page or for one of the page tables it needs.
+    OutOfFrames,
+    /// The page at this address is already mapped, on its own or as part of a huge page.
+    AlreadyMapped(u64),
+    /// The page at this address is not mapped.
+    NotMapped(u64),
+    /// No free range of virtual addresses is large enough.
+    OutOfAddressSpace,
+    /// The shared region has been revoked.
+    Revoked,
+    /// The protection key is of the wrong kind for the pages it was to tag.
+    WrongKeyKind,
+}
+
+impl fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MemoryError::NotInitialized => write!(f, "memory manager not initialized"),
+            MemoryError::InvalidRange { start, end } => {
+                write!(f, "invalid range {:X}h-{:X}h", start, end)
+            }
+            MemoryError::NotUsable { start, end } => {
+                write!(f, "range {:X}h-{:X}h is not usable memory", start, end)
+            }
+            MemoryError::OutOfFrames => write!(f, "out of physical frames"),
+            MemoryError::AlreadyMapped(addr) => write!(f, "page at {:X}h is already mapped", addr),
+            MemoryError::NotMapped(addr) => write!(f, "page at {:X}h is not mapped", addr),
+            MemoryError::OutOfAddressSpace => write!(f, "out of virtual address space"),
+            MemoryError::Revoked => write!(f, "shared region has been revoked"),
+            MemoryError::WrongKeyKind => write!(f, "protection key of the wrong kind"),
+        }
+    }
+}
+
+/// A range of pages that was mapped successfully. The pages stay mapped until they are freed with [`free_range`]
+/// (or the owning address space unmaps them); dropping this does nothing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Mapping {
+    start: u64,
+    end: u64,
+    phys: Option<u64>,
+}
+
+impl Mapping {
+    /// Returns the first virtual address of the mapping. Always page-aligned.
+    #[inline]
+    pub fn start(&self) -> VirtAddr {
+        VirtAddr::new_truncate(self.start)
+    }
+
+    /// Returns the page-aligned range of virtual addresses the mapping covers.
+    #[inline]
+    pub fn range(&self) -> Range<u64> {
+        self.start..self.end
+    }
+
+    /// Returns the size of the mapping in bytes.
+    #[inline]
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    /// Returns the physical address the mapping starts at, if it was mapped to a given physical range rather than
+    /// to freshly allocated frames.
+    #[inline]
+    pub fn phys_addr(&self) -> Option<PhysAddr> {
+        self.phys.map(PhysAddr::new_truncate)
+    }
+}
+
+/// Result of mapping a single page.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MapOutcome {
+    /// The page was mapped.
+    Mapped,
+    /// The page (or a page table where a huge page would go) is already mapped.
+    AlreadyMapped,
+    /// The page lies within an existing huge page.
+    Covered,
+    /// No frame was available for the page or for an intermediate page table.
+    NoFrame,
+}
+
+/// Hands out frames for the page tables a mapping needs, marking them as page tables in the frame metadata.
+struct TableAllocator<'a, A>(&'a mut A);
+
+unsafe impl<A: FrameAllocator<Size4KiB>> FrameAllocator<Size4KiB> for TableAllocator<'_, A> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.0.allocate_frame()?;
+        frame::mark(
+            frame.start_address().as_u64(),
+            Size4KiB::SIZE,
+            frame::FrameFlags::PAGE_TABLE,
+        );
+        Some(frame)
+    }
+}
+
+/// Maps a single page of size `S`, allocating a frame for it if `frame` is `None`. Frames allocated here are returned
+/// to the allocator if the mapping fails.
+unsafe fn map_page<S, M, A>(
+    mapper: &mut M,
+    frame_allocator: &mut A,
+    page: Page<S>,
+    frame: Option<PhysFrame<S>>,
+    flags: PageTableFlags,
+) -> MapOutcome
+where
+    S: PageSize,
+    M: Mapper<S>,
+    A: FrameAllocator<S> + FrameDeallocator<S> + FrameAllocator<Size4KiB>,
+{
+    let (frame, owned) = match frame {
+        Some(f) => (f, false),
+        None => match FrameAllocator::<S>::allocate_frame(frame_allocator) {
+            Some(f) => (f, true),
+            None => return MapOutcome::NoFrame,
+        },
+    };
+    match unsafe { mapper.map_to(page, frame, flags, &mut TableAllocator(frame_allocator)) } {
+        Ok(f) => {
+            f.flush();
+            MUSE.fetch_add(S::SIZE / Size4KiB::SIZE, Ordering::Relaxed);
+            MapOutcome::Mapped
+        }
+        Err(e) => {
+            if owned {
+                unsafe {
+                    FrameDeallocator::<S>::deallocate_frame(frame_allocator, frame);
+                }
+            }
+            match e {
+                MapToError::PageAlreadyMapped(_) => MapOutcome::AlreadyMapped,
+                MapToError::ParentEntryHugePage => MapOutcome::Covered,
+                MapToError::FrameAllocationFailed => MapOutcome::NoFrame,
+            }
+        }
+    }
+}
+
+/// Maps `len` bytes starting at `virt`, using the largest page size that the alignment of the range (and of
+/// `phys`, if given) allows. If `phys` is `None`, fresh frames are allocated, falling back to smaller pages when no
+/// huge frame is free. If any page cannot be mapped, the pages mapped so far are unmapped again (and the frames
+/// allocated for them released), so that either the whole range is mapped or none of it is.
+unsafe fn map_range(
+    mapper: &mut (impl MapperAllSizes + Translate),
+    frame_allocator: &mut impl FrameAllocatorAllSizes,
+    virt: u64,
+    phys: Option<u64>,
+    len: u64,
+    flags: PageTableFlags,
+) -> Result<(), MemoryError> {
+    let mut off = 0;
+    // Kernel mappings are shared by every address space, so keep them in the TLB across CR3 switches
+    let flags = if virt >= HIGHER_HALF {
+        flags | PageTableFlags::GLOBAL
+    } else {
+        flags
+    };
+    // The mapper refuses 4 KiB entries with the PAT bit, since it doubles as the huge page bit; it is set afterwards
+    let pat = flags.contains(mmio::PAT_4K);
+    while off < len {
+        let (v, p) = (virt + off, phys.map(|p| p + off));
+        let fits = |size: u64| {
+            !pat && v % size == 0 && p.map_or(true, |p| p % size == 0) && len - off >= size
+        };
+        if *GIB_PAGES && fits(Size1GiB::SIZE) {
+            let page = Page::<Size1GiB>::containing_address(VirtAddr::new_truncate(v));
+            let frame = p.map(|p| PhysFrame::containing_address(PhysAddr::new_truncate(p)));
+            if unsafe { map_page(mapper, frame_allocator, page, frame, flags) }
+                == MapOutcome::Mapped
+            {
+                off += Size1GiB::SIZE;
+                continue;
+            }
+        }
+        if fits(Size2MiB::SIZE) {
+            let page = Page::<Size2MiB>::containing_address(VirtAddr::new_truncate(v));
+            let frame = p.map(|p| PhysFrame::containing_address(PhysAddr::new_truncate(p)));
+            if unsafe { map_page(mapper, frame_allocator, page, frame, flags) }
+                == MapOutcome::Mapped
+            {
+                off += Size2MiB::SIZE;
+                continue;
+            }
+        }
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new_truncate(v));
+        let frame = p.map(|p| PhysFrame::containing_address(PhysAddr::new_truncate(p)));
+        let error = match unsafe {
+            map_page(mapper, frame_allocator, page, frame, flags & !mmio::PAT_4K)
+        } {
+            MapOutcome::Mapped => {
+                if pat {
+                    if let Ok(f) = unsafe { Mapper::<Size4KiB>::update_flags(mapper, page, flags) }
+                    {
+                        f.flush();
+                    }
+                }
+                off += Size4KiB::SIZE;
+                continue;
+            }
+            MapOutcome::AlreadyMapped | MapOutcome::Covered => MemoryError::AlreadyMapped(v),
+            MapOutcome::NoFrame => MemoryError::OutOfFrames,
+        };
+        if off > 0 {
+            unsafe {
+                let _ = unmap_range(mapper, frame_allocator, virt..virt + off, |a, _, p, l| {
+                    if phys.is_none() {
+                        release_frames(a, p, l);
+                    }
+                });
+            }
+        }
+        return Err(error);
+    }
+    Ok(())
+}
+
+/// Unmaps the page of the given size that starts at `addr` and is mapped with `flags`.
+fn unmap_page(
+    mapper: &mut impl MapperAllSizes,
+    addr: u64,
+    frame: MappedFrame,
+    flags: PageTableFlags,
+) -> Result<(), UnmapError> {
+    let virt = VirtAddr::new_truncate(addr);
+    match frame {
+        MappedFrame::Size4KiB(_) => {
+            let page = Page::containing_address(virt);
+            // The mapper takes the PAT bit of a 4 KiB entry for a huge page and refuses to unmap it
+            if flags.contains(mmio::PAT_4K) {
+                let _ = unsafe {
+                    Mapper::<Size4KiB>::update_flags(mapper, page, flags & !mmio::PAT_4K)
+                };
+            }
+            Mapper::<Size4KiB>::unmap(mapper, page).map(|(_, f)| f.flush())
+        }
+        MappedFrame::Size2MiB(_) => {
+            Mapper::<Size2MiB>::unmap(mapper, Page::containing_address(virt))
+                .map(|(_, f)| f.flush())
+        }
+        MappedFrame::Size1GiB(_) => {
+            Mapper::<Size1GiB>::unmap(mapper, Page::containing_address(virt))
+                .map(|(_, f)| f.flush())
+        }
+    }
+}
+
+/// Unmaps every page in `span`, splitting huge pages that straddle either end of it. `on_unmap` is called with the
+/// frame allocator and the virtual address, physical address and length of every piece that was unmapped. Pages that
+/// cannot be unmapped are skipped, and the first of them is reported once the rest of the span is done.
+unsafe fn unmap_range<A: FrameAllocatorAllSizes>(
+    mapper: &mut (impl MapperAllSizes + Translate),
+    frame_allocator: &mut A,
+    span: Range<u64>,
+    mut on_unmap: impl FnMut(&mut A, u64, u64, u64),
+) -> Result<(), MemoryError> {
+    let mut ret = Ok(());
+    let mut addr = span.start;
+    while addr < span.end {
+        addr = match mapper.translate(VirtAddr::new_truncate(addr)) {
+            TranslateResult::Mapped {
+                frame,
+                offset,
+                flags,
+            } => {
+                let base = addr - offset;
+                let size = frame.size();
+                let phys = frame.start_address().as_u64();
+                if let Err(e) = unmap_page(mapper, base, frame, flags) {
+                    warn!("Cannot unmap page at {:X}h: {:#?}", base, e);
+                    ret = ret.and(Err(MemoryError::NotMapped(base)));
+                } else {
+                    MUSE.fetch_sub(size / Size4KiB::SIZE, Ordering::Relaxed);
+                    // Split a huge page that straddles the range by remapping the parts outside of it
+                    let flags = flags & !PageTableFlags::HUGE_PAGE;
+                    let mut remap = |virt, phys, len| {
+                        if let Err(e) = unsafe {
+                            map_range(mapper, frame_allocator, virt, Some(phys), len, flags)
+                        } {
+                            warn!("Cannot remap part of huge page at {:X}h: {}", base, e);
+                        }
+                    };
+                    if base < span.start {
+                        remap(base, phys, span.start - base);
+                    }
+                    if base + size > span.end {
+                        remap(span.end, phys + (span.end - base), base + size - span.end);
+                    }
+                    let start = base.max(span.start);
+                    let end = (base + size).min(span.end);
+                    on_unmap(frame_allocator, start, phys + (start - base), end - start);
+                }
+                base + size
+            }
+            e => {
+                warn!("Cannot unmap page at {:X}h: {:#?}", addr, e);
+                ret = ret.and(Err(MemoryError::NotMapped(addr)));
+                addr + Size4KiB::SIZE
+            }
+        };
+    }
+    ret
+}
+
+/// Flushes every TLB entry on this processor, global ones included.
+pub(crate) fn flush_tlb_all() {
+    let cr4 = Cr4::read();
+    if cr4.contains(Cr4Flags::PAGE_GLOBAL) {
+        unsafe {
+            Cr4::write(cr4 - Cr4Flags::PAGE_GLOBAL);
+            Cr4::write(cr4);
+        }
+    } else {
+        x86_64::instructions::tlb::flush_all();
+    }
+}
+
+/// Drops a reference to every frame in `[phys, phys + len)`, returning the frames that lose their last one to the
+/// frame allocator one 4 KiB frame at a time; the buddy allocator merges them back into larger blocks. Frames the
+/// allocator never handed out have no references and are left alone.
+unsafe fn release_frames(
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+    phys: u64,
+    len: u64,
+) {
+    (phys..phys + len)
+        .step_by(Size4KiB::SIZE as usize)
+        .filter(|&addr| frame::put(addr))
+        .for_each(|addr| unsafe {
+            frame_allocator.deallocate_frame(PhysFrame::<Size4KiB>::containing_address(
+                PhysAddr::new(addr),
+            ));
+        });
+}
+
+/// Returns `[start, end]` widened to the 4 KiB pages that contain it, as a half-open range.
+#[inline]
+fn page_span(start: u64, end: u64) -> Range<u64> {
+    (start & !(Size4KiB::SIZE - 1))..((end & !(Size4KiB::SIZE - 1)) + Size4KiB::SIZE)
+}
+
+/// Allocates a paged heap. Uses 2 MiB or 1 GiB pages wherever the heap's alignment allows.
+#[cold]
+pub fn allocate_paged_heap(
+    start: u64,
+    size: u64,
+    mapper: &mut (impl MapperAllSizes + Translate),
+    frame_allocator: &mut impl FrameAllocatorAllSizes,
+    perms: Option<PageTableFlags>,
+) -> Result<Mapping, MemoryError> {
+    if size == 0 {
+        return Err(MemoryError::InvalidRange { start, end: start });
+    }
+    debug!(
+        "Allocating heap in paged memory with start of {:X}, size {:X}",
+        start, size
+    );
+    let span = page_span(start, start + size - 1);
+    debug!("Page range constructed: {:X?}", span);
+    let flags = if let Some(flags) = perms {
+        PageTableFlags::PRESENT | flags
+    } else {
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+    };
+    debug!("Requesting mapping of pages with flags {:X}", flags);
+    unsafe {
+        map_range(
+            mapper,
+            frame_allocator,
+            span.start,
+            None,
+            span.end - span.start,
+            flags,
+        )?;
+    }
+    SMUSE.fetch_add(span.end - span.start, Ordering::Relaxed);
+    Ok(Mapping {
+        start: span.start,
+        end: span.end,
+        phys: None,
+    })
+}
+
+/// Returns the kernel's level 4 table. With 5-level paging, this is the table behind the last level 5 entry, which
+/// covers the higher half; lower-half kernel mappings go through [`with_kernel_mapper`].
+#[cold]
+unsafe fn get_active_l4_table(physical_memory_offset: u64) -> (&'static mut PageTable, Cr3Flags) {
+    let (table_frame, flags) = Cr3::read();
+    // The bootloader's direct map is above the 48-bit canonical range with 5-level paging, so `VirtAddr` cannot
+    // hold it
+    let table = |phys: PhysAddr| (phys.as_u64() + physical_memory_offset) as *mut PageTable;
+    let mut page_table_ptr = table(table_frame.start_address());
+    if LA57.load(Ordering::Relaxed) {
+        page_table_ptr = table(unsafe { &*page_table_ptr }[511].addr());
+    }
+    unsafe { (&mut *page_table_ptr, flags) }
+}
+
+/// Makes the bootloader's direct map, which starts at `physical_memory_offset` under level 5 entry 256 with 5-level
+/// paging, visible at [`HIGHER_HALF`] as well, where it is in the 48-bit canonical range. The level 3 tables are
+/// shared, so nothing is copied. Returns the offset the kernel should use from then on.
+#[cold]
+unsafe fn alias_direct_map(physical_memory_offset: u64) -> u64 {
+    let table = |phys: PhysAddr| (phys.as_u64() + physical_memory_offset) as *mut PageTable;
+    let root = unsafe { &*table(Cr3::read().0.start_address()) };
+    let direct = unsafe { &*table(root[((physical_memory_offset >> 48) & 0x1FF) as usize].addr()) };
+    let kernel = unsafe { &mut *table(root[511].addr()) };
+    // The direct map may use at most the part of the kernel half below the kernel VA window
+    let slots = ((vspace::KERNEL_VA_START - HIGHER_HALF) >> 39) as usize;
+    if direct.iter().skip(slots).any(|e| !e.is_unused()) {
+        warn!(
+            "Physical memory beyond {:X}h is not in the direct map",
+            (slots as u64) << 39
+        );
+    }
+    kernel
+        .iter_mut()
+        .skip(256)
+        .zip(direct.iter().take(slots))
+        .filter(|(_, d)| !d.is_unused())
+        .for_each(|(k, d)| {
+            assert!(
+                k.is_unused(),
+                "Kernel half is in use where the direct map goes"
+            );
+            *k = d.clone();
+        });
+    x86_64::instructions::tlb::flush_all();
+    HIGHER_HALF
+}
+
+/// Returns the number of paging levels the processor is using: 5 with CR4.LA57 set, otherwise 4.
+#[inline]
+pub fn paging_levels() -> u8 {
+    if LA57.load(Ordering::Relaxed) {
+        5
+    } else {
+        4
+    }
+}
+
+/// Returns the number of significant bits in a virtual address: 57 with 5-level paging, otherwise 48.
+#[inline]
+pub fn virt_addr_bits() -> u32 {
+    12 + 9 * u32::from(paging_levels())
+}
+
+/// Sign-extends `addr` from its highest significant bit, making it canonical under the current paging mode.
+#[inline]
+pub fn canonicalize(addr: u64) -> u64 {
+    let shift = 64 - virt_addr_bits();
+    (((addr << shift) as i64) >> shift) as u64
+}
+
+/// Returns whether `addr` is canonical under the current paging mode.
+#[inline]
+pub fn is_canonical(addr: u64) -> bool {
+    canonicalize(addr) == addr
+}
+
+/// The global frame allocator. Hands out physical frames from a buddy allocator built over the usable regions of
+/// the memory map.
+#[derive(Debug)]
+pub(crate) struct GlobalFrameAllocator(BuddyAllocator);
+
+impl GlobalFrameAllocator {
+    /// Initializes the global frame allocator. Frames within `reserved` are never handed out.
+    #[cold]
+    pub(crate) fn init(physical_memory_offset: u64, reserved: &[Range<u64>]) -> Self {
+        let of_kind = |kind: MemoryKind| {
+            MMAP.wait()
+                .iter()
+                .filter(move |r| r.kind == kind)
+                .map(|r| r.start..r.end)
+        };
+        let reclaimable =
+            of_kind(MemoryKind::BootloaderReclaimable).chain(of_kind(MemoryKind::AcpiReclaimable));
+        let buddy = unsafe {
+            BuddyAllocator::new(
+                physical_memory_offset,
+                of_kind(MemoryKind::Usable),
+                reclaimable,
+                reserved,
+                size_of::<frame::FrameInfo>() as u64,
+            )
+        }
+        .expect(
+            "No usable memory region can hold the frame allocator's order map and frame metadata",
+        );
+        frame::init(&buddy, physical_memory_offset);
+        info!("Frame allocator managing {} frames", buddy.total_frames());
+        GlobalFrameAllocator(buddy)
+    }
+
+    /// Hands out a block of `1 << order` frames, giving each frame a single reference.
+    fn allocate(&mut self, order: usize) -> Option<PhysFrame> {
+        self.0.allocate(order).map(claim(order))
+    }
+
+    /// Returns a block of `1 << order` frames to the buddy allocator, whatever references they still have.
+    unsafe fn free(&mut self, frame: PhysFrame, order: usize) {
+        frame::release(frame.start_address().as_u64(), 1 << order);
+        unsafe {
+            self.0.free(frame, order);
+        }
+    }
+}
+
+/// Returns a function that records a freshly allocated block of `1 << order` frames in the frame metadata.
+#[inline]
+fn claim(order: usize) -> impl Fn(PhysFrame) -> PhysFrame {
+    move |f| {
+        frame::claim(f.start_address().as_u64(), 1 << order);
+        f
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    #[must_use]
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.allocate(0)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        unsafe {
+            self.free(frame, 0);
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        self.allocate(buddy::order_for_size(Size2MiB::SIZE))
+            .map(|f| PhysFrame::containing_address(f.start_address()))
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        unsafe {
+            self.free(
+                PhysFrame::containing_address(frame.start_address()),
+                buddy::order_for_size(Size2MiB::SIZE),
+            );
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size1GiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size1GiB>> {
+        self.allocate(buddy::order_for_size(Size1GiB::SIZE))
+            .map(|f| PhysFrame::containing_address(f.start_address()))
+    }
+}
+
+impl FrameDeallocator<Size1GiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size1GiB>) {
+        unsafe {
+            self.free(
+                PhysFrame::containing_address(frame.start_address()),
+                buddy::order_for_size(Size1GiB::SIZE),
+            );
+        }
+    }
+}
+
+/// Initializes the memory subsystem.
+#[cold]
+pub fn init(physical_memory_offset: u64) {
+    LA57.store(Cr4::read().contains(Cr4Flags::L5_PAGING), Ordering::Relaxed);
+    let physical_memory_offset = if LA57.load(Ordering::Relaxed) {
+        info!("5-level paging is enabled");
+        unsafe { alias_direct_map(physical_memory_offset) }
+    } else {
+        physical_memory_offset
+    };
+    PHYS_OFFSET.store(physical_memory_offset, Ordering::Relaxed);
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    *allocator = Some(GlobalFrameAllocator::init(physical_memory_offset, &[]));
+    if let Some(a) = allocator.as_mut() {
+        unsafe {
+            adopt_page_tables(a);
+        }
+    }
+    let mut mapper = MAPPER.lock();
+    *mapper = Some(unsafe { init_mapper(physical_memory_offset) });
+    match (mapper.as_mut(), allocator.as_mut()) {
+        (Some(m), Some(a)) => {
+            image::init(m, a);
+            space::init(m, a);
+        }
+        _ => panic!("Memory allocator or page frame allocator failed creation!"),
+    }
+    mmio::init_pat();
+    pkey::init();
+}
+
+/// Moves an application processor onto the kernel's page table and gives it the paging and protection features the
+/// bootstrap processor got from [`init`]. Must be the first thing an application processor does, while it still runs
+/// on the bootloader's page table.
+#[cold]
+pub fn init_ap() {
+    image::enable_protections();
+    unsafe {
+        Cr3::write(space::kernel_root(), Cr3Flags::empty());
+    }
+    space::init_ap();
+    // The bootloader's global entries survive the switch
+    flush_tlb_all();
+    mmio::init_pat();
+    pkey::init();
+}
+
+/// Copies the page tables the bootloader built into frames from the frame allocator and switches to the copy, so
+/// that the bootloader's memory (which holds its page tables) can be reclaimed later on.
+#[cold]
+unsafe fn adopt_page_tables(frame_allocator: &mut GlobalFrameAllocator) {
+    let (root, flags) = Cr3::read();
+    let copy = unsafe { copy_table(frame_allocator, root.start_address(), paging_levels()) };
+    unsafe {
+        Cr3::write(PhysFrame::containing_address(copy), flags);
+    }
+}
+
+/// Copies the page table of the given level at `phys`, and every lower-level table it references. Returns the
+/// address of the copy.
+unsafe fn copy_table(
+    frame_allocator: &mut GlobalFrameAllocator,
+    phys: PhysAddr,
+    level: u8,
+) -> PhysAddr {
+    let frame: PhysFrame = frame_allocator
+        .allocate_frame()
+        .expect("Cannot copy the page tables: no more frames");
+    frame::mark(
+        frame.start_address().as_u64(),
+        Size4KiB::SIZE,
+        frame::FrameFlags::PAGE_TABLE,
+    );
+    let old: &PageTable = unsafe { &*phys_to_virt(phys).as_ptr() };
+    let new: &mut PageTable = unsafe { &mut *phys_to_virt(frame.start_address()).as_mut_ptr() };
+    for (n, o) in new.iter_mut().zip(old.iter()) {
+        *n = o.clone();
+        if level > 1
+            && o.flags().contains(PageTableFlags::PRESENT)
+            && !o.flags().contains(PageTableFlags::HUGE_PAGE)
+        {
+            let child = unsafe { copy_table(frame_allocator, o.addr(), level - 1) };
+            n.set_addr(child, o.flags());
+        }
+    }
+    frame.start_address()
+}
+
+/// Hands the memory holding the bootloader's data to the frame allocator, along with the memory holding the ACPI
+/// tables if `acpi` is set. Returns the number of bytes reclaimed.
+///
+/// Nothing the bootloader passed to the kernel may be touched afterwards, and with `acpi` set, neither may the ACPI
+/// tables; the ACPI module reads them again whenever it is asked for a table.
+#[cold]
+pub fn reclaim_boot_memory(acpi: bool) -> u64 {
+    let mut kinds: Vec<MemoryKind, 2> = Vec::new();
+    if !RECLAIMED_BOOT.swap(true, Ordering::AcqRel) {
+        let _ = kinds.push(MemoryKind::BootloaderReclaimable);
+    }
+    if acpi && !RECLAIMED_ACPI.swap(true, Ordering::AcqRel) {
+        let _ = kinds.push(MemoryKind::AcpiReclaimable);
+    }
+    let frames: usize = match FRAME_ALLOCATOR.lock().as_mut() {
+        Some(a) => MMAP
+            .wait()
+            .iter()
+            .filter(|r| kinds.contains(&r.kind))
+            .map(|r| unsafe { a.0.add_region(r.start..r.end, &[]) })
+            .sum(),
+        None => panic!("Frame allocator not initialized!"),
+    };
+    let bytes = frames as u64 * Size4KiB::SIZE;
+    info!("Reclaimed {:X} bytes of {:?} memory", bytes, kinds);
+    bytes
+}
+
+/// Allocates a paged (virtual) contiguous address range within [start, end]. `end` must be >= `start` and vice-versa.
+/// If `perms` is not `None`, allows specification of custom privileges for the range. The `P` (present) bit is always set.
+/// Nothing is left mapped if this fails.
+pub fn allocate_page_range(
+    start: u64,
+    end: u64,
+    perms: Option<PageTableFlags>,
+) -> Result<Mapping, MemoryError> {
+    if end < start {
+        return Err(MemoryError::InvalidRange { start, end });
+    }
+    let flags = if let Some(flags) = perms {
+        PageTableFlags::PRESENT | flags
+    } else {
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+    };
+    let span = page_span(start, end);
+    with_kernel_mapper(span.start, |m, a| unsafe {
+        map_range(m, a, span.start, None, span.end - span.start, flags)
+    })?;
+    SMUSE.fetch_add(span.end - span.start, Ordering::Relaxed);
+    Ok(Mapping {
+        start: span.start,
+        end: span.end,
+        phys: None,
+    })
+}
+
+/// Allocates a physical memory address range within [start, end]. `end` must be > `start`.
+/// If `force` is specified, the allocation will occur even if the range is not marked as usable (free).
+/// If `perms` is not `None`, custom permissions can be specified for this memory range. The `P` (present) bit is always set.
+/// Every allocated frame in the range gains a reference. Nothing is left mapped if this fails.
+pub fn allocate_phys_range(
+    start: u64,
+    end: u64,
+    force: bool,
+    perms: Option<PageTableFlags>,
+) -> Result<Mapping, MemoryError> {
+    if end < start {
+        return Err(MemoryError::InvalidRange { start, end });
+    }
+    let usable = memory_map().any(|r| {
+        r.kind == MemoryKind::Usable
+            && (r.start..r.end).contains(&start)
+            && (r.start..r.end).contains(&end)
+    });
+    if !usable && !force {
+        return Err(MemoryError::NotUsable { start, end });
+    }
+    let flags = if let Some(flags) = perms {
+        PageTableFlags::PRESENT | flags
+    } else {
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | mmio::CacheType::Uncached.flags()
+    };
+    let span = page_span(start, end);
+    with_kernel_mapper(span.start, |m, a| unsafe {
+        map_range(
+            m,
+            a,
+            span.start,
+            Some(span.start),
+            span.end - span.start,
+            flags,
+        )
+    })?;
+    frame::get_range(span.start, span.end - span.start);
+    SMUSE.fetch_add(span.end - span.start, Ordering::Relaxed);
+    Ok(Mapping {
+        start: span.start,
+        end: span.end,
+        phys: Some(span.start),
+    })
+}
+
+/// Frees a contiguous range of memory (either virtual or physical). Pages in the range that are not mapped are
+/// skipped, and the first of them is reported. `end` must be > `start`. Each unmapped frame loses a reference, and
+/// goes back to the frame allocator if that was its last one.
+pub fn free_range(start: u64, end: u64) -> Result<(), MemoryError> {
+    if end < start {
+        return Err(MemoryError::InvalidRange { start, end });
+    }
+    let mut unmapped = 0;
+    let ret = with_kernel_mapper(start, |m, a| unsafe {
+        unmap_range(m, a, page_span(start, end), |a, _, phys, len| {
+            unmapped += len;
+            release_frames(a, phys, len)
+        })
+    });
+    unaccount(unmapped);
+    ret
+}
+
+/// Runs `f` with the frame allocator and a mapper that reaches `virt` in the kernel's own page table.
+///
+/// [`MAPPER`] walks the kernel's level 4 table, which with 5-level paging is the one behind the last level 5 entry
+/// and only covers the higher half. Lower-half addresses, such as identity mappings of device memory, get a mapper
+/// over the level 4 table behind the first level 5 entry instead, so that they are mapped where they were asked to be.
+fn with_kernel_mapper<R>(
+    virt: u64,
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut GlobalFrameAllocator) -> Result<R, MemoryError>,
+) -> Result<R, MemoryError> {
+    let mut mapper = MAPPER.lock();
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let (m, a) = match (mapper.as_mut(), allocator.as_mut()) {
+        (Some(m), Some(a)) => (m, a),
+        _ => return Err(MemoryError::NotInitialized),
+    };
+    if virt >= HIGHER_HALF || !LA57.load(Ordering::Relaxed) {
+        return f(m, a);
+    }
+    // The first level 5 entry covers the lower 256 TiB; nothing the kernel identity-maps lies above it
+    if virt >> 48 != 0 {
+        return Err(MemoryError::InvalidRange {
+            start: virt,
+            end: virt,
+        });
+    }
+    let mut lower = unsafe { lower_half_mapper(a) }.ok_or(MemoryError::OutOfFrames)?;
+    f(&mut lower, a)
+}
+
+/// Returns a mapper over the level 4 table behind the first level 5 entry of the kernel's page table, which holds the
+/// kernel's lower-half mappings with 5-level paging. The table is made if there is none yet.
+unsafe fn lower_half_mapper(
+    frame_allocator: &mut GlobalFrameAllocator,
+) -> Option<OffsetPageTable<'static>> {
+    let root: &mut PageTable =
+        unsafe { &mut *phys_to_virt(space::kernel_root().start_address()).as_mut_ptr() };
+    if root[0].is_unused() {
+        let frame: PhysFrame = frame_allocator.allocate_frame()?;
+        frame::mark(
+            frame.start_address().as_u64(),
+            Size4KiB::SIZE,
+            frame::FrameFlags::PAGE_TABLE,
+        );
+        unsafe {
+            phys_to_virt(frame.start_address())
+                .as_mut_ptr::<PageTable>()
+                .write(PageTable::new());
+        }
+        root[0].set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+    }
+    Some(unsafe {
+        OffsetPageTable::new(
+            &mut *phys_to_virt(root[0].addr()).as_mut_ptr(),
+            VirtAddr::new_truncate(PHYS_OFFSET.load(Ordering::Relaxed)),
+        )
+    })
+}
+
+/// Takes `len` bytes off the count of mapped bytes, stopping at zero rather than wrapping.
+fn unaccount(len: u64) {
+    let mut cur = SMUSE.load(Ordering::Relaxed);
+    while let Err(v) = SMUSE.compare_exchange_weak(
+        cur,
+        cur.saturating_sub(len),
+        Ordering::Relaxed,
+        Ordering::Relaxed,
+    ) {
+        cur = v;
+    }
+}
+
+/// Allocates `1 << order` physically contiguous frames, aligned to their combined size. Returns the first frame
+/// of the block, or `None` if no block of that order is free even after shrinking the kernel heap.
+pub fn allocate_frames(order: usize) -> Option<PhysFrame> {
+    let frame = match FRAME_ALLOCATOR.lock().as_mut() {
+        Some(a) => a.allocate(order),
+        None => panic!("Frame allocator not initialized!"),
+    };
+    frame.or_else(|| {
+        if heap::trim() > 0 {
+            FRAME_ALLOCATOR.lock().as_mut()?.allocate(order)
+        } else {
+            None
+        }
+    })
+}
+
+/// Allocates `1 << order` physically contiguous frames, aligned to their combined size, that end at or below the
+/// physical address `limit`. Returns `None` if no such block is free even after shrinking the kernel heap.
+pub fn allocate_frames_below(order: usize, limit: u64) -> Option<PhysFrame> {
+    allocate_frames_near(order, limit, None)
+}
+
+/// Allocates `1 << order` physically contiguous frames, aligned to their combined size, preferably on NUMA node
+/// `node`. Falls back to any other node if `node` has no such block free.
+pub fn allocate_frames_on(order: usize, node: NodeId) -> Option<PhysFrame> {
+    allocate_frames_near(order, u64::MAX, Some(node))
+}
+
+/// Allocates `1 << order` physically contiguous frames, aligned to their combined size, that end at or below the
+/// physical address `limit`, preferably on NUMA node `node`.
+pub fn allocate_frames_below_on(order: usize, limit: u64, node: NodeId) -> Option<PhysFrame> {
+    allocate_frames_near(order, limit, Some(node))
+}
+
+fn allocate_frames_near(order: usize, limit: u64, node: Option<NodeId>) -> Option<PhysFrame> {
+    let attempt = || {
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        let a = match allocator.as_mut() {
+            Some(a) => &mut a.0,
+            None => panic!("Frame allocator not initialized!"),
+        };
+        node.and_then(|node| {
+            numa::ranges_of(node).find_map(|r| a.allocate_in(order, r.start..r.end.min(limit)))
+        })
+        .or_else(|| a.allocate_below(order, limit))
+        .map(claim(order))
+    };
+    attempt().or_else(|| if heap::trim() > 0 { attempt() } else { None })
+}
+
+/// Drops a reference to each frame of a block of `1 << order` frames previously obtained from [`allocate_frames`]
+/// or one of its variants, or referenced with [`frame::get`]. Frames go back to the frame allocator once their last
+/// reference is dropped, so frames that are still mapped elsewhere stay allocated until they are unmapped.
+///
+/// # Safety
+///
+/// The caller must hold the references it drops, and must not use the frames afterwards.
+pub unsafe fn free_frames(frame: PhysFrame, order: usize) {
+    if let Some(a) = FRAME_ALLOCATOR.lock().as_mut() {
+        unsafe {
+            release_frames(a, frame.start_address().as_u64(), buddy::order_size(order));
+        }
+    }
+}
+
+/// Returns whether the given physical address lies in a frame that the frame allocator considers free.
+pub fn is_frame_free(addr: PhysAddr) -> bool {
+    FRAME_ALLOCATOR
+        .lock()
+        .as_ref()
+        .map_or(false, |a| a.0.is_free(addr.as_u64()))
+}
+
+/// Returns the number of free 4 KiB frames.
+pub fn free_frame_count() -> usize {
+    FRAME_ALLOCATOR
+        .lock()
+        .as_ref()
+        .map_or(0, |a| a.0.free_frames())
+}
+
+/// What a region of the physical memory map holds, as reported by the bootloader.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MemoryKind {
+    /// Free RAM.
+    Usable,
+    /// Memory that must not be used.
+    Reserved,
+    /// RAM holding ACPI tables, which can be used once the tables have been read.
+    AcpiReclaimable,
+    /// Memory that the firmware needs preserved across sleep states.
+    AcpiNvs,
+    /// RAM that is known to be faulty.
+    BadMemory,
+    /// RAM holding bootloader data, which can be used once the kernel no longer needs it.
+    BootloaderReclaimable,
+    /// The kernel image and the modules loaded with it.
+    Kernel,
+    /// The framebuffer.
+    Framebuffer,
+}
+
+impl From<StivaleMemoryMapEntryType> for MemoryKind {
+    fn from(kind: StivaleMemoryMapEntryType) -> Self {
+        match kind {
+            StivaleMemoryMapEntryType::Usable => MemoryKind::Usable,
+            StivaleMemoryMapEntryType::Reserved => MemoryKind::Reserved,
+            StivaleMemoryMapEntryType::AcpiReclaimable => MemoryKind::AcpiReclaimable,
+            StivaleMemoryMapEntryType::AcpiNvs => MemoryKind::AcpiNvs,
+            StivaleMemoryMapEntryType::BadMemory => MemoryKind::BadMemory,
+            StivaleMemoryMapEntryType::BootloaderReclaimable => MemoryKind::BootloaderReclaimable,
+            StivaleMemoryMapEntryType::Kernel => MemoryKind::Kernel,
+            StivaleMemoryMapEntryType::Framebuffer => MemoryKind::Framebuffer,
+        }
+    }
+}
+
+/// A region of the physical memory map.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MemoryRegion {
+    /// The first physical address of the region.
+    pub start: u64,
+    /// The physical address one past the end of the region.
+    pub end: u64,
+    /// What the region holds.
+    pub kind: MemoryKind,
+}
+
+impl MemoryRegion {
+    /// Returns the size of the region in bytes.
+    #[inline]
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    /// Returns the NUMA node the start of the region belongs to, if known.
+    #[inline]
+    pub fn node(&self) -> Option<NodeId> {
+        numa::node_of_addr(self.start)
+    }
+}
+
+/// Returns an iterator over the physical memory map, in the order the bootloader reported it. Regions reclaimed by
+/// [`reclaim_boot_memory`] are reported as usable.
+pub fn memory_map() -> impl Iterator<Item = MemoryRegion> {
+    let boot = RECLAIMED_BOOT.load(Ordering::Acquire);
+    let acpi = RECLAIMED_ACPI.load(Ordering::Acquire);
+    MMAP.get()
+        .into_iter()
+        .flatten()
+        .map(move |&r| match r.kind {
+            MemoryKind::BootloaderReclaimable if boot => MemoryRegion {
+                kind: MemoryKind::Usable,
+                ..r
+            },
+            MemoryKind::AcpiReclaimable if acpi => MemoryRegion {
+                kind: MemoryKind::Usable,
+                ..r
+            },
+            _ => r,
+        })
+}
+
+/// A snapshot of memory usage.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct MemoryStats {
+    /// Bytes covered by the memory map.
+    pub total: u64,
+    /// Bytes of free RAM in the memory map.
+    pub usable: u64,
+    /// Bytes in the memory map that are neither usable nor reclaimable.
+    pub reserved: u64,
+    /// Bytes of RAM holding ACPI tables.
+    pub acpi_reclaimable: u64,
+    /// Bytes of RAM holding bootloader data.
+    pub bootloader_reclaimable: u64,
+    /// 4 KiB pages mapped through the memory manager.
+    pub mapped_pages: u64,
+    /// Bytes of whole pages mapped through the memory manager's allocation functions, the heap included.
+    pub mapped_bytes: u64,
+    /// Frames the frame allocator manages.
+    pub total_frames: u64,
+    /// Frames the frame allocator has free.
+    pub free_frames: u64,
+    /// Bytes of the kernel heap that are mapped.
+    pub heap_size: u64,
+    /// Bytes of the kernel heap that are allocated.
+    pub heap_used: u64,
+}
+
+/// Returns a snapshot of memory usage.
+pub fn stats() -> MemoryStats {
+    let bytes = |kind: MemoryKind| {
+        memory_map()
+            .filter(|r| r.kind == kind)
+            .map(|r| r.size())
+            .sum::<u64>()
+    };
+    let total = STOTAL.load(Ordering::Relaxed);
+    let usable = bytes(MemoryKind::Usable);
+    let acpi_reclaimable = bytes(MemoryKind::AcpiReclaimable);
+    let bootloader_reclaimable = bytes(MemoryKind::BootloaderReclaimable);
+    let (total_frames, free_frames) = FRAME_ALLOCATOR.lock().as_ref().map_or((0, 0), |a| {
+        (a.0.total_frames() as u64, a.0.free_frames() as u64)
+    });
+    MemoryStats {
+        total,
+        usable,
+        reserved: total - usable - acpi_reclaimable - bootloader_reclaimable,
+        acpi_reclaimable,
+        bootloader_reclaimable,
+        mapped_pages: MUSE.load(Ordering::Relaxed),
+        mapped_bytes: SMUSE.load(Ordering::Relaxed),
+        total_frames,
+        free_frames,
+        heap_size: heap::size(),
+        heap_used: heap::used(),
+    }
+}
+
+/// Initializes the internal memory map. Frames found faulty by [`memtest::run`] are split out of the usable regions
+/// holding them and reported as bad memory.
+#[cold]
+pub fn init_memory_map(map: &[StivaleMemoryMapEntry], rsdpaddr: u64) {
+    info!(
+        "Loading free memory region list from memory map at {:p}",
+        &map
+    );
+    MMAP.call_once(|| {
+        let mut mmap: Vec<MemoryRegion, 1024> = Vec::new();
+        let mut push = |start: u64, end: u64, kind: MemoryKind| {
+            if start < end {
+                mmap.push(MemoryRegion { start, end, kind })
+                    .expect("Memory map has too many regions");
+            }
+        };
+        map.iter().for_each(|region| {
+            let (start, end) = (region.base, region.end_address());
+            let kind = region.entry_type().into();
+            STOTAL.fetch_add(end - start, Ordering::Relaxed);
+            if kind != MemoryKind::Usable {
+                push(start, end, kind);
+                return;
+            }
+            let mut usable = start;
+            memtest::bad_ranges()
+                .iter()
+                .filter(|bad| bad.start < end && start < bad.end)
+                .for_each(|bad| {
+                    push(usable, bad.start, MemoryKind::Usable);
+                    usable = usable.max(bad.start.max(start));
+                    push(usable, bad.end.min(end), MemoryKind::BadMemory);
+                    usable = usable.max(bad.end.min(end));
+                });
+            push(usable, end, MemoryKind::Usable);
+        });
+        mmap
+    });
+    info!("Discovered {} bytes of RAM", STOTAL.load(Ordering::Relaxed));
+    info!("RSDP at {:X}", rsdpaddr);
+    RSDP.swap(rsdpaddr, Ordering::Relaxed);
+}
+
+/// Reserves a randomly placed, page-aligned range of kernel virtual address space of the given size. Returns 0
+/// if no such range is free. Nothing is mapped in the returned range.
+#[no_mangle]
+pub extern "C" fn get_free_addr(size: u64) -> u64 {
+    vspace::reserve(size, 4096, 0, true).map_or(0, |addr| addr.as_u64())
+}
+
+/// Reserves a randomly placed range of kernel virtual address space of the given size, aligned to the given
+/// alignment. Returns 0 if no such range is free. Nothing is mapped in the returned range.
+#[no_mangle]
+pub extern "C" fn get_aligned_free_addr(size: u64, alignment: u64) -> u64 {
+    vspace::reserve(size, alignment, 0, true).map_or(0, |addr| addr.as_u64())
+}
+
+/// Returns the virtual address through which the given physical address can be accessed in the higher-half
+/// physical memory mapping.
+#[inline]
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new_truncate(addr.as_u64() + PHYS_OFFSET.load(Ordering::Relaxed))
+}
+
+/// Gets the address for the RSDP
+#[inline]
+pub fn get_rsdp() -> u64 {
+    RSDP.load(Ordering::Relaxed)
+}
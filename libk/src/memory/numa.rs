@@ -0,0 +1,188 @@
+// SPDX-License-Identifier: MPL-2.0
+use alloc::vec::Vec;
+use core::ops::Range;
+use log::*;
+use raw_cpuid::CpuId;
+use spin::Once;
+
+/// A NUMA node, identified by its ACPI proximity domain.
+pub type NodeId = u32;
+/// The distance from a node to itself, on the SLIT's scale.
+pub const LOCAL_DISTANCE: u8 = 10;
+/// The distance assumed between two different nodes when the firmware provides no SLIT.
+pub const REMOTE_DISTANCE: u8 = 20;
+
+static TOPOLOGY: Once<Topology> = Once::new();
+
+/// A range of physical memory and the node it belongs to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemoryAffinity {
+    /// The physical address range.
+    pub range: Range<u64>,
+    /// The node the range belongs to.
+    pub node: NodeId,
+    /// Whether the range may be hot-plugged.
+    pub hot_pluggable: bool,
+    /// Whether the range is non-volatile memory.
+    pub non_volatile: bool,
+}
+
+/// A processor and the node it belongs to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CpuAffinity {
+    /// The processor's local (x2)APIC ID.
+    pub apic_id: u32,
+    /// The node the processor belongs to.
+    pub node: NodeId,
+}
+
+/// The NUMA topology of the machine, as described by the ACPI SRAT and SLIT.
+#[derive(Clone, Debug, Default)]
+pub struct Topology {
+    memory: Vec<MemoryAffinity>,
+    cpus: Vec<CpuAffinity>,
+    nodes: Vec<NodeId>,
+    localities: usize,
+    distances: Vec<u8>,
+}
+
+impl Topology {
+    /// Builds a topology from the memory and processor affinities of the SRAT and the row-major distance matrix of
+    /// the SLIT, which has `localities` rows. `distances` is empty if there is no SLIT.
+    pub fn new(
+        memory: Vec<MemoryAffinity>,
+        cpus: Vec<CpuAffinity>,
+        localities: usize,
+        distances: Vec<u8>,
+    ) -> Topology {
+        let mut nodes: Vec<NodeId> = memory
+            .iter()
+            .map(|m| m.node)
+            .chain(cpus.iter().map(|c| c.node))
+            .collect();
+        nodes.sort_unstable();
+        nodes.dedup();
+        let (localities, distances) = if distances.len() == localities * localities {
+            (localities, distances)
+        } else {
+            warn!("SLIT distance matrix is malformed; ignoring it");
+            (0, Vec::new())
+        };
+        Topology {
+            memory,
+            cpus,
+            nodes,
+            localities,
+            distances,
+        }
+    }
+
+    /// Returns every node, in ascending order.
+    #[inline]
+    pub fn nodes(&self) -> &[NodeId] {
+        &self.nodes
+    }
+
+    /// Returns every memory range with a known node.
+    #[inline]
+    pub fn memory(&self) -> &[MemoryAffinity] {
+        &self.memory
+    }
+
+    /// Returns every processor with a known node.
+    #[inline]
+    pub fn cpus(&self) -> &[CpuAffinity] {
+        &self.cpus
+    }
+
+    /// Returns the relative distance between two nodes, [`LOCAL_DISTANCE`] being the distance of a node to itself.
+    pub fn distance(&self, from: NodeId, to: NodeId) -> u8 {
+        let (from, to) = (from as usize, to as usize);
+        if from < self.localities && to < self.localities {
+            self.distances[from * self.localities + to]
+        } else if from == to {
+            LOCAL_DISTANCE
+        } else {
+            REMOTE_DISTANCE
+        }
+    }
+}
+
+/// Records the NUMA topology of the machine. Only the first call has any effect.
+#[cold]
+pub fn init(topology: Topology) {
+    let topology = TOPOLOGY.call_once(|| topology);
+    topology.nodes().iter().for_each(|&node| {
+        info!(
+            "NUMA node {}: {} processors, {:X} bytes of memory",
+            node,
+            topology.cpus().iter().filter(|c| c.node == node).count(),
+            topology
+                .memory()
+                .iter()
+                .filter(|m| m.node == node)
+                .map(|m| m.range.end - m.range.start)
+                .sum::<u64>()
+        );
+    });
+}
+
+/// Returns the NUMA topology, if the firmware described one.
+#[inline]
+pub fn topology() -> Option<&'static Topology> {
+    TOPOLOGY.get()
+}
+
+/// Returns the number of NUMA nodes. Machines without an SRAT are one node.
+pub fn node_count() -> usize {
+    TOPOLOGY.get().map_or(1, |t| t.nodes().len().max(1))
+}
+
+/// Returns the node the physical address `addr` belongs to.
+pub fn node_of_addr(addr: u64) -> Option<NodeId> {
+    TOPOLOGY
+        .get()?
+        .memory()
+        .iter()
+        .find(|m| m.range.contains(&addr))
+        .map(|m| m.node)
+}
+
+/// Returns the node of the processor with the given local (x2)APIC ID.
+pub fn node_of_cpu(apic_id: u32) -> Option<NodeId> {
+    TOPOLOGY
+        .get()?
+        .cpus()
+        .iter()
+        .find(|c| c.apic_id == apic_id)
+        .map(|c| c.node)
+}
+
+/// Returns the node of the processor this runs on.
+pub fn current_node() -> Option<NodeId> {
+    let id = CpuId::new();
+    let apic_id = match id.get_extended_topology_info().and_then(|mut t| t.next()) {
+        Some(level) => level.x2apic_id(),
+        None => u32::from(id.get_feature_info()?.initial_local_apic_id()),
+    };
+    node_of_cpu(apic_id)
+}
+
+/// Returns the distance between two nodes; see [`Topology::distance`].
+pub fn distance(from: NodeId, to: NodeId) -> u8 {
+    match TOPOLOGY.get() {
+        Some(t) => t.distance(from, to),
+        None if from == to => LOCAL_DISTANCE,
+        None => REMOTE_DISTANCE,
+    }
+}
+
+/// Returns the physical ranges belonging to `node`. Does not allocate.
+pub(crate) fn ranges_of(node: NodeId) -> impl Iterator<Item = Range<u64>> {
+    TOPOLOGY
+        .get()
+        .map_or(&[][..], |t| t.memory())
+        .iter()
+        .filter(move |m| m.node == node)
+        .map(|m| m.range.clone())
+}
@@ -0,0 +1,282 @@
+// SPDX-License-Identifier: MPL-2.0
+use super::{image, GlobalFrameAllocator, MemoryError, FRAME_ALLOCATOR, HIGHER_HALF, MAPPER};
+use core::arch::asm;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use log::*;
+use raw_cpuid::{cpuid, CpuId};
+use x86_64::{
+    instructions::tlb,
+    registers::{
+        control::{Cr4, Cr4Flags},
+        model_specific::Msr,
+    },
+    structures::paging::{PageTable, PageTableFlags},
+    VirtAddr,
+};
+
+/// The IA32_PKRS MSR, which holds the access rights of supervisor protection keys.
+const IA32_PKRS: u32 = 0x6E1;
+/// The number of protection keys of each kind.
+pub const KEY_COUNT: u8 = 16;
+/// The page table entry bits (59-62) that hold the protection key.
+const KEY_SHIFT: u64 = 59;
+const KEY_MASK: u64 = 0xF << KEY_SHIFT;
+
+static PKU_ENABLED: AtomicBool = AtomicBool::new(false);
+static PKS_ENABLED: AtomicBool = AtomicBool::new(false);
+/// Allocated keys of each kind. Key 0 is the key every page starts with, so it is never handed out.
+static USER_KEYS: AtomicU16 = AtomicU16::new(1);
+static SUPERVISOR_KEYS: AtomicU16 = AtomicU16::new(1);
+
+/// Which pages a protection key governs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum KeyKind {
+    /// User pages, whose access rights live in the PKRU register.
+    User,
+    /// Supervisor pages, whose access rights live in the PKRS MSR.
+    Supervisor,
+}
+
+/// The access that pages tagged with a protection key allow.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum KeyAccess {
+    /// Reads and writes are allowed, as far as the page tables allow them.
+    ReadWrite,
+    /// Reads are allowed; writes fault.
+    ReadOnly,
+    /// Every data access faults. Instruction fetches are not affected.
+    NoAccess,
+}
+
+impl KeyAccess {
+    /// Returns the access-disable and write-disable bits for this access, as they sit in PKRU and PKRS.
+    #[inline]
+    fn bits(self) -> u32 {
+        match self {
+            KeyAccess::ReadWrite => 0b00,
+            KeyAccess::ReadOnly => 0b10,
+            KeyAccess::NoAccess => 0b01,
+        }
+    }
+}
+
+/// Enables protection keys for user pages and, where the processor supports them, for supervisor pages. Every key
+/// starts out allowing full access. Must run on every processor; does nothing on processors without the feature.
+#[cold]
+pub fn init() {
+    let pku = CpuId::new()
+        .get_extended_feature_info()
+        .map_or(false, |f| f.has_pku());
+    // raw-cpuid does not know about PKS yet: it is CPUID.(EAX=7,ECX=0):ECX[31]
+    let pks = cpuid!(7, 0).ecx & (1 << 31) != 0;
+    unsafe {
+        Cr4::update(|f| {
+            f.set(Cr4Flags::PROTECTION_KEY_USER, pku);
+            f.set(Cr4Flags::PROTECTION_KEY_SUPERVISOR, pks);
+        });
+    }
+    if pku {
+        unsafe {
+            write_pkru(0);
+        }
+    }
+    if pks {
+        unsafe {
+            Msr::new(IA32_PKRS).write(0);
+        }
+    }
+    PKU_ENABLED.store(pku, Ordering::Relaxed);
+    PKS_ENABLED.store(pks, Ordering::Relaxed);
+    info!("Protection keys: user {}, supervisor {}", pku, pks);
+}
+
+/// Returns whether keys of the given kind are enforced. Keys of a kind that is not are still handed out, but
+/// tagging pages with them and changing their access does nothing.
+#[inline]
+pub fn is_supported(kind: KeyKind) -> bool {
+    match kind {
+        KeyKind::User => PKU_ENABLED.load(Ordering::Relaxed),
+        KeyKind::Supervisor => PKS_ENABLED.load(Ordering::Relaxed),
+    }
+}
+
+/// A protection key. Pages tagged with it allow only the access the key is set to on the processor accessing them,
+/// so access to them can be switched with a register write instead of page table changes and TLB shootdowns.
+///
+/// The key is freed when this is dropped. Pages still tagged with it keep the tag, so they should be retagged first.
+#[derive(Debug)]
+pub struct ProtectionKey {
+    key: u8,
+    kind: KeyKind,
+}
+
+impl ProtectionKey {
+    /// Allocates a key of the given kind. Returns `None` if all of them are in use.
+    pub fn allocate(kind: KeyKind) -> Option<ProtectionKey> {
+        let keys = keys(kind);
+        let mut current = keys.load(Ordering::Acquire);
+        loop {
+            let key = (!current).trailing_zeros();
+            if key >= u32::from(KEY_COUNT) {
+                return None;
+            }
+            match keys.compare_exchange_weak(
+                current,
+                current | 1 << key,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => {
+                    let key = ProtectionKey {
+                        key: key as u8,
+                        kind,
+                    };
+                    key.set_access(KeyAccess::ReadWrite);
+                    return Some(key);
+                }
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// Returns the key's number, as it appears in page table entries.
+    #[inline]
+    pub fn key(&self) -> u8 {
+        self.key
+    }
+
+    /// Returns which pages the key governs.
+    #[inline]
+    pub fn kind(&self) -> KeyKind {
+        self.kind
+    }
+
+    /// Returns the page table flags that tag a page with this key.
+    #[inline]
+    pub fn flags(&self) -> PageTableFlags {
+        PageTableFlags::from_bits_truncate(u64::from(self.key) << KEY_SHIFT)
+    }
+
+    /// Sets the access that pages tagged with this key allow on this processor. For user keys this is a property of
+    /// the running thread, which the scheduler must save and restore along with the rest of its state.
+    pub fn set_access(&self, access: KeyAccess) {
+        if !is_supported(self.kind) {
+            return;
+        }
+        let shift = 2 * u32::from(self.key);
+        let update = |rights: u32| (rights & !(0b11 << shift)) | access.bits() << shift;
+        match self.kind {
+            KeyKind::User => unsafe { write_pkru(update(read_pkru())) },
+            KeyKind::Supervisor => unsafe {
+                let mut msr = Msr::new(IA32_PKRS);
+                let rights = update(msr.read() as u32);
+                msr.write(u64::from(rights));
+            },
+        }
+    }
+
+    /// Returns the access that pages tagged with this key allow on this processor.
+    pub fn access(&self) -> KeyAccess {
+        if !is_supported(self.kind) {
+            return KeyAccess::ReadWrite;
+        }
+        let rights = match self.kind {
+            KeyKind::User => read_pkru(),
+            KeyKind::Supervisor => unsafe { Msr::new(IA32_PKRS).read() as u32 },
+        };
+        match (rights >> (2 * u32::from(self.key))) & 0b11 {
+            0b00 => KeyAccess::ReadWrite,
+            0b10 => KeyAccess::ReadOnly,
+            _ => KeyAccess::NoAccess,
+        }
+    }
+
+    /// Runs `f` with the key set to `access` on this processor, then restores the access it had before.
+    pub fn with_access<R>(&self, access: KeyAccess, f: impl FnOnce() -> R) -> R {
+        let previous = self.access();
+        self.set_access(access);
+        let ret = f();
+        self.set_access(previous);
+        ret
+    }
+}
+
+impl Drop for ProtectionKey {
+    fn drop(&mut self) {
+        self.set_access(KeyAccess::ReadWrite);
+        let _ = keys(self.kind).fetch_and(!(1 << self.key), Ordering::AcqRel);
+    }
+}
+
+#[inline]
+fn keys(kind: KeyKind) -> &'static AtomicU16 {
+    match kind {
+        KeyKind::User => &USER_KEYS,
+        KeyKind::Supervisor => &SUPERVISOR_KEYS,
+    }
+}
+
+#[inline]
+fn read_pkru() -> u32 {
+    let rights: u32;
+    unsafe {
+        asm!("rdpkru", in("ecx") 0, out("eax") rights, out("edx") _, options(nomem, nostack, preserves_flags));
+    }
+    rights
+}
+
+#[inline]
+unsafe fn write_pkru(rights: u32) {
+    unsafe {
+        asm!("wrpkru", in("eax") rights, in("ecx") 0, in("edx") 0, options(nostack, preserves_flags));
+    }
+}
+
+/// Tags every page of the kernel half in `[start, start + len)` with the supervisor key `key`. Huge pages that
+/// straddle either end of the range are split so that only the range is tagged. Pages in the range that are not mapped
+/// are skipped, and the first of them is reported.
+pub fn tag_kernel_range(start: u64, len: u64, key: &ProtectionKey) -> Result<(), MemoryError> {
+    if key.kind != KeyKind::Supervisor {
+        return Err(MemoryError::WrongKeyKind);
+    }
+    if start < HIGHER_HALF || start.checked_add(len).is_none() {
+        return Err(MemoryError::InvalidRange {
+            start,
+            end: start.wrapping_add(len),
+        });
+    }
+    match (MAPPER.lock().as_mut(), FRAME_ALLOCATOR.lock().as_mut()) {
+        (Some(m), Some(a)) => unsafe { tag_range(m.level_4_table(), a, start..start + len, key) },
+        _ => Err(MemoryError::NotInitialized),
+    }
+}
+
+/// Tags every page mapped in `span` under the level 4 table `l4` with `key`, flushing each from this processor's TLB.
+/// Huge pages that straddle either end of `span` are split first. Does nothing if keys of that kind are not
+/// supported. Pages in the range that are not mapped are skipped, and the first of them is reported; fails with
+/// [`MemoryError::OutOfFrames`] if a huge page cannot be split.
+///
+/// # Safety
+///
+/// `frame_allocator` must be the locked frame allocator, and the caller must invalidate other processors' TLBs and
+/// PCIDs as needed.
+pub(crate) unsafe fn tag_range(
+    l4: &mut PageTable,
+    frame_allocator: &mut GlobalFrameAllocator,
+    span: Range<u64>,
+    key: &ProtectionKey,
+) -> Result<(), MemoryError> {
+    if !is_supported(key.kind) {
+        return Ok(());
+    }
+    let unmapped = unsafe {
+        image::try_for_each_leaf(l4, frame_allocator, span, |addr, entry| {
+            entry.set_flags(
+                PageTableFlags::from_bits_truncate(entry.flags().bits() & !KEY_MASK) | key.flags(),
+            );
+            tlb::flush(VirtAddr::new_truncate(addr));
+        })
+    }?;
+    unmapped.map_or(Ok(()), |addr| Err(MemoryError::NotMapped(addr)))
+}
@@ -0,0 +1,153 @@
+// SPDX-License-Identifier: MPL-2.0
+use super::{
+    allocate_frames, free_frames, phys_to_virt,
+    space::{AddressSpace, SpaceState},
+    Mapping, MemoryError,
+};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::mutex::ticket::TicketMutex;
+use x86_64::{
+    addr::align_up,
+    structures::paging::{PageSize, PageTableFlags, PhysFrame, Size4KiB},
+    VirtAddr,
+};
+
+/// Identifiers for shared regions. Zero is never handed out; it marks mappings that belong to no region.
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A region of memory that can be mapped into several address spaces at once, such as the buffer an application and
+/// a user-space driver exchange requests through.
+///
+/// The region is backed by frames of its own, which are zeroed when it is created. Each mapping has its own
+/// permissions, so the same region can be writable in one address space and read-only in another. Revoking the region
+/// unmaps it from every address space it is mapped into and invalidates their TLB entries; the frames themselves are
+/// only freed once the region is dropped and nothing maps them anymore.
+#[derive(Debug)]
+pub struct SharedRegion {
+    id: u64,
+    frames: Vec<PhysFrame>,
+    /// Set once the region has been revoked; it cannot be mapped again.
+    revoked: AtomicBool,
+    /// The address spaces the region has been mapped into. Entries of address spaces that have since been dropped
+    /// no longer upgrade.
+    spaces: TicketMutex<Vec<Weak<SpaceState>>>,
+}
+
+impl SharedRegion {
+    /// Allocates a zeroed region of at least `size` bytes.
+    pub fn new(size: u64) -> Result<SharedRegion, MemoryError> {
+        let pages = align_up(size.max(1), Size4KiB::SIZE) / Size4KiB::SIZE;
+        let mut frames = Vec::with_capacity(pages as usize);
+        for _ in 0..pages {
+            match allocate_frames(0) {
+                Some(frame) => {
+                    unsafe {
+                        phys_to_virt(frame.start_address())
+                            .as_mut_ptr::<u8>()
+                            .write_bytes(0, Size4KiB::SIZE as usize);
+                    }
+                    frames.push(frame);
+                }
+                None => {
+                    frames.iter().for_each(|&f| unsafe { free_frames(f, 0) });
+                    return Err(MemoryError::OutOfFrames);
+                }
+            }
+        }
+        Ok(SharedRegion {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            frames,
+            revoked: AtomicBool::new(false),
+            spaces: TicketMutex::new(Vec::new()),
+        })
+    }
+
+    /// Returns the size of the region in bytes.
+    #[inline]
+    pub fn size(&self) -> u64 {
+        self.frames.len() as u64 * Size4KiB::SIZE
+    }
+
+    /// Returns the frames backing the region, in order.
+    #[inline]
+    pub fn frames(&self) -> &[PhysFrame] {
+        &self.frames
+    }
+
+    /// Returns whether the region has been revoked.
+    #[inline]
+    pub fn is_revoked(&self) -> bool {
+        self.revoked.load(Ordering::Acquire)
+    }
+
+    /// Maps the region into the user half of `space` at an address of the kernel's choosing, with the given flags.
+    /// The `P` (present) bit is always set. The address is reserved in `space` until the mapping is unmapped or the
+    /// region is revoked.
+    pub fn map(
+        &self,
+        space: &mut AddressSpace,
+        flags: PageTableFlags,
+    ) -> Result<Mapping, MemoryError> {
+        let start = space
+            .reserve(self.size(), Size4KiB::SIZE, Size4KiB::SIZE, true)
+            .ok_or(MemoryError::OutOfAddressSpace)?;
+        self.map_at(space, start.as_u64(), flags).map_err(|e| {
+            let _ = space.release(start);
+            e
+        })
+    }
+
+    /// Maps the region into the user half of `space` at `start`, with the given flags. The `P` (present) bit is always
+    /// set. Fails, leaving nothing mapped, if the region has been revoked or any page in the way is already mapped.
+    pub fn map_at(
+        &self,
+        space: &mut AddressSpace,
+        start: u64,
+        flags: PageTableFlags,
+    ) -> Result<Mapping, MemoryError> {
+        // Holding the list keeps a concurrent revocation from missing this mapping
+        let mut spaces = self.spaces.lock();
+        if self.is_revoked() {
+            return Err(MemoryError::Revoked);
+        }
+        let mapping = space.map_frames(start, &self.frames, flags, self.id)?;
+        let state = Arc::downgrade(space.state());
+        spaces.retain(|s| s.strong_count() > 0);
+        if !spaces.iter().any(|s| s.ptr_eq(&state)) {
+            spaces.push(state);
+        }
+        Ok(mapping)
+    }
+
+    /// Unmaps the mapping of the region starting at `start` from `space`, invalidating its TLB entries, and releases
+    /// the reservation it starts at, if any. Fails with [`MemoryError::NotMapped`] if no mapping of this region starts
+    /// there.
+    pub fn unmap(&self, space: &mut AddressSpace, start: u64) -> Result<(), MemoryError> {
+        space.state().unmap_region(start, self.id)?;
+        let _ = space.release(VirtAddr::new_truncate(start));
+        Ok(())
+    }
+
+    /// Unmaps the region from every address space it is mapped into, invalidates their TLB entries and releases the
+    /// reservations the mappings start at. The region cannot be mapped again afterwards.
+    pub fn revoke(&self) {
+        let mut spaces = self.spaces.lock();
+        self.revoked.store(true, Ordering::Release);
+        spaces
+            .iter()
+            .filter_map(Weak::upgrade)
+            .for_each(|state| state.revoke(self.id));
+        spaces.clear();
+    }
+}
+
+impl Drop for SharedRegion {
+    fn drop(&mut self) {
+        self.revoke();
+        self.frames
+            .iter()
+            .for_each(|&frame| unsafe { free_frames(frame, 0) });
+    }
+}
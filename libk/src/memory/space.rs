@@ -0,0 +1,722 @@
+// SPDX-License-Identifier: MPL-2.0
+use super::{
+    frame::{self, FrameFlags},
+    lock::OwnedMutex,
+    map_range, page_span, paging_levels, phys_to_virt,
+    pkey::{self, KeyKind, ProtectionKey},
+    release_frames, unmap_range,
+    vm::{self, Backing, RegionMap},
+    vspace::VirtualSpace,
+    GlobalFrameAllocator, Mapping, MemoryError, FRAME_ALLOCATOR, PHYS_OFFSET,
+};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, Ordering};
+use log::*;
+use raw_cpuid::CpuId;
+use spin::{mutex::ticket::TicketMutex, Lazy, Once};
+use x86_64::{
+    instructions::tlb::{flush_pcid, InvPicdCommand, Pcid},
+    registers::control::{Cr3, Cr4, Cr4Flags},
+    structures::paging::{
+        mapper::TranslateResult, FrameAllocator, FrameDeallocator, OffsetPageTable, PageSize,
+        PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// End (exclusive) of the user half of an address space.
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+/// Lowest address handed out to user mappings; the null page is never mapped.
+const USER_START: u64 = 0x1000;
+/// Number of level 4 entries covering the user half.
+const USER_ENTRIES: usize = 256;
+/// The shared region recorded for mappings that belong to none.
+pub(crate) const PRIVATE_MAPPING: u64 = 0;
+/// Bit 63 of CR3 asks the processor to keep the TLB entries of the new PCID.
+const CR3_NOFLUSH: u64 = 1 << 63;
+
+/// The top-level table of the kernel's own page table: level 4, or level 5 with 5-level paging.
+static KERNEL_ROOT: Once<PhysFrame> = Once::new();
+static PCID_ENABLED: AtomicBool = AtomicBool::new(false);
+static INVPCID: Lazy<bool> = Lazy::new(|| {
+    CpuId::new()
+        .get_extended_feature_info()
+        .map_or(false, |f| f.has_invpcid())
+});
+/// PCIDs in use. PCID 0 belongs to the kernel's own page table.
+static PCIDS: Lazy<TicketMutex<[u64; 64]>> = Lazy::new(|| {
+    let mut pcids = [0; 64];
+    pcids[0] = 1;
+    TicketMutex::new(pcids)
+});
+
+/// Prepares the kernel page table for sharing and enables PCIDs if the processor has them.
+///
+/// Every empty entry of the kernel half of the level 4 table is given an empty level 3 table, so that kernel
+/// mappings made after an address space was created are visible in it too.
+#[cold]
+pub(crate) fn init(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut GlobalFrameAllocator,
+) {
+    let _ = KERNEL_ROOT.call_once(|| Cr3::read().0);
+    let l4 = mapper.level_4_table();
+    for entry in l4.iter_mut().skip(USER_ENTRIES).filter(|e| e.is_unused()) {
+        match frame_allocator.allocate_frame() {
+            Some(frame) => unsafe {
+                zero_frame(frame);
+                entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+            },
+            None => panic!("Cannot allocate kernel page tables: no more frames"),
+        }
+    }
+    let id = CpuId::new();
+    let features = id.get_feature_info();
+    unsafe {
+        Cr4::update(|f| {
+            if features.as_ref().map_or(false, |f| f.has_pge()) {
+                f.insert(Cr4Flags::PAGE_GLOBAL);
+            }
+            if features.as_ref().map_or(false, |f| f.has_pcid()) && Cr3::read().1.is_empty() {
+                f.insert(Cr4Flags::PCID);
+            }
+        });
+    }
+    if Cr4::read().contains(Cr4Flags::PCID) {
+        PCID_ENABLED.store(true, Ordering::Relaxed);
+        info!(
+            "PCIDs enabled{}",
+            if *INVPCID { " with INVPCID" } else { "" }
+        );
+    }
+}
+
+/// Enables the paging features [`init`] enabled on the bootstrap processor on an application processor, which must
+/// already run on the kernel's page table with PCID 0.
+#[cold]
+pub(crate) fn init_ap() {
+    let pge = CpuId::new()
+        .get_feature_info()
+        .map_or(false, |f| f.has_pge());
+    unsafe {
+        Cr4::update(|f| {
+            f.set(Cr4Flags::PAGE_GLOBAL, pge);
+            f.set(Cr4Flags::PCID, PCID_ENABLED.load(Ordering::Relaxed));
+        });
+    }
+}
+
+/// Returns the top-level table of the kernel's own page table.
+pub(crate) fn kernel_root() -> PhysFrame {
+    KERNEL_ROOT.get().copied().unwrap_or_else(|| Cr3::read().0)
+}
+
+/// Zeroes a frame through the higher-half physical memory mapping.
+unsafe fn zero_frame(frame: PhysFrame) {
+    let table: *mut PageTable = phys_to_virt(frame.start_address()).as_mut_ptr();
+    unsafe {
+        table.write(PageTable::new());
+    }
+}
+
+/// Writes CR3 with the given level 4 table and PCID.
+unsafe fn write_cr3(frame: PhysFrame, pcid: u16, noflush: bool) {
+    let value =
+        frame.start_address().as_u64() | u64::from(pcid) | if noflush { CR3_NOFLUSH } else { 0 };
+    unsafe {
+        asm!("mov cr3, {}", in(reg) value, options(nostack, preserves_flags));
+    }
+}
+
+/// Switches back to the kernel's own page table.
+///
+/// # Safety
+///
+/// Anything mapped only in the user half of the current address space becomes inaccessible.
+pub unsafe fn switch_to_kernel() {
+    if let Some(frame) = KERNEL_ROOT.get() {
+        unsafe {
+            write_cr3(*frame, 0, PCID_ENABLED.load(Ordering::Relaxed));
+        }
+    }
+}
+
+/// An address space with its own level 4 page table.
+///
+/// The user half (below [`USER_END`]) is private to the address space. The kernel half shares the kernel's own level 3
+/// tables, so kernel mappings are the same in every address space. With 5-level paging, the level 4 table sits under
+/// the first entry of a level 5 table of the address space's own, which shares the kernel's level 4 tables instead.
+/// When the processor supports PCIDs, each address space is tagged with its own PCID so switching to it keeps the TLB
+/// entries of every other address space.
+#[derive(Debug)]
+pub struct AddressSpace {
+    state: Arc<SpaceState>,
+    /// Regions backed on demand by the page fault handler.
+    regions: Arc<OwnedMutex<RegionMap>>,
+}
+
+/// The page tables, TLB state and mappings of an address space. Shared regions keep a reference to it, so that they
+/// can take their mappings back out of address spaces they do not own.
+#[derive(Debug)]
+pub(crate) struct SpaceState {
+    /// The table CR3 points at; the same as `l4` with 4-level paging.
+    root: PhysFrame,
+    l4: PhysFrame,
+    pcid: Option<Pcid>,
+    /// Set when the TLB may hold stale entries for this address space's PCID.
+    stale: AtomicBool,
+    /// Ranges mapped with [`AddressSpace::map`] and its relatives, keyed by start, with their end and the shared
+    /// region they belong to ([`PRIVATE_MAPPING`] for none). `None` once the address space is being torn down.
+    mapped: TicketMutex<Option<BTreeMap<u64, (u64, u64)>>>,
+    /// The reserved ranges of the user half.
+    vspace: TicketMutex<VirtualSpace>,
+}
+
+impl AddressSpace {
+    /// Creates an address space with an empty user half.
+    pub fn new() -> Option<AddressSpace> {
+        let kernel_root = *KERNEL_ROOT.get()?;
+        let (root, l4) = {
+            let mut allocator = FRAME_ALLOCATOR.lock();
+            let a = allocator.as_mut()?;
+            let l4 = a.allocate_frame()?;
+            if paging_levels() < 5 {
+                (l4, l4)
+            } else if let Some(root) = a.allocate_frame() {
+                (root, l4)
+            } else {
+                unsafe {
+                    a.deallocate_frame(l4);
+                }
+                return None;
+            }
+        };
+        [root, l4].iter().for_each(|f| {
+            frame::mark(
+                f.start_address().as_u64(),
+                Size4KiB::SIZE,
+                FrameFlags::PAGE_TABLE,
+            )
+        });
+        unsafe {
+            zero_frame(l4);
+            zero_frame(root);
+            let new: &mut PageTable = &mut *phys_to_virt(root.start_address()).as_mut_ptr();
+            let kernel: &PageTable = &*phys_to_virt(kernel_root.start_address()).as_ptr();
+            new.iter_mut()
+                .zip(kernel.iter())
+                .skip(USER_ENTRIES)
+                .for_each(|(n, k)| *n = k.clone());
+            if root != l4 {
+                new[0].set_frame(
+                    l4,
+                    PageTableFlags::PRESENT
+                        | PageTableFlags::WRITABLE
+                        | PageTableFlags::USER_ACCESSIBLE,
+                );
+            }
+        }
+        let pcid = if PCID_ENABLED.load(Ordering::Relaxed) {
+            allocate_pcid()
+        } else {
+            None
+        };
+        let regions = Arc::new(OwnedMutex::new(RegionMap::default()));
+        vm::register_space(root, regions.clone());
+        Some(AddressSpace {
+            state: Arc::new(SpaceState {
+                root,
+                l4,
+                pcid,
+                stale: AtomicBool::new(true),
+                mapped: TicketMutex::new(Some(BTreeMap::new())),
+                vspace: TicketMutex::new(VirtualSpace::new(USER_START..USER_END)),
+            }),
+            regions,
+        })
+    }
+
+    /// Returns the frame holding this address space's level 4 table.
+    #[inline]
+    pub fn l4_frame(&self) -> PhysFrame {
+        self.state.l4
+    }
+
+    /// Returns the frame holding the table CR3 points at when this address space is active: the level 5 table with
+    /// 5-level paging, otherwise the level 4 table.
+    #[inline]
+    pub fn root_frame(&self) -> PhysFrame {
+        self.state.root
+    }
+
+    /// Returns the PCID this address space is tagged with, if any.
+    #[inline]
+    pub fn pcid(&self) -> Option<Pcid> {
+        self.state.pcid
+    }
+
+    /// Returns whether this address space is the one currently loaded in CR3.
+    pub fn is_active(&self) -> bool {
+        self.state.is_active()
+    }
+
+    /// Returns the state shared regions need to reach this address space.
+    #[inline]
+    pub(crate) fn state(&self) -> &Arc<SpaceState> {
+        &self.state
+    }
+
+    /// Reserves `size` bytes of the user half aligned to `align`, with `guard` bytes kept free on either side.
+    pub fn reserve(
+        &mut self,
+        size: u64,
+        align: u64,
+        guard: u64,
+        randomize: bool,
+    ) -> Option<VirtAddr> {
+        self.state
+            .vspace
+            .lock()
+            .reserve(size, align, guard, randomize)
+    }
+
+    /// Releases a range of the user half previously returned by [`reserve`](AddressSpace::reserve), making it (and
+    /// its guard gaps) available again. Returns the size of the range, or `None` if no reservation starts at `start`.
+    /// This does not unmap anything mapped in the range.
+    pub fn release(&mut self, start: VirtAddr) -> Option<u64> {
+        self.state.vspace.lock().release(start)
+    }
+
+    /// Maps `[start, start + len)` in the user half to freshly allocated frames. The `P` (present) bit is always set.
+    /// Fails if the range is outside the user half or any page in it was already mapped, leaving nothing mapped.
+    pub fn map(
+        &mut self,
+        start: u64,
+        len: u64,
+        flags: PageTableFlags,
+    ) -> Result<Mapping, MemoryError> {
+        self.map_inner(start, None, len, flags)
+    }
+
+    /// Maps `[start, start + len)` in the user half to the physical range starting at `phys`. The `P` (present) bit
+    /// is always set, and every allocated frame in the range gains a reference. Fails if the range is outside the user
+    /// half or any page in it was already mapped, leaving nothing mapped.
+    pub fn map_phys(
+        &mut self,
+        start: u64,
+        phys: u64,
+        len: u64,
+        flags: PageTableFlags,
+    ) -> Result<Mapping, MemoryError> {
+        self.map_inner(start, Some(phys), len, flags)
+    }
+
+    fn map_inner(
+        &mut self,
+        start: u64,
+        phys: Option<u64>,
+        len: u64,
+        flags: PageTableFlags,
+    ) -> Result<Mapping, MemoryError> {
+        let span = user_span(start, len)?;
+        let phys = phys.map(|p| p & !0xFFF);
+        self.state
+            .map(span.clone(), PRIVATE_MAPPING, |mapper, a| unsafe {
+                map_range(
+                    mapper,
+                    a,
+                    span.start,
+                    phys,
+                    span.end - span.start,
+                    PageTableFlags::PRESENT | flags,
+                )
+            })?;
+        if let Some(phys) = phys {
+            frame::get_range(phys, span.end - span.start);
+        }
+        Ok(Mapping {
+            start: span.start,
+            end: span.end,
+            phys,
+        })
+    }
+
+    /// Maps the page-aligned range starting at `start` to `frames`, one page per frame, on behalf of the shared
+    /// region `region`. Every frame gains a reference. Nothing is left mapped if this fails.
+    pub(crate) fn map_frames(
+        &mut self,
+        start: u64,
+        frames: &[PhysFrame],
+        flags: PageTableFlags,
+        region: u64,
+    ) -> Result<Mapping, MemoryError> {
+        let span = user_span(start, frames.len() as u64 * Size4KiB::SIZE)?;
+        let flags = PageTableFlags::PRESENT | flags;
+        self.state.map(span.clone(), region, |mapper, a| {
+            for (i, frame) in frames.iter().enumerate() {
+                let page = span.start + i as u64 * Size4KiB::SIZE;
+                let phys = frame.start_address().as_u64();
+                if let Err(e) =
+                    unsafe { map_range(mapper, a, page, Some(phys), Size4KiB::SIZE, flags) }
+                {
+                    unsafe {
+                        let _ = unmap_range(mapper, a, span.start..page, |_, _, _, _| ());
+                    }
+                    return Err(e);
+                }
+            }
+            Ok(())
+        })?;
+        frames
+            .iter()
+            .for_each(|f| frame::get_range(f.start_address().as_u64(), Size4KiB::SIZE));
+        Ok(Mapping {
+            start: span.start,
+            end: span.end,
+            phys: None,
+        })
+    }
+
+    /// Unmaps `[start, start + len)` from the user half. Every unmapped frame loses a reference, and goes back to the
+    /// frame allocator if that was its last one. Pages in the range that are not mapped are skipped, and the first of
+    /// them is reported.
+    pub fn unmap(&mut self, start: u64, len: u64) -> Result<(), MemoryError> {
+        self.state.unmap(user_span(start, len)?)
+    }
+
+    /// Tags every page mapped in `[start, start + len)` with the user protection key `key`, so that access to them
+    /// follows the access the key is set to. Huge pages that straddle either end of the range are split so that only
+    /// the range is tagged. Pages in the range that are not mapped are skipped, and the first of them is reported;
+    /// pages backed on demand must be touched before they can be tagged.
+    pub fn set_protection_key(
+        &mut self,
+        start: u64,
+        len: u64,
+        key: &ProtectionKey,
+    ) -> Result<(), MemoryError> {
+        if key.kind() != KeyKind::User {
+            return Err(MemoryError::WrongKeyKind);
+        }
+        let span = user_span(start, len)?;
+        let ret = match FRAME_ALLOCATOR.lock().as_mut() {
+            Some(a) => unsafe { pkey::tag_range(self.mapper().level_4_table(), a, span, key) },
+            None => return Err(MemoryError::NotInitialized),
+        };
+        self.invalidate();
+        ret
+    }
+
+    /// Reserves `[start, start + len)` in the user half to be backed on demand from `backing`, one page at a time as
+    /// it is touched, with the given flags. The `P` (present) bit is implied. Returns false if the range is outside
+    /// the user half or overlaps another demand-paged region.
+    pub fn map_lazy(
+        &mut self,
+        start: u64,
+        len: u64,
+        flags: PageTableFlags,
+        backing: Backing,
+    ) -> bool {
+        if len == 0 || start.checked_add(len).map_or(true, |end| end > USER_END) {
+            return false;
+        }
+        self.regions
+            .lock()
+            .insert(page_span(start, start + len - 1), flags, backing)
+    }
+
+    /// Removes the demand-paged region starting at `start`, unmapping whatever of it was touched and returning the
+    /// frames that belonged to it to the frame allocator. Returns false if no region starts at `start`.
+    pub fn unmap_lazy(&mut self, start: u64) -> bool {
+        let region = match self.regions.lock().remove(start) {
+            Some(region) => region,
+            None => return false,
+        };
+        match FRAME_ALLOCATOR.lock().as_mut() {
+            Some(a) => unsafe { vm::unmap_pages(&mut self.mapper(), a, region.range()) },
+            None => panic!("Frame allocator not initialized!"),
+        }
+        self.invalidate();
+        true
+    }
+
+    /// Translates a virtual address in this address space to a physical address.
+    pub fn translate(&mut self, addr: VirtAddr) -> Option<PhysAddr> {
+        match self.mapper().translate(addr) {
+            TranslateResult::Mapped { frame, offset, .. } => Some(frame.start_address() + offset),
+            _ => None,
+        }
+    }
+
+    /// Loads this address space into CR3. With PCIDs, TLB entries tagged with this address space's PCID are kept
+    /// unless the address space was changed while inactive and the change could not be invalidated directly.
+    ///
+    /// # Safety
+    ///
+    /// The code, stack and data the caller uses must be mapped in the kernel half.
+    pub unsafe fn activate(&self) {
+        let state = &self.state;
+        match state.pcid {
+            Some(pcid) => unsafe {
+                write_cr3(
+                    state.root,
+                    pcid.value(),
+                    !state.stale.swap(false, Ordering::AcqRel),
+                );
+            },
+            None => unsafe { write_cr3(state.root, 0, false) },
+        }
+    }
+
+    fn invalidate(&self) {
+        self.state.invalidate();
+    }
+
+    fn mapper(&mut self) -> OffsetPageTable<'_> {
+        unsafe { self.state.mapper() }
+    }
+}
+
+impl SpaceState {
+    /// Returns whether this address space is the one currently loaded in CR3.
+    fn is_active(&self) -> bool {
+        Cr3::read().0 == self.root
+    }
+
+    /// Invalidates TLB entries of this address space after its mappings changed. Entries of the active address space
+    /// were already flushed page by page; other PCIDs are flushed with INVPCID or on their next activation.
+    fn invalidate(&self) {
+        if let Some(pcid) = self.pcid {
+            if self.is_active() {
+                return;
+            }
+            if *INVPCID {
+                unsafe {
+                    flush_pcid(InvPicdCommand::Single(pcid));
+                }
+            } else {
+                self.stale.store(true, Ordering::Release);
+            }
+        }
+    }
+
+    /// Returns a mapper for the user half.
+    ///
+    /// # Safety
+    ///
+    /// The page tables must only be changed with the frame allocator locked, and the mapper must not outlive the
+    /// address space.
+    unsafe fn mapper(&self) -> OffsetPageTable<'static> {
+        unsafe {
+            OffsetPageTable::new(
+                &mut *phys_to_virt(self.l4.start_address()).as_mut_ptr(),
+                VirtAddr::new_truncate(PHYS_OFFSET.load(Ordering::Relaxed)),
+            )
+        }
+    }
+
+    /// Runs `map` with the frame allocator locked and records `span` as mapped on behalf of `region` if it succeeds.
+    fn map(
+        &self,
+        span: Range<u64>,
+        region: u64,
+        map: impl FnOnce(
+            &mut OffsetPageTable<'static>,
+            &mut GlobalFrameAllocator,
+        ) -> Result<(), MemoryError>,
+    ) -> Result<(), MemoryError> {
+        let mut mapped = self.mapped.lock();
+        let ranges = mapped.as_mut().ok_or(MemoryError::NotInitialized)?;
+        match FRAME_ALLOCATOR.lock().as_mut() {
+            Some(a) => map(&mut unsafe { self.mapper() }, a)?,
+            None => return Err(MemoryError::NotInitialized),
+        }
+        let _ = ranges.insert(span.start, (span.end, region));
+        Ok(())
+    }
+
+    /// Unmaps `span`, dropping a reference to every frame in it, and trims the recorded mappings to what is still
+    /// mapped.
+    fn unmap(&self, span: Range<u64>) -> Result<(), MemoryError> {
+        let mut mapped = self.mapped.lock();
+        let ranges = mapped.as_mut().ok_or(MemoryError::NotInitialized)?;
+        let overlapping: Vec<(u64, (u64, u64))> = ranges
+            .range(..span.end)
+            .filter(|(_, &(e, _))| span.start < e)
+            .map(|(&s, &v)| (s, v))
+            .collect();
+        let ret = match FRAME_ALLOCATOR.lock().as_mut() {
+            Some(a) => unsafe { self.unmap_locked(a, span.clone()) },
+            None => return Err(MemoryError::NotInitialized),
+        };
+        overlapping.into_iter().for_each(|(s, (e, region))| {
+            let _ = ranges.remove(&s);
+            if s < span.start {
+                let _ = ranges.insert(s, (span.start, region));
+            }
+            if e > span.end {
+                let _ = ranges.insert(span.end, (e, region));
+            }
+        });
+        self.invalidate();
+        ret
+    }
+
+    /// Unmaps the mapping made on behalf of the shared region `region` that starts at `start`. Fails, leaving
+    /// everything mapped, if no mapping of that region starts there.
+    pub(crate) fn unmap_region(&self, start: u64, region: u64) -> Result<(), MemoryError> {
+        let mut mapped = self.mapped.lock();
+        let ranges = mapped.as_mut().ok_or(MemoryError::NotInitialized)?;
+        let end = match ranges.get(&start) {
+            Some(&(end, r)) if r == region => end,
+            _ => return Err(MemoryError::NotMapped(start)),
+        };
+        let ret = match FRAME_ALLOCATOR.lock().as_mut() {
+            Some(a) => unsafe { self.unmap_locked(a, start..end) },
+            None => return Err(MemoryError::NotInitialized),
+        };
+        let _ = ranges.remove(&start);
+        self.invalidate();
+        ret
+    }
+
+    /// Unmaps everything mapped on behalf of the shared region `region`, and releases the reservations its mappings
+    /// start at. Does nothing if the address space is being torn down, since that unmaps everything anyway.
+    pub(crate) fn revoke(&self, region: u64) {
+        let mut mapped = self.mapped.lock();
+        let ranges = match mapped.as_mut() {
+            Some(ranges) => ranges,
+            None => return,
+        };
+        let spans: Vec<Range<u64>> = ranges
+            .iter()
+            .filter(|(_, &(_, r))| r == region)
+            .map(|(&s, &(e, _))| s..e)
+            .collect();
+        spans.iter().for_each(|span| {
+            let _ = ranges.remove(&span.start);
+        });
+        if let Some(a) = FRAME_ALLOCATOR.lock().as_mut() {
+            spans.iter().for_each(|span| unsafe {
+                let _ = self.unmap_locked(a, span.clone());
+            });
+        }
+        self.invalidate();
+        // Released with the frame allocator unlocked, since the reservations live on the heap
+        let mut vspace = self.vspace.lock();
+        spans.iter().for_each(|span| {
+            let _ = vspace.release(VirtAddr::new_truncate(span.start));
+        });
+    }
+
+    /// Unmaps `span`, dropping a reference to every frame in it. Must not allocate from the heap, since the frame
+    /// allocator is locked.
+    unsafe fn unmap_locked(
+        &self,
+        a: &mut GlobalFrameAllocator,
+        span: Range<u64>,
+    ) -> Result<(), MemoryError> {
+        unsafe {
+            unmap_range(&mut self.mapper(), a, span, |a, _, phys, len| {
+                release_frames(a, phys, len)
+            })
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if self.is_active() {
+            unsafe {
+                switch_to_kernel();
+            }
+        }
+        let state = self.state.clone();
+        vm::unregister_space(state.root);
+        let mapped = state.mapped.lock().take().unwrap_or_default();
+        let regions = core::mem::take(&mut *self.regions.lock());
+        if let Some(a) = FRAME_ALLOCATOR.lock().as_mut() {
+            unsafe {
+                mapped.iter().for_each(|(&s, &(e, _))| {
+                    let _ = state.unmap_locked(a, s..e);
+                });
+                regions
+                    .regions()
+                    .for_each(|r| vm::unmap_pages(&mut self.mapper(), a, r.range()));
+                let l4: &PageTable = &*phys_to_virt(state.l4.start_address()).as_ptr();
+                l4.iter()
+                    .take(USER_ENTRIES)
+                    .filter(|e| e.flags().contains(PageTableFlags::PRESENT))
+                    .for_each(|e| free_table(a, e.addr(), 3));
+                a.deallocate_frame(state.l4);
+                if state.root != state.l4 {
+                    a.deallocate_frame(state.root);
+                }
+            }
+        }
+        if let Some(pcid) = state.pcid {
+            if *INVPCID {
+                unsafe {
+                    flush_pcid(InvPicdCommand::Single(pcid));
+                }
+            }
+            free_pcid(pcid);
+        }
+    }
+}
+
+/// Frees a page table of the given level and every lower-level table it references.
+unsafe fn free_table(a: &mut GlobalFrameAllocator, addr: PhysAddr, level: u8) {
+    if level > 1 {
+        let table: &PageTable = unsafe { &*phys_to_virt(addr).as_ptr() };
+        table
+            .iter()
+            .filter(|e| {
+                e.flags().contains(PageTableFlags::PRESENT)
+                    && !e.flags().contains(PageTableFlags::HUGE_PAGE)
+            })
+            .for_each(|e| unsafe { free_table(a, e.addr(), level - 1) });
+    }
+    unsafe {
+        a.deallocate_frame(PhysFrame::<Size4KiB>::containing_address(addr));
+    }
+}
+
+/// Returns the pages covering `[start, start + len)`, or an error if the range is empty or leaves the user half.
+fn user_span(start: u64, len: u64) -> Result<Range<u64>, MemoryError> {
+    match start.checked_add(len) {
+        Some(end) if len > 0 && end <= USER_END => Ok(page_span(start, end - 1)),
+        _ => Err(MemoryError::InvalidRange {
+            start,
+            end: start.wrapping_add(len),
+        }),
+    }
+}
+
+/// Returns the level 4 table holding the user half of the address space whose top-level table is `root`.
+pub(crate) fn user_l4(root: PhysFrame) -> PhysFrame {
+    if paging_levels() < 5 {
+        return root;
+    }
+    let table: &PageTable = unsafe { &*phys_to_virt(root.start_address()).as_ptr() };
+    PhysFrame::containing_address(table[0].addr())
+}
+
+fn allocate_pcid() -> Option<Pcid> {
+    let mut pcids = PCIDS.lock();
+    let (word, bits) = pcids
+        .iter_mut()
+        .enumerate()
+        .find(|(_, w)| **w != u64::MAX)?;
+    let bit = bits.trailing_ones();
+    *bits |= 1 << bit;
+    Pcid::new((word as u16) * 64 + (bit as u16)).ok()
+}
+
+fn free_pcid(pcid: Pcid) {
+    let value = usize::from(pcid.value());
+    PCIDS.lock()[value / 64] &= !(1 << (value % 64));
+}
@@ -0,0 +1,145 @@
+// SPDX-License-Identifier: MPL-2.0
+use super::{
+    image::no_execute, map_range, release_frames, unmap_range, vspace, FRAME_ALLOCATOR, MAPPER,
+};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use core::arch::asm;
+use core::mem::forget;
+use core::ops::Range;
+use log::*;
+use spin::{Lazy, RwLock};
+use x86_64::{
+    addr::align_up,
+    structures::paging::{PageSize, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+/// The size of the unmapped guard gap kept below (and above) every kernel stack. Frames larger than this can jump
+/// over the guard, so functions with big locals should not run on kernel stacks.
+pub const GUARD_SIZE: u64 = 4 * Size4KiB::SIZE;
+/// The default size of a kernel stack.
+pub const DEFAULT_STACK_SIZE: u64 = 64 * 1024;
+
+/// Every kernel stack, keyed by its lowest mapped address.
+static STACKS: Lazy<RwLock<BTreeMap<u64, StackRecord>>> =
+    Lazy::new(|| RwLock::new(BTreeMap::new()));
+
+/// What the page fault handler needs to know about a stack.
+#[derive(Debug)]
+struct StackRecord {
+    guard: u64,
+    owner: String,
+}
+
+/// A kernel stack in kernel virtual address space, with unmapped guard pages around it so that an overflow faults
+/// instead of silently corrupting whatever lies below. The stack is unmapped and its frames and addresses released
+/// when it is dropped.
+#[derive(Debug)]
+pub struct KernelStack {
+    bottom: u64,
+    top: u64,
+}
+
+impl KernelStack {
+    /// Allocates a stack of at least `size` bytes for `owner`, which is named in the report if the stack ever
+    /// overflows. Returns `None` if no kernel virtual address space or no memory is left.
+    pub fn new(size: u64, owner: &str) -> Option<KernelStack> {
+        let size = align_up(size.max(1), Size4KiB::SIZE);
+        let bottom = vspace::reserve(size, Size4KiB::SIZE, GUARD_SIZE, true)?.as_u64();
+        let mapped = match (MAPPER.lock().as_mut(), FRAME_ALLOCATOR.lock().as_mut()) {
+            (Some(m), Some(a)) => unsafe {
+                map_range(
+                    m,
+                    a,
+                    bottom,
+                    None,
+                    size,
+                    PageTableFlags::PRESENT | PageTableFlags::WRITABLE | no_execute(),
+                )
+            },
+            _ => panic!("Memory allocator or frame allocator are not set"),
+        };
+        if let Err(e) = mapped {
+            warn!("Cannot map kernel stack at {:X}h: {}", bottom, e);
+            let _ = vspace::release(VirtAddr::new_truncate(bottom));
+            return None;
+        }
+        register(bottom - GUARD_SIZE..bottom, owner);
+        Some(KernelStack {
+            bottom,
+            top: bottom + size,
+        })
+    }
+
+    /// Returns the initial stack pointer: the address just past the top of the stack.
+    #[inline]
+    pub fn top(&self) -> VirtAddr {
+        VirtAddr::new_truncate(self.top)
+    }
+
+    /// Returns the lowest address of the stack.
+    #[inline]
+    pub fn bottom(&self) -> VirtAddr {
+        VirtAddr::new_truncate(self.bottom)
+    }
+
+    /// Returns the size of the stack in bytes.
+    #[inline]
+    pub fn size(&self) -> u64 {
+        self.top - self.bottom
+    }
+
+    /// Switches to this stack and calls `entry` on it. The current stack is abandoned and this one is never freed.
+    pub fn enter(self, entry: extern "C" fn() -> !) -> ! {
+        let top = self.top;
+        forget(self);
+        unsafe {
+            asm!(
+                "mov rsp, {top}",
+                "xor ebp, ebp",
+                "call {entry}",
+                "ud2",
+                top = in(reg) top,
+                entry = in(reg) entry,
+                options(noreturn)
+            )
+        }
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let _ = STACKS.write().remove(&self.bottom);
+        match (MAPPER.lock().as_mut(), FRAME_ALLOCATOR.lock().as_mut()) {
+            (Some(m), Some(a)) => unsafe {
+                let _ = unmap_range(m, a, self.bottom..self.top, |a, _, phys, len| {
+                    release_frames(a, phys, len)
+                });
+            },
+            _ => panic!("Memory allocator or frame allocator are not set"),
+        }
+        let _ = vspace::release(VirtAddr::new_truncate(self.bottom));
+    }
+}
+
+/// Records `guard` as the guard gap below a stack belonging to `owner`. The stack starts at `guard.end`.
+pub fn register(guard: Range<u64>, owner: &str) {
+    let record = StackRecord {
+        guard: guard.start,
+        owner: String::from(owner),
+    };
+    let _ = STACKS.write().insert(guard.end, record);
+}
+
+/// If `addr` lies in the guard gap below a kernel stack, calls `f` with the name of the stack's owner and returns
+/// what it returns. Does not allocate or block, so it is safe to call from the page fault handler.
+pub fn guard_owner<R>(addr: u64, f: impl FnOnce(&str) -> R) -> Option<R> {
+    let stacks = STACKS.try_read()?;
+    let (&bottom, record) = stacks.range(addr..).next()?;
+    if addr >= record.guard && addr < bottom {
+        Some(f(&record.owner))
+    } else {
+        None
+    }
+}
@@ -0,0 +1,405 @@
+// SPDX-License-Identifier: MPL-2.0
+use super::{
+    frame, lock::OwnedMutex, map_range, mmio::CacheType, phys_to_virt, release_frames,
+    space::user_l4, unmap_range, vspace, GlobalFrameAllocator, FRAME_ALLOCATOR, HIGHER_HALF,
+    MAPPER, PHYS_OFFSET,
+};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::fmt::Debug;
+use core::ops::Range;
+use core::slice;
+use core::sync::atomic::Ordering;
+use spin::Lazy;
+use x86_64::{
+    addr::align_up,
+    registers::control::Cr3,
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            mapper::{MapperAllSizes, TranslateResult},
+            FrameAllocator, FrameDeallocator, OffsetPageTable, PageSize, PageTableFlags, PhysFrame,
+            Size4KiB, Translate,
+        },
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// Marks a page whose frame belongs to its region, as opposed to a shared or device frame. Uses one of the bits the
+/// processor leaves to software.
+const PRIVATE: PageTableFlags = PageTableFlags::BIT_9;
+const PAGE: u64 = Size4KiB::SIZE;
+
+/// Demand-paged regions of the kernel half.
+static KERNEL_REGIONS: Lazy<OwnedMutex<RegionMap>> =
+    Lazy::new(|| OwnedMutex::new(RegionMap::default()));
+/// Demand-paged regions of every address space, keyed by the address of its top-level table.
+static SPACES: Lazy<OwnedMutex<BTreeMap<u64, Arc<OwnedMutex<RegionMap>>>>> =
+    Lazy::new(|| OwnedMutex::new(BTreeMap::new()));
+
+/// Supplies the contents of file-backed regions.
+pub trait Pager: Send + Sync + Debug {
+    /// Fills all of `page` with the page-sized chunk of the backing object at `offset`, zeroing whatever lies past
+    /// its end. Returns false if the object cannot be read.
+    ///
+    /// This runs in the page fault handler with interrupts disabled, so it must neither block nor touch
+    /// demand-paged memory.
+    fn read_page(&self, offset: u64, page: &mut [u8]) -> bool;
+}
+
+/// Where the contents of a region come from.
+#[derive(Clone, Debug)]
+pub enum Backing {
+    /// Anonymous memory, zero-filled when first touched.
+    Zero,
+    /// The physical range starting at `phys`, mapped read-only and shared until a page is written to, at which
+    /// point that page is copied.
+    CopyOnWrite {
+        /// Start of the shared physical range.
+        phys: u64,
+    },
+    /// An object read in page by page through a pager.
+    File {
+        /// The pager that reads the object.
+        pager: Arc<dyn Pager>,
+        /// Offset into the object of the start of the region.
+        offset: u64,
+    },
+    /// Device memory.
+    Device {
+        /// Start of the device's physical range.
+        phys: u64,
+        /// The memory type to map it with.
+        cache: CacheType,
+    },
+}
+
+/// A range of virtual address space that is reserved up front and backed one page at a time, by the page fault
+/// handler, as it is touched.
+#[derive(Clone, Debug)]
+pub struct VmRegion {
+    start: u64,
+    end: u64,
+    flags: PageTableFlags,
+    backing: Backing,
+}
+
+impl VmRegion {
+    /// Returns the range of addresses the region covers.
+    #[inline]
+    pub fn range(&self) -> Range<u64> {
+        self.start..self.end
+    }
+
+    /// Returns the flags pages of the region are mapped with.
+    #[inline]
+    pub fn flags(&self) -> PageTableFlags {
+        self.flags
+    }
+
+    /// Returns where the contents of the region come from.
+    #[inline]
+    pub fn backing(&self) -> &Backing {
+        &self.backing
+    }
+}
+
+/// The demand-paged regions of one address space, keyed by start.
+#[derive(Debug, Default)]
+pub(crate) struct RegionMap(BTreeMap<u64, VmRegion>);
+
+impl RegionMap {
+    /// Adds a region covering the page-aligned `span`. Returns false if it overlaps an existing region.
+    pub(crate) fn insert(
+        &mut self,
+        span: Range<u64>,
+        flags: PageTableFlags,
+        backing: Backing,
+    ) -> bool {
+        if self
+            .0
+            .range(..span.end)
+            .next_back()
+            .map_or(false, |(_, r)| r.end > span.start)
+        {
+            return false;
+        }
+        let region = VmRegion {
+            start: span.start,
+            end: span.end,
+            flags: PageTableFlags::PRESENT | flags,
+            backing,
+        };
+        let _ = self.0.insert(span.start, region);
+        true
+    }
+
+    /// Removes the region starting at `start`.
+    pub(crate) fn remove(&mut self, start: u64) -> Option<VmRegion> {
+        self.0.remove(&start)
+    }
+
+    /// Returns the region containing `addr`.
+    fn find(&self, addr: u64) -> Option<&VmRegion> {
+        self.0
+            .range(..=addr)
+            .next_back()
+            .map(|(_, r)| r)
+            .filter(|r| addr < r.end)
+    }
+
+    /// Returns every region, in address order.
+    pub(crate) fn regions(&self) -> impl Iterator<Item = &VmRegion> {
+        self.0.values()
+    }
+}
+
+/// Makes the regions of the address space with the given top-level table visible to the page fault handler.
+pub(crate) fn register_space(root: PhysFrame, regions: Arc<OwnedMutex<RegionMap>>) {
+    let _ = SPACES.lock().insert(root.start_address().as_u64(), regions);
+}
+
+/// Hides the regions of the address space with the given top-level table from the page fault handler.
+pub(crate) fn unregister_space(root: PhysFrame) {
+    // Dropped once the lock is released, as this may free the map
+    let regions = SPACES.lock().remove(&root.start_address().as_u64());
+    drop(regions);
+}
+
+/// Reserves `size` bytes of kernel virtual address space, backed on demand from `backing` and mapped with `flags`
+/// (the `P` bit is implied). Nothing is mapped until it is touched. Returns `None` if no kernel virtual address
+/// space is left.
+pub fn reserve(size: u64, flags: PageTableFlags, backing: Backing) -> Option<VirtAddr> {
+    let size = align_up(size.max(1), PAGE);
+    let start = vspace::reserve(size, PAGE, PAGE, true)?;
+    let span = start.as_u64()..start.as_u64() + size;
+    if KERNEL_REGIONS.lock().insert(span, flags, backing) {
+        Some(start)
+    } else {
+        let _ = vspace::release(start);
+        None
+    }
+}
+
+/// Releases a region returned by [`reserve`], unmapping whatever was touched and freeing the frames that belonged to
+/// it. Returns false if no region starts at `start`.
+pub fn release(start: VirtAddr) -> bool {
+    let region = match KERNEL_REGIONS.lock().remove(start.as_u64()) {
+        Some(region) => region,
+        None => return false,
+    };
+    match (MAPPER.lock().as_mut(), FRAME_ALLOCATOR.lock().as_mut()) {
+        (Some(m), Some(a)) => unsafe { unmap_pages(m, a, region.range()) },
+        _ => panic!("Memory allocator or frame allocator are not set"),
+    }
+    let _ = vspace::release(start);
+    // The region (and whatever pager it holds) is dropped here, after the locks are gone
+    true
+}
+
+/// Unmaps every page in `span`, dropping a reference to each frame, so that the region's private copies go back to
+/// the frame allocator. Must not allocate from the heap, since the frame allocator is locked.
+pub(crate) unsafe fn unmap_pages(
+    mapper: &mut (impl MapperAllSizes + Translate),
+    frame_allocator: &mut GlobalFrameAllocator,
+    span: Range<u64>,
+) {
+    (span.start..span.end)
+        .step_by(PAGE as usize)
+        .for_each(|addr| {
+            if let TranslateResult::Mapped { .. } = mapper.translate(VirtAddr::new_truncate(addr)) {
+                unsafe {
+                    let _ = unmap_range(
+                        mapper,
+                        frame_allocator,
+                        addr..addr + PAGE,
+                        |a, _, phys, len| release_frames(a, phys, len),
+                    );
+                }
+            }
+        });
+}
+
+/// Resolves a page fault at `addr` that hit a demand-paged region, by mapping (or copying) the page it needs.
+/// Returns false if the fault is a true access violation, or the page could not be backed.
+///
+/// The fault may have been taken while this processor held one of the locks this needs. Such a lock is not waited for,
+/// and the fault is reported as unresolved rather than deadlocking; locks other processors hold are waited for.
+pub fn handle_fault(addr: VirtAddr, error: PageFaultErrorCode) -> bool {
+    if error.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+        return false;
+    }
+    let addr = addr.as_u64();
+    if addr >= HIGHER_HALF {
+        if error.contains(PageFaultErrorCode::USER_MODE) {
+            return false;
+        }
+        let regions = match KERNEL_REGIONS.lock_unless_held() {
+            Some(regions) => regions,
+            None => return false,
+        };
+        let region = match regions.find(addr) {
+            Some(region) => region,
+            None => return false,
+        };
+        let (mut mapper, mut allocator) = match (
+            MAPPER.lock_unless_held(),
+            FRAME_ALLOCATOR.lock_unless_held(),
+        ) {
+            (Some(m), Some(a)) => (m, a),
+            _ => return false,
+        };
+        match (mapper.as_mut(), allocator.as_mut()) {
+            (Some(m), Some(a)) => unsafe { resolve(m, a, region, addr, error).is_some() },
+            _ => false,
+        }
+    } else {
+        let root = Cr3::read().0;
+        let spaces = match SPACES.lock_unless_held() {
+            Some(spaces) => spaces,
+            None => return false,
+        };
+        let regions = match spaces
+            .get(&root.start_address().as_u64())
+            .and_then(|regions| regions.lock_unless_held())
+        {
+            Some(regions) => regions,
+            None => return false,
+        };
+        let region = match regions.find(addr) {
+            Some(region) => region,
+            None => return false,
+        };
+        let mut mapper = unsafe {
+            OffsetPageTable::new(
+                &mut *phys_to_virt(user_l4(root).start_address()).as_mut_ptr(),
+                VirtAddr::new_truncate(PHYS_OFFSET.load(Ordering::Relaxed)),
+            )
+        };
+        match FRAME_ALLOCATOR
+            .lock_unless_held()
+            .as_mut()
+            .and_then(|a| a.as_mut())
+        {
+            Some(a) => unsafe { resolve(&mut mapper, a, region, addr, error).is_some() },
+            None => false,
+        }
+    }
+}
+
+/// Maps the page of `region` containing `addr` as the fault described by `error` requires.
+unsafe fn resolve(
+    mapper: &mut (impl MapperAllSizes + Translate),
+    frame_allocator: &mut GlobalFrameAllocator,
+    region: &VmRegion,
+    addr: u64,
+    error: PageFaultErrorCode,
+) -> Option<()> {
+    let write = error.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+    if (write && !region.flags.contains(PageTableFlags::WRITABLE))
+        || (error.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+            && region.flags.contains(PageTableFlags::NO_EXECUTE))
+        || (error.contains(PageFaultErrorCode::USER_MODE)
+            && !region.flags.contains(PageTableFlags::USER_ACCESSIBLE))
+    {
+        return None;
+    }
+    let page = addr & !(PAGE - 1);
+    let offset = page - region.start;
+    let current = match mapper.translate(VirtAddr::new_truncate(page)) {
+        TranslateResult::Mapped { frame, flags, .. } => {
+            Some((frame.start_address().as_u64(), flags))
+        }
+        _ => None,
+    };
+    let a = frame_allocator;
+    let (phys, flags) = match (current, &region.backing) {
+        // A write to a shared copy-on-write page: give the region its own copy
+        (Some((shared, flags)), Backing::CopyOnWrite { .. })
+            if write && !flags.contains(PRIVATE) =>
+        {
+            let frame = private_frame(a, |p| p.copy_from_slice(unsafe { frame_bytes(shared) }))?;
+            unsafe {
+                let _ = unmap_range(mapper, a, page..page + PAGE, |a, _, phys, len| {
+                    release_frames(a, phys, len)
+                });
+            }
+            (frame, region.flags | PRIVATE)
+        }
+        // Anything else that is present is either a real violation, or was resolved by another processor first
+        (Some(_), _) => {
+            return if error.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+                None
+            } else {
+                Some(())
+            }
+        }
+        (None, Backing::Zero) => (private_frame(a, |p| p.fill(0))?, region.flags | PRIVATE),
+        (None, Backing::CopyOnWrite { phys }) if write => (
+            private_frame(a, |p| {
+                p.copy_from_slice(unsafe { frame_bytes(phys + offset) })
+            })?,
+            region.flags | PRIVATE,
+        ),
+        (None, Backing::CopyOnWrite { phys }) => {
+            (phys + offset, region.flags - PageTableFlags::WRITABLE)
+        }
+        (
+            None,
+            Backing::File {
+                pager,
+                offset: base,
+            },
+        ) => {
+            let frame = private_frame(a, |_| ())?;
+            if !pager.read_page(base + offset, unsafe { frame_bytes(frame) }) {
+                unsafe {
+                    a.deallocate_frame(PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(
+                        frame,
+                    )));
+                }
+                return None;
+            }
+            (frame, region.flags | PRIVATE)
+        }
+        (None, Backing::Device { phys, cache }) => (phys + offset, region.flags | cache.flags()),
+    };
+    if unsafe { map_range(mapper, a, page, Some(phys), PAGE, flags) }.is_err() {
+        if flags.contains(PRIVATE) {
+            unsafe {
+                release_frames(a, phys, PAGE);
+            }
+        }
+        return None;
+    }
+    // Private copies keep the reference they were allocated with; shared frames gain one for this mapping
+    if !flags.contains(PRIVATE) {
+        frame::get_range(phys, PAGE);
+    }
+    Some(())
+}
+
+/// Allocates a frame for a region and fills it with `fill`. Returns its physical address.
+fn private_frame(
+    frame_allocator: &mut GlobalFrameAllocator,
+    fill: impl FnOnce(&mut [u8]),
+) -> Option<u64> {
+    let frame: PhysFrame = frame_allocator.allocate_frame()?;
+    let phys = frame.start_address().as_u64();
+    fill(unsafe { frame_bytes(phys) });
+    Some(phys)
+}
+
+/// Returns the page-sized frame at `phys` as bytes, through the higher-half physical memory mapping.
+///
+/// # Safety
+///
+/// Nothing else may access the frame while the returned slice is alive.
+unsafe fn frame_bytes(phys: u64) -> &'static mut [u8] {
+    unsafe {
+        slice::from_raw_parts_mut(
+            phys_to_virt(PhysAddr::new(phys)).as_mut_ptr(),
+            PAGE as usize,
+        )
+    }
+}
@@ -0,0 +1,201 @@
+// SPDX-License-Identifier: MPL-2.0
+use super::ADDRRNG;
+use alloc::collections::BTreeMap;
+use core::iter::once;
+use core::ops::Range;
+use log::*;
+use rand_core::RngCore;
+use spin::{mutex::ticket::TicketMutex, Lazy};
+use x86_64::{
+    addr::{align_down, align_up},
+    VirtAddr,
+};
+
+/// Start of the window of kernel virtual address space managed by the kernel VA allocator.
+pub const KERNEL_VA_START: u64 = 0xFFFF_C000_0000_0000;
+/// End (exclusive) of the window of kernel virtual address space managed by the kernel VA allocator.
+pub const KERNEL_VA_END: u64 = 0xFFFF_E000_0000_0000;
+const PAGE_SIZE: u64 = 4096;
+
+static KVA: Lazy<TicketMutex<VirtualSpace>> =
+    Lazy::new(|| TicketMutex::new(VirtualSpace::new(KERNEL_VA_START..KERNEL_VA_END)));
+
+/// A reserved range. The guard gaps on either side are reserved along with it.
+#[derive(Clone, Copy, Debug)]
+struct Reservation {
+    start: u64,
+    end: u64,
+    guard: u64,
+}
+
+/// Tracks which parts of a window of virtual address space are reserved.
+///
+/// Reservations are kept in an ordered map keyed by their start (guard gaps included), so they never overlap and
+/// the free gaps between them can be walked in address order.
+#[derive(Debug)]
+pub struct VirtualSpace {
+    window: Range<u64>,
+    reserved: BTreeMap<u64, Reservation>,
+}
+
+impl VirtualSpace {
+    /// Creates an empty virtual space covering `window`.
+    pub fn new(window: Range<u64>) -> Self {
+        VirtualSpace {
+            window,
+            reserved: BTreeMap::new(),
+        }
+    }
+
+    /// Reserves `size` bytes aligned to `align`, keeping `guard` bytes on either side out of every other reservation.
+    /// If `randomize` is set, the range is picked uniformly among every position that fits; otherwise the lowest one is
+    /// used. Sizes are rounded up to whole pages.
+    pub fn reserve(
+        &mut self,
+        size: u64,
+        align: u64,
+        guard: u64,
+        randomize: bool,
+    ) -> Option<VirtAddr> {
+        if size == 0 || !align.is_power_of_two() {
+            return None;
+        }
+        let size = align_up(size, PAGE_SIZE);
+        let guard = align_up(guard, PAGE_SIZE);
+        let align = align.max(PAGE_SIZE);
+        // Returns the first usable start within a gap and how many aligned starts fit
+        let slots = |gap: &Range<u64>| -> (u64, u64) {
+            let first = align_up(gap.start + guard, align);
+            match gap.end.checked_sub(size + guard) {
+                Some(last) if last >= first => {
+                    (first, (align_down(last, align) - first) / align + 1)
+                }
+                _ => (first, 0),
+            }
+        };
+        let start = if randomize {
+            let total: u64 = self.gaps().map(|g| slots(&g).1).sum();
+            if total == 0 {
+                return None;
+            }
+            let mut pick = ADDRRNG.lock().next_u64() % total;
+            self.gaps().find_map(|g| {
+                let (first, count) = slots(&g);
+                if pick < count {
+                    Some(first + pick * align)
+                } else {
+                    pick -= count;
+                    None
+                }
+            })?
+        } else {
+            self.gaps().find_map(|g| match slots(&g) {
+                (first, count) if count > 0 => Some(first),
+                _ => None,
+            })?
+        };
+        let _ = self.reserved.insert(
+            start - guard,
+            Reservation {
+                start,
+                end: start + size,
+                guard,
+            },
+        );
+        Some(VirtAddr::new_truncate(start))
+    }
+
+    /// Reserves the fixed range `[start, start + size)`. Fails if any part of it is outside the window or already
+    /// reserved.
+    pub fn reserve_at(&mut self, start: u64, size: u64) -> bool {
+        let range = align_down(start, PAGE_SIZE)..align_up(start + size, PAGE_SIZE);
+        if range.start < self.window.start
+            || range.end > self.window.end
+            || !self
+                .gaps()
+                .any(|g| g.start <= range.start && range.end <= g.end)
+        {
+            return false;
+        }
+        let _ = self.reserved.insert(
+            range.start,
+            Reservation {
+                start: range.start,
+                end: range.end,
+                guard: 0,
+            },
+        );
+        true
+    }
+
+    /// Releases the reservation starting at `start`, making it (and its guard gaps) available again. Returns the
+    /// size of the released range, or `None` if no reservation starts there.
+    pub fn release(&mut self, start: VirtAddr) -> Option<u64> {
+        let start = start.as_u64();
+        let key = self
+            .reserved
+            .range(..=start)
+            .next_back()
+            .filter(|(_, r)| r.start == start)
+            .map(|(&k, _)| k)?;
+        self.reserved.remove(&key).map(|r| r.end - r.start)
+    }
+
+    /// Returns the reservation containing `addr` and whether `addr` falls in one of its guard gaps.
+    pub fn find(&self, addr: VirtAddr) -> Option<(Range<u64>, bool)> {
+        let addr = addr.as_u64();
+        self.reserved
+            .range(..=addr)
+            .next_back()
+            .map(|(_, r)| r)
+            .filter(|r| addr < r.end + r.guard)
+            .map(|r| (r.start..r.end, !(r.start..r.end).contains(&addr)))
+    }
+
+    /// Iterates over the unreserved gaps of the window in address order.
+    fn gaps(&self) -> impl Iterator<Item = Range<u64>> + '_ {
+        let mut cursor = self.window.start;
+        self.reserved
+            .iter()
+            .map(|(&start, r)| (start, r.end + r.guard))
+            .chain(once((self.window.end, self.window.end)))
+            .filter_map(move |(start, end)| {
+                let gap = cursor..start;
+                cursor = end;
+                if gap.start < gap.end {
+                    Some(gap)
+                } else {
+                    None
+                }
+            })
+    }
+}
+
+/// Reserves `size` bytes of kernel virtual address space aligned to `align`, keeping `guard` bytes on either side
+/// unreserved. If `randomize` is set, the placement is randomized. Requires the kernel heap.
+pub fn reserve(size: u64, align: u64, guard: u64, randomize: bool) -> Option<VirtAddr> {
+    let addr = KVA.lock().reserve(size, align, guard, randomize);
+    if addr.is_none() {
+        warn!(
+            "Cannot reserve {:X} bytes of kernel address space aligned to {:X}",
+            size, align
+        );
+    }
+    addr
+}
+
+/// Reserves the fixed range `[start, start + size)` of kernel virtual address space.
+pub fn reserve_at(start: u64, size: u64) -> bool {
+    KVA.lock().reserve_at(start, size)
+}
+
+/// Releases a kernel virtual address range previously returned by [`reserve`]. Returns the size of the range.
+/// This does not unmap anything mapped in the range.
+pub fn release(start: VirtAddr) -> Option<u64> {
+    KVA.lock().release(start)
+}
+
+/// Returns the kernel reservation containing `addr` and whether `addr` falls in one of its guard gaps.
+pub fn find(addr: VirtAddr) -> Option<(Range<u64>, bool)> {
+    KVA.lock().find(addr)
+}
@@ -1,10 +1,16 @@
 // SPDX-License-Identifier: MPL-2.0
-use crate::memory::{allocate_phys_range, free_range};
+use crate::interrupts::{alloc_vector, alloc_vectors, IrqVector};
+use crate::memory::{
+    allocate_phys_range, free_range,
+    mmio::{CacheType, IoMapping},
+    MemoryError,
+};
 use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
 use async_recursion::async_recursion;
 use bit_field::BitField;
+use core::fmt;
 use heapless::LinearMap;
 use log::*;
 use spin::{mutex::ticket::TicketMutex, Lazy};
@@ -138,12 +144,334 @@ pub struct Capabilities {
     pub extended: BTreeMap<u16, u64>,
 }
 
+/// The capability IDs of MSI and MSI-X.
+const CAP_MSI: u8 = 0x05;
+const CAP_MSIX: u8 = 0x11;
+/// Bits of the command register.
+const CMD_BUS_MASTER: usize = 2;
+const CMD_INTX_DISABLE: usize = 10;
+/// The address range MSI writes go to, with the destination APIC ID in bits 12-19.
+const MSI_ADDRESS: u32 = 0xFEE0_0000;
+/// The priority class MSI vectors are allocated from.
+const MSI_PRIORITY_CLASS: u8 = 8;
+/// The size of an MSI-X table entry.
+const MSIX_ENTRY_SIZE: u64 = 16;
+
+/// Errors reported when enabling message signaled interrupts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MsiError {
+    /// The device lacks the MSI or MSI-X capability.
+    NotSupported,
+    /// No interrupts were asked for.
+    NoVectors,
+    /// Not enough free interrupt vectors were left.
+    OutOfVectors,
+    /// The processor with this local APIC ID cannot be named in a message address, whose destination is 8 bits wide.
+    UnreachableCpu(u32),
+    /// The MSI-X table or pending bit array lies in a BAR that is not a memory BAR, or could not be mapped.
+    BadTable,
+}
+
+impl fmt::Display for MsiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MsiError::NotSupported => write!(f, "device does not support this kind of MSI"),
+            MsiError::NoVectors => write!(f, "no interrupts requested"),
+            MsiError::OutOfVectors => write!(f, "out of interrupt vectors"),
+            MsiError::UnreachableCpu(id) => {
+                write!(f, "local APIC ID {:X} cannot be an MSI destination", id)
+            }
+            MsiError::BadTable => write!(f, "cannot map MSI-X table"),
+        }
+    }
+}
+
+impl PciDevice {
+    /// Enables MSI with up to `vectors` interrupts, delivered to the processor with local APIC ID `dest_cpu`, and
+    /// disables legacy INTx interrupts and MSI-X. The number of interrupts is rounded up to a power of two and capped
+    /// at what the device supports, so the device may get fewer than asked for. Handlers are registered on the
+    /// vectors of the returned [`Msi`], all of which start out unmasked.
+    pub fn enable_msi(&self, vectors: u8, dest_cpu: u32) -> Result<Msi, MsiError> {
+        let cap = *self.caps.pci.get(&CAP_MSI).ok_or(MsiError::NotSupported)? as usize;
+        if vectors == 0 {
+            return Err(MsiError::NoVectors);
+        }
+        let dest = u8::try_from(dest_cpu).map_err(|_| MsiError::UnreachableCpu(dest_cpu))?;
+        let mut control = read_dword(cap, 0x00);
+        let capable = 1u8 << control.get_bits(17..20).min(5);
+        let count = vectors
+            .checked_next_power_of_two()
+            .unwrap_or(u8::MAX)
+            .min(capable)
+            .min(16);
+        let vectors = alloc_vectors(MSI_PRIORITY_CLASS, count).ok_or(MsiError::OutOfVectors)?;
+        self.disable_msix();
+        control.set_bit(16, false);
+        write_dword(cap, 0x00, control);
+        let is_64bit = control.get_bit(23);
+        let (data, mask) = if is_64bit {
+            write_dword(cap, 0x08, 0);
+            (0x0C, 0x10)
+        } else {
+            (0x08, 0x0C)
+        };
+        write_dword(cap, 0x04, MSI_ADDRESS | u32::from(dest) << 12);
+        // Fixed delivery, edge triggered; the device ORs the message number into the low bits of the vector
+        write_dword(cap, data, u32::from(vectors[0].vector()));
+        let per_vector_mask = control.get_bit(24);
+        if per_vector_mask {
+            write_dword(cap, mask, 0);
+        }
+        control.set_bits(20..23, count.trailing_zeros());
+        control.set_bit(16, true);
+        write_dword(cap, 0x00, control);
+        self.disable_intx();
+        info!(
+            "{:X}:{:X}:{:X}:{:X}: MSI enabled with {} vector(s) from int. {:X} on CPU {:X}",
+            self.domain,
+            self.bus,
+            self.device,
+            self.function,
+            count,
+            vectors[0].vector(),
+            dest
+        );
+        Ok(Msi {
+            cap,
+            mask: if per_vector_mask { Some(mask) } else { None },
+            vectors,
+        })
+    }
+
+    /// Enables MSI-X with the first `table` entries of the device's MSI-X table, capped at the size of the table,
+    /// each given its own vector and delivered to the processor with local APIC ID `dest_cpu`, and disables legacy
+    /// INTx interrupts and MSI. The table and pending bit array are mapped from the BARs they live in. Handlers are
+    /// registered on the vectors of the returned [`MsiX`], all of which start out unmasked.
+    pub fn enable_msix(&self, table: u16, dest_cpu: u32) -> Result<MsiX, MsiError> {
+        let cap = *self.caps.pci.get(&CAP_MSIX).ok_or(MsiError::NotSupported)? as usize;
+        if table == 0 {
+            return Err(MsiError::NoVectors);
+        }
+        let dest = u8::try_from(dest_cpu).map_err(|_| MsiError::UnreachableCpu(dest_cpu))?;
+        let mut control = read_dword(cap, 0x00);
+        let size = control.get_bits(16..27) as u64 + 1;
+        let count = u64::from(table).min(size);
+        let table = self
+            .map_msix_region(read_dword(cap, 0x04), size * MSIX_ENTRY_SIZE)
+            .ok_or(MsiError::BadTable)?;
+        let pba = self
+            .map_msix_region(read_dword(cap, 0x08), ((size + 63) / 64) * 8)
+            .ok_or(MsiError::BadTable)?;
+        let vectors = (0..count)
+            .map(|_| alloc_vector(MSI_PRIORITY_CLASS))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(MsiError::OutOfVectors)?;
+        self.disable_msi();
+        // Mask the whole function while the table changes
+        control.set_bit(30, true);
+        control.set_bit(31, true);
+        write_dword(cap, 0x00, control);
+        (0..size as usize).for_each(|entry| {
+            let offset = entry * MSIX_ENTRY_SIZE as usize;
+            table.write(offset + 12, 1u32);
+        });
+        vectors.iter().zip(0..).for_each(|(vector, entry)| {
+            let offset = entry * MSIX_ENTRY_SIZE as usize;
+            table.write(offset, MSI_ADDRESS | u32::from(dest) << 12);
+            table.write(offset + 4, 0u32);
+            table.write(offset + 8, u32::from(vector.vector()));
+            table.write(offset + 12, 0u32);
+        });
+        control.set_bit(30, false);
+        write_dword(cap, 0x00, control);
+        self.disable_intx();
+        info!(
+            "{:X}:{:X}:{:X}:{:X}: MSI-X enabled with {} of {} entries on CPU {:X}",
+            self.domain, self.bus, self.device, self.function, count, size, dest
+        );
+        Ok(MsiX {
+            cap,
+            table,
+            pba,
+            vectors,
+        })
+    }
+
+    /// Maps the part of a BAR an MSI-X table or pending bit array register points to.
+    fn map_msix_region(&self, reg: u32, len: u64) -> Option<IoMapping> {
+        let bir = reg.get_bits(0..3);
+        if bir > 5 || read_dword(self.phys_addr as _, BAR0 + bir * 4).get_bit(0) {
+            return None;
+        }
+        let base = calculate_bar_addr(self, BAR0 + bir * 4) as u64;
+        IoMapping::new(base + u64::from(reg & !0x07), len, CacheType::Uncached)
+    }
+
+    /// Keeps the device from raising legacy INTx interrupts, and lets it master the bus, as writing MSI messages
+    /// requires.
+    fn disable_intx(&self) {
+        let addr = self.phys_addr as usize;
+        let mut command = read_dword(addr, COMMAND);
+        command.set_bit(CMD_BUS_MASTER, true);
+        command.set_bit(CMD_INTX_DISABLE, true);
+        write_dword(addr, COMMAND, command);
+    }
+
+    fn disable_msi(&self) {
+        if let Some(&cap) = self.caps.pci.get(&CAP_MSI) {
+            let mut control = read_dword(cap as usize, 0x00);
+            control.set_bit(16, false);
+            write_dword(cap as usize, 0x00, control);
+        }
+    }
+
+    fn disable_msix(&self) {
+        if let Some(&cap) = self.caps.pci.get(&CAP_MSIX) {
+            let mut control = read_dword(cap as usize, 0x00);
+            control.set_bit(31, false);
+            write_dword(cap as usize, 0x00, control);
+        }
+    }
+}
+
+/// MSI enabled on a device with [`PciDevice::enable_msi`]. Message `n` is delivered at `vectors()[n]`. MSI is
+/// disabled again, and the vectors freed, when this is dropped; legacy INTx interrupts stay disabled.
+#[derive(Debug)]
+pub struct Msi {
+    /// The address of the MSI capability.
+    cap: usize,
+    /// The offset of the mask bits in the capability, if the device supports per-vector masking.
+    mask: Option<u32>,
+    vectors: Vec<IrqVector>,
+}
+
+impl Msi {
+    /// Returns the vectors the device's messages are delivered at, in message order.
+    #[inline]
+    pub fn vectors(&self) -> &[IrqVector] {
+        &self.vectors
+    }
+
+    /// Returns whether messages can be masked one at a time.
+    #[inline]
+    pub fn can_mask(&self) -> bool {
+        self.mask.is_some()
+    }
+
+    /// Masks message `index`, so that the device holds it back until it is unmasked. Returns false if the device
+    /// cannot mask messages one at a time or has no such message.
+    pub fn mask(&self, index: usize) -> bool {
+        self.set_masked(index, true)
+    }
+
+    /// Unmasks message `index`. Returns false if the device cannot mask messages one at a time or has no such
+    /// message.
+    pub fn unmask(&self, index: usize) -> bool {
+        self.set_masked(index, false)
+    }
+
+    fn set_masked(&self, index: usize, masked: bool) -> bool {
+        match self.mask {
+            Some(mask) if index < self.vectors.len() => {
+                let mut bits = read_dword(self.cap, mask);
+                bits.set_bit(index, masked);
+                write_dword(self.cap, mask, bits);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Drop for Msi {
+    fn drop(&mut self) {
+        let mut control = read_dword(self.cap, 0x00);
+        control.set_bit(16, false);
+        write_dword(self.cap, 0x00, control);
+    }
+}
+
+/// MSI-X enabled on a device with [`PciDevice::enable_msix`]. Table entry `n` is delivered at `vectors()[n]`.
+/// MSI-X is disabled again, and the vectors freed, when this is dropped; legacy INTx interrupts stay disabled.
+#[derive(Debug)]
+pub struct MsiX {
+    /// The address of the MSI-X capability.
+    cap: usize,
+    table: IoMapping,
+    pba: IoMapping,
+    vectors: Vec<IrqVector>,
+}
+
+impl MsiX {
+    /// Returns the vectors the enabled table entries are delivered at, in table order.
+    #[inline]
+    pub fn vectors(&self) -> &[IrqVector] {
+        &self.vectors
+    }
+
+    /// Masks table entry `index`, so that the device holds its message back until it is unmasked. Returns false if
+    /// the entry is not enabled.
+    pub fn mask(&self, index: usize) -> bool {
+        self.set_masked(index, true)
+    }
+
+    /// Unmasks table entry `index`. Returns false if the entry is not enabled.
+    pub fn unmask(&self, index: usize) -> bool {
+        self.set_masked(index, false)
+    }
+
+    /// Returns whether the device holds back a message for table entry `index` because the entry is masked.
+    pub fn is_pending(&self, index: usize) -> bool {
+        index < self.vectors.len() && self.pba.read::<u64>(index / 64 * 8).get_bit(index % 64)
+    }
+
+    fn set_masked(&self, index: usize, masked: bool) -> bool {
+        if index >= self.vectors.len() {
+            return false;
+        }
+        let offset = index * MSIX_ENTRY_SIZE as usize + 12;
+        let mut control: u32 = self.table.read(offset);
+        control.set_bit(0, masked);
+        self.table.write(offset, control);
+        true
+    }
+}
+
+impl Drop for MsiX {
+    fn drop(&mut self) {
+        let mut control = read_dword(self.cap, 0x00);
+        control.set_bit(30, true);
+        control.set_bit(31, false);
+        write_dword(self.cap, 0x00, control);
+    }
+}
+
 // Adds a device to the PCI device list.
 #[inline]
 fn add_device(device: PciDevice) {
     PCI_DEVICES.lock().push(device);
 }
 
+// Identity-maps the configuration space of a function. Functions of the same device may already have mapped it.
+fn map_config_space(addr: usize) {
+    match allocate_phys_range(addr as u64, (addr as u64) + 0x1000, true, None) {
+        Ok(_) | Err(MemoryError::AlreadyMapped(_)) => (),
+        Err(e) => panic!("Cannot map PCI configuration space at {:X}: {}", addr, e),
+    }
+}
+
+// Identity-maps a memory BAR as uncached device memory.
+fn map_bar(addr: u64, size: u64) {
+    if let Err(e) = allocate_phys_range(
+        addr,
+        addr + size,
+        true,
+        Some(PageTableFlags::WRITE_THROUGH | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE),
+    ) {
+        warn!("Cannot map BAR at {:X}, size {:X}: {}", addr, size, e);
+    }
+}
+
 #[track_caller]
 #[inline]
 fn read_dword(phys_addr: usize, addr: u32) -> u32 {
@@ -180,7 +508,7 @@ pub async fn probe() {
 async fn check_sg(sg: u16) {
     let regions = crate::acpi::get_pci_regions().unwrap();
     let addr = regions.physical_address(sg, 0, 0, 0).unwrap() as usize;
-    allocate_phys_range(addr as u64, (addr as u64) + 0x1000, true, None);
+    map_config_space(addr);
     let header_type = read_dword(addr, 0x0C).get_bits(16..24);
     if !header_type.get_bit(7) {
         debug!("Scanning bus 0");
@@ -229,10 +557,10 @@ async fn check_function(sg: u16, bus: u8, device: u8, function: u8) {
         .unwrap()
         .physical_address(sg, bus, device, function)
         .unwrap() as usize;
-    allocate_phys_range(addr as u64, (addr as u64) + 0x1000, true, None);
+    map_config_space(addr);
     let vid_did = read_dword(addr, 0x00);
     if vid_did.get_bits(0..16) == 0xFFFF && vid_did.get_bits(16..32) == 0xFFFF {
-        free_range(addr as u64, (addr as u64) + 0x1000);
+        let _ = free_range(addr as u64, (addr as u64) + 0x1000);
         return;
     }
     let data = read_dword(addr, 0x08);
@@ -333,32 +661,14 @@ async fn check_function(sg: u16, bus: u8, device: u8, function: u8) {
             bar = (!bar) + 1;
             dev.bars.insert(idx, (oldbar as u64, bar as u64)).unwrap();
             debug!("Barcheck: {:X}, {:X}", bar, bar as u64);
-            allocate_phys_range(
-                oldbar as u64,
-                (oldbar as u64) + (bar as u64),
-                true,
-                Some(
-                    PageTableFlags::WRITE_THROUGH
-                        | PageTableFlags::WRITABLE
-                        | PageTableFlags::NO_CACHE,
-                ),
-            );
+            map_bar(oldbar as u64, bar as u64);
         } else {
             let mut bar = (bar2 as u64) << 32 | (bar as u64);
             let oldbar = (oldbar2 as u64) << 32 | (oldbar as u64);
             bar.set_bits(0..4, 0);
             bar = (!bar) + 1;
             dev.bars.insert(idx, (oldbar, bar)).unwrap();
-            allocate_phys_range(
-                oldbar,
-                oldbar + bar,
-                true,
-                Some(
-                    PageTableFlags::WRITE_THROUGH
-                        | PageTableFlags::WRITABLE
-                        | PageTableFlags::NO_CACHE,
-                ),
-            );
+            map_bar(oldbar, bar);
         }
         if oldbar.get_bits(1..=2) == 0x02 {
             inc = 2;
@@ -369,14 +679,11 @@ async fn check_function(sg: u16, bus: u8, device: u8, function: u8) {
     // Iterate through permissions
     if read_dword(addr, STATUS).get_bit(4) {
         // We have a caps list
-        let mut cap_addr = addr + (read_dword(addr, CAP_LIST) as usize);
+        let mut cap_addr = addr + (read_dword(addr, CAP_LIST).get_bits(0..8) as usize & !0x03);
         loop {
             let data = read_dword(cap_addr, 0x00);
             let cap_id = data.get_bits(0..8);
-            let next_ptr = data.get_bits(8..16);
-            if next_ptr == 0x00 {
-                break;
-            }
+            let next_ptr = data.get_bits(8..16) as usize & !0x03;
             info!(
                 "Found {} ({}) capability at addr {:X}",
                 match cap_id {
@@ -408,7 +715,11 @@ async fn check_function(sg: u16, bus: u8, device: u8, function: u8) {
                 cap_addr
             );
             let _ = dev.caps.pci.entry(cap_id as u8).or_insert(cap_addr as u64);
-            cap_addr += next_ptr as usize;
+            // The next pointer is an offset into configuration space, and zero on the last capability
+            if next_ptr == 0x00 {
+                break;
+            }
+            cap_addr = addr + next_ptr;
         }
         // Loop through extended capabilities
         cap_addr = addr + 0x100;
@@ -417,7 +728,7 @@ async fn check_function(sg: u16, bus: u8, device: u8, function: u8) {
             let cap_id = data.get_bits(0..16);
             let cap_ver = data.get_bits(16..20);
             let next_ptr = data.get_bits(20..32);
-            if data == 0x00000000 || next_ptr == 0x0000 {
+            if data == 0x00000000 || data == 0xFFFF_FFFF {
                 break;
             }
             info!(
@@ -478,7 +789,10 @@ async fn check_function(sg: u16, bus: u8, device: u8, function: u8) {
                 .extended
                 .entry(cap_id as u16)
                 .or_insert(cap_addr as u64);
-            cap_addr += next_ptr as usize;
+            if next_ptr == 0x0000 {
+                break;
+            }
+            cap_addr = addr + next_ptr as usize;
         }
     }
     add_device(dev);
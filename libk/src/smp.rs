@@ -0,0 +1,82 @@
+// SPDX-License-Identifier: MPL-2.0
+use crate::memory::stack::{KernelStack, DEFAULT_STACK_SIZE};
+use crate::{gdt, idle_forever, interrupts, ipi, memory};
+use core::arch::x86_64::_mm_pause;
+use core::cell::UnsafeCell;
+use core::ptr::addr_of;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use log::*;
+use stivale_boot::v2::{StivaleSmpInfo, StivaleSmpTag};
+
+/// Size of the stack application processors start on.
+const TRAMPOLINE_SIZE: usize = 64 * 1024;
+/// How many times to poll for a started processor before giving up on it.
+const START_SPINS: u64 = 1 << 26;
+
+/// The stack an application processor starts on. It is part of the kernel image, so it is mapped in the bootloader's
+/// page table and the kernel's alike. Processors are started one at a time, each leaving it before the next starts.
+#[repr(C, align(16))]
+struct Trampoline(UnsafeCell<[u8; TRAMPOLINE_SIZE]>);
+unsafe impl Sync for Trampoline {}
+static TRAMPOLINE: Trampoline = Trampoline(UnsafeCell::new([0; TRAMPOLINE_SIZE]));
+/// Application processors that have left the trampoline stack and come online.
+static STARTED: AtomicUsize = AtomicUsize::new(0);
+
+/// Starts every application processor the bootloader parked, one at a time, and waits for each to come online.
+///
+/// Until it is started, a processor spins on a stack and page table in bootloader-reclaimable memory. Returns false if
+/// one never came online; it may still be running from that memory, so the memory must not be reclaimed then, and no
+/// processor after it is started. Requires the memory manager.
+#[cold]
+pub fn start(smp: &StivaleSmpTag) -> bool {
+    let all = smp
+        .as_slice()
+        .iter()
+        .filter(|cpu| cpu.lapic_id != smp.bsp_lapic_id)
+        .all(|cpu| {
+            let started = STARTED.load(Ordering::Acquire);
+            let top = TRAMPOLINE.0.get() as u64 + TRAMPOLINE_SIZE as u64;
+            // The processor polls `goto_address` and jumps as soon as it changes, so it goes last
+            unsafe {
+                (*(addr_of!(cpu.target_stack) as *const AtomicU64)).store(top, Ordering::Relaxed);
+                (*(addr_of!(cpu.goto_address) as *const AtomicU64))
+                    .store(ap_entry as usize as u64, Ordering::Release);
+            }
+            let arrived = (0..START_SPINS).any(|_| {
+                unsafe {
+                    _mm_pause();
+                }
+                STARTED.load(Ordering::Acquire) != started
+            });
+            if !arrived {
+                error!(
+                    "Processor with local APIC ID {:X} did not come online",
+                    cpu.lapic_id
+                );
+            }
+            arrived
+        });
+    info!(
+        "{} application processor(s) online",
+        STARTED.load(Ordering::Acquire)
+    );
+    all
+}
+
+/// Where an application processor starts, on the trampoline stack and the bootloader's page table.
+extern "C" fn ap_entry(_info: &StivaleSmpInfo) -> ! {
+    memory::init_ap();
+    gdt::init_ap();
+    interrupts::init_idt();
+    KernelStack::new(DEFAULT_STACK_SIZE, "application processor")
+        .expect("Cannot allocate an application processor's stack")
+        .enter(ap_main)
+}
+
+/// Runs an application processor once it is on a stack of its own.
+extern "C" fn ap_main() -> ! {
+    interrupts::init_ic();
+    ipi::mark_online();
+    let _ = STARTED.fetch_add(1, Ordering::Release);
+    idle_forever();
+}
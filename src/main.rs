@@ -36,20 +36,31 @@
 #![forbid(clippy::all)]
 extern crate alloc;
 mod graphics;
+use core::cell::UnsafeCell;
+use core::ffi::{c_char, CStr};
 use core::panic::PanicInfo;
+use libk::memory::{heap::KernelHeap, stack::KernelStack};
 use log::*;
-use slab_allocator_rs::*;
 use stivale_boot::v2::*;
 use x86_64::instructions::random::RdRand;
 
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: KernelHeap = KernelHeap;
 static LOGGER: Logger = Logger;
-const MAX_HEAP_SIZE: u64 = 1024 * 8192;
 const MAX_STACK_SIZE: usize = 1024 * 256;
+// The stack must be writable, so it lives in an UnsafeCell to keep it out of .rodata
 #[repr(C, align(4))]
-struct Stack([u8; MAX_STACK_SIZE]);
-static STACK: Stack = Stack([0; MAX_STACK_SIZE]);
+struct Stack(UnsafeCell<[u8; MAX_STACK_SIZE]>);
+unsafe impl Sync for Stack {}
+// The linker script puts the boot stack right above an unmapped guard page
+#[link_section = ".boot_stack"]
+static STACK: Stack = Stack(UnsafeCell::new([0; MAX_STACK_SIZE]));
+// Size of the guard page the linker script leaves below the boot stack
+const BOOT_STACK_GUARD_SIZE: u64 = 0x1000;
+
+extern "C" {
+    static __boot_stack_guard: u8;
+}
 
 // Tags
 static VIDEO_TAG: StivaleAnyVideoTag = StivaleAnyVideoTag::new()
@@ -70,7 +81,7 @@ static UNMAP_NULL_TAG: StivaleUnmapNullHeaderTag = StivaleUnmapNullHeaderTag::ne
 #[link_section = ".stivale2hdr"]
 #[used]
 static BOOT_LOADER_HEADER: StivaleHeader = StivaleHeader::new()
-    .stack(STACK.0.as_ptr_range().end)
+    .stack(unsafe { (STACK.0.get() as *const u8).add(MAX_STACK_SIZE) })
     .flags((1 << 1) | (1 << 2) | (1 << 3) | (1 << 4))
     .tags(&VIDEO_TAG as *const StivaleAnyVideoTag as *const ());
 
@@ -129,33 +140,52 @@ pub extern "C" fn _start(boot_info: &'static StivaleStruct) -> ! {
         &STACK,
         BOOT_LOADER_HEADER.get_stack() as u64
     );
-    libk::memory::init_memory_map(mmap.as_slice(), rsdp.rsdp);
     let vmap = boot_info
         .vmap()
         .expect("Bootloader did not provide a higher-half physical memory offset!");
-    let mut idx = usize::MAX;
-    let addrs = loop {
-        idx = idx.wrapping_add(1);
-        let entry = mmap.iter().nth(idx);
-        if entry.is_none() {
-            break (0, 0);
-        }
-        let entry = entry.unwrap();
-        if entry.entry_type() != StivaleMemoryMapEntryType::Usable {
-            continue;
-        }
-        if entry.length > MAX_HEAP_SIZE {
-            let base = entry.base;
-            break (base, base + MAX_HEAP_SIZE);
-        }
-    };
-    if addrs == (0, 0) {
-        panic!("Can't find a memory region for the heap!");
+    if let Some(passes) = memtest_passes(boot_info) {
+        libk::memory::memtest::run(mmap.as_slice(), vmap.address, passes);
     }
-    libk::memory::init(vmap.address, addrs.0, MAX_HEAP_SIZE);
-    unsafe {
-        ALLOCATOR.init(addrs.0 as usize, MAX_HEAP_SIZE as usize);
+    libk::memory::init_memory_map(mmap.as_slice(), rsdp.rsdp);
+    libk::memory::init(vmap.address);
+    libk::gdt::protect_ist_stacks();
+    let guard = unsafe { core::ptr::addr_of!(__boot_stack_guard) } as u64;
+    libk::memory::stack::register(guard..guard + BOOT_STACK_GUARD_SIZE, "boot");
+    // The application processors wait on the bootloader's stacks and page tables until they are started
+    if boot_info.smp().map_or(true, libk::smp::start) {
+        // Nothing the bootloader handed over is used past this point
+        libk::memory::reclaim_boot_memory(false);
+    } else {
+        warn!("Not reclaiming bootloader memory: an application processor may still be using it");
     }
+    info!("Switching to guarded kernel stack");
+    KernelStack::new(MAX_STACK_SIZE as u64, "kernel")
+        .expect("Cannot allocate the kernel stack")
+        .enter(kernel_main);
+}
+
+// Returns the number of passes the `memtest` boot parameter asks for; `memtest` alone asks for one
+fn memtest_passes(boot_info: &StivaleStruct) -> Option<u32> {
+    boot_info
+        .command_line()
+        .and_then(|tag| {
+            unsafe { CStr::from_ptr(tag.command_line as *const c_char) }
+                .to_str()
+                .ok()
+        })
+        .and_then(|cmdline| {
+            cmdline
+                .split_whitespace()
+                .find_map(|arg| match arg.strip_prefix("memtest") {
+                    Some("") => Some(1),
+                    Some(passes) => passes.strip_prefix('=').and_then(|n| n.parse().ok()),
+                    None => None,
+                })
+        })
+}
+
+// Runs the rest of the kernel on a stack with guard pages
+extern "C" fn kernel_main() -> ! {
     libk::init();
     libk::idle_forever();
 }